@@ -2,12 +2,8 @@ use crossbeam::sync::WaitGroup;
 use env_logger;
 use i2p;
 
-use crossbeam_channel::select;
 use i2p::net::{I2pListener, I2pStream};
-use i2p::sam_options::{
-	I2CPClientOptions, I2CPOptions, I2CPRouterOptions, I2CPTunnelInboundOptions,
-	I2CPTunnelOutboundOptions, SAMOptions, SignatureType,
-};
+use i2p::sam_options::{SAMOptions, SignatureType};
 use log::*;
 use std::io::{Read, Write};
 use std::net::Shutdown;
@@ -30,109 +26,78 @@ async fn main() {
 	info!("New secret key: {}", seckey);
 	// thread synchronization primitive
 	let wg = WaitGroup::new();
-	// message channel primitive
-	let (tx, rx) = crossbeam_channel::bounded::<bool>(1);
-	{
+	// handle used to stop the server once the client is done
+	let server_handle = {
 		let sam_session = i2p::sam::Session::create(
 			DEFAULT_API,
 			seckey.as_str(),
 			"hello_world",
 			SessionStyle::Stream,
-			SAMOptions {
-				i2cp_options: Some(I2CPOptions {
-					router_options: Some(I2CPRouterOptions {
-						inbound: Some(I2CPTunnelInboundOptions {
-							length: Some(1),
-							quantity: Some(2),
-							backup_quantity: Some(2),
-							..Default::default()
-						}),
-						outbound: Some(I2CPTunnelOutboundOptions {
-							length: Some(1),
-							quantity: Some(2),
-							backup_quantity: Some(2),
-							..Default::default()
-						}),
-						..Default::default()
-					}),
-					..Default::default()
-				}),
-				signature_type: SignatureType::EdDsaSha512Ed25519,
-				..Default::default()
-			},
+			SAMOptions::low_latency().with_backup_quantity(2),
 		)
 		.unwrap();
 		let local_dest = i2p::net::I2pAddr::from_b64(&sam_session.local_dest).unwrap();
 		info!("local_dest {}", local_dest);
-		let rx = rx;
 		let listener = match I2pListener::bind_with_session(&sam_session) {
 			Ok(listener) => listener,
 			Err(err) => panic!("failed to establish listener with session {:#?}", err),
 		};
-		// force the connection into non-blocking mode
-		sam_session.sam.set_nonblocking(true).unwrap();
+		let server_handle = listener.shutdown_handle();
 		// akin to Golang's sync.WaitGroup, and allows synchronizing execution across different threads
 		// particularly useful for enabling graceful shutdowns within server applications
 		let wg = wg.clone();
 		// spawns a background task, somewhat similar to Golang's goroutines
 		tokio::task::spawn_blocking(move || {
-			loop {
-				// check to see if we received a message through the crossbeam channel
-				// if not, immediately activate default case, which will continue onto
-				// the following listener.accept() statement
-				select! {
-					recv(rx) -> _msg => {
-						warn!("server received exit signal, goodbye...");
-						match sam_session.sam.conn.shutdown(Shutdown::Both) {
-							Ok(_) => info!("server shutdown ok"),
-							Err(err) => error!("server failed to properly shutdown {:#?}", err)
+			// the iterator ends once the listener is shut down from the main thread
+			for incoming_conn in listener.incoming() {
+				let mut incoming_conn = match incoming_conn {
+					Ok(incoming_conn) => incoming_conn,
+					Err(err) => {
+						error!("server failed to accept connection {:#?}", err);
+						continue;
+					}
+				};
+				let conn_addr = match incoming_conn.peer_addr() {
+					Ok(conn_addr) => conn_addr,
+					Err(err) => {
+						error!("server failed to get peer address {:#?}", err);
+						continue;
+					}
+				};
+				info!("server accepted connection from {}", conn_addr);
+				let mut buf = [0_u8; 512];
+				match incoming_conn.read(&mut buf) {
+					Ok(n) => {
+						// dont do this outside of an example
+						unsafe {
+							info!(
+								"server read {} bytes. msg {}",
+								n,
+								String::from_utf8_unchecked(buf[0..n].to_vec()).replace("\n", "")
+							);
 						}
-						drop(wg);
-						return;
-					},
-					default() => {}
-				}
-				match listener.accept() {
-					Ok((mut incoming_conn, conn_addr)) => {
-						info!("server accepted connection from {}", conn_addr);
-						let mut buf = [0_u8; 512];
-						match incoming_conn.read(&mut buf) {
+						match incoming_conn.write(&buf[0..n]) {
 							Ok(n) => {
-								// dont do this outside of an example
-								unsafe {
-									info!(
-										"server read {} bytes. msg {}",
-										n,
-										String::from_utf8_unchecked(buf[0..n].to_vec())
-											.replace("\n", "")
-									);
-								}
-								match incoming_conn.write(&buf[0..n]) {
-									Ok(n) => {
-										info!("server wrote {} bytes", n)
-									}
-									Err(err) => {
-										error!(
-											"server failed to write response for {}: {:#?}",
-											conn_addr, err
-										);
-									}
-								}
+								info!("server wrote {} bytes", n)
 							}
 							Err(err) => {
-								error!("server failed to read data from {}: {:#?}", conn_addr, err);
+								error!(
+									"server failed to write response for {}: {:#?}",
+									conn_addr, err
+								);
 							}
 						}
 					}
 					Err(err) => {
-						error!("server failed to accept connection {:#?}", err);
+						error!("server failed to read data from {}: {:#?}", conn_addr, err);
 					}
 				}
-				// because we called spawn_blocking this wont block main thread
-				std::thread::sleep(std::time::Duration::from_millis(125));
 			}
+			warn!("server listener was shut down, goodbye...");
+			drop(wg);
 		});
-	}
+		server_handle
+	};
 	info!("waiting 10 seconds for tunnel things to happen");
 	// because we used tokio::task::spawn_blocking, we can sleep here
 	// or in the spawned task without either sleeping blocking a thread
@@ -141,9 +106,7 @@ async fn main() {
 	let mut client_conn = match I2pStream::connect(&format!("{}:0", pubkey)) {
 		Ok(client_conn) => client_conn,
 		Err(err) => {
-			if let Err(err) = tx.send(true) {
-				error!("client failed to signal server task to exit {:#?}", err);
-			}
+			server_handle.shutdown();
 			panic!(
 				"client failed to connect to destination {}, {:#?}",
 				i2p::net::I2pAddr::from_b64(&pubkey).unwrap(),
@@ -154,9 +117,7 @@ async fn main() {
 	match client_conn.write(b"hello_world") {
 		Ok(n) => info!("client wrote {} bytes", n),
 		Err(err) => {
-			if let Err(err) = tx.send(true) {
-				error!("client failed to signal server task to exit {:#?}", err);
-			}
+			server_handle.shutdown();
 			panic!("client failed to write into stream {:#?}", err);
 		}
 	}
@@ -170,19 +131,16 @@ async fn main() {
 			);
 		},
 		Err(err) => {
-			if let Err(err) = tx.send(true) {
-				error!("client failed to signal server task to exit {:#?}", err);
-			}
+			server_handle.shutdown();
 			panic!("client failed to read from stream {:#?}", err);
 		}
 	}
-	// send a message through the channel to notify the tokio task
-	// to exit
-	if let Err(err) = tx.send(true) {
-		info!("client failed to signal server task to exit {:#?}", err);
-	}
+	// stop accepting connections, which ends the server task's accept loop
+	server_handle.shutdown();
 	// wait for all references to the wait group to be dropped
 	wg.wait();
+	// give the server's streams a moment to finish before closing them
+	server_handle.drain(time::Duration::from_secs(5));
 	match client_conn.shutdown(Shutdown::Both) {
 		Ok(_) => info!("client shutdown ok"),
 		Err(err) => error!("client failed to properly shutdown {:#?}", err),
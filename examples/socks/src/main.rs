@@ -0,0 +1,32 @@
+use env_logger;
+use i2p::sam::DEFAULT_API;
+use i2p::sam_options::SAMOptions;
+use i2p::tunnel::SocksProxy;
+use std::env;
+
+fn help() {
+	println!("Usage: i2p-socks [listen_addr] [sam_addr]")
+}
+
+fn main() {
+	env_logger::init();
+	let args: Vec<String> = env::args().collect();
+	if args.len() > 3 {
+		help();
+		return;
+	}
+	let listen_addr = args.get(1).map(String::as_str).unwrap_or("127.0.0.1:4447");
+	let sam_addr = args.get(2).map(String::as_str).unwrap_or(DEFAULT_API);
+
+	let proxy = match SocksProxy::bind(listen_addr, sam_addr, SAMOptions::default()) {
+		Ok(proxy) => proxy.with_username_isolation(true),
+		Err(e) => {
+			println!("Failed to start proxy: {}", e.kind());
+			help();
+			return;
+		}
+	};
+	if let Err(e) = proxy.run() {
+		println!("Proxy stopped: {}", e.kind());
+	}
+}
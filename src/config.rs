@@ -0,0 +1,362 @@
+//! sessions described in TOML or JSON config files
+//!
+//! A config file describes one session: where the SAM bridge is, which
+//! destination to use and its options, keyed as in SAM commands. Every
+//! field is optional.
+//!
+//! ```toml
+//! # SAM bridge to create the session on, defaults to 127.0.0.1:7656
+//! sam = "127.0.0.1:7656"
+//! # for a bridge with authentication enabled
+//! user = "alice"
+//! password = "secret"
+//! # SAM versions to accept, 3.0 to 3.2 by default
+//! min_version = "3.0"
+//! max_version = "3.1"
+//! # name of the session, random by default
+//! nickname = "eepsite"
+//! # stream (default), datagram or raw
+//! style = "stream"
+//! # file holding the base64 private key of the destination, relative to the
+//! # config file; a new destination is used if neither this nor
+//! # `destination`, the private key itself, is set
+//! keys = "eepsite.key"
+//! # low_latency, balanced, high_anonymity, bulk_transfer or client_only,
+//! # applied before `options`
+//! preset = "high_anonymity"
+//!
+//! [options]
+//! "inbound.quantity" = 4
+//! "i2cp.leaseSetEncType" = "4,0"
+//! "i2p.streaming.maxConnsPerMinute" = 10
+//! ```
+//!
+//! The same in JSON:
+//!
+//! ```json
+//! {
+//!   "nickname": "eepsite",
+//!   "keys": "eepsite.key",
+//!   "preset": "high_anonymity",
+//!   "options": { "inbound.quantity": 4, "i2p.streaming.maxConnsPerMinute": 10 }
+//! }
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::{Error, ErrorKind};
+use crate::net::I2pListener;
+use crate::sam::{ConnectOptions, SamVersion, Session, SessionBuilder, SessionStyle, DEFAULT_API};
+use crate::sam_options::{self, SAMOptions};
+
+/// A session, as described in a config file.
+///
+/// # Examples
+///
+/// ```no_run
+/// use i2p::config::SessionConfig;
+///
+/// let config = SessionConfig::load("eepsite.toml").unwrap();
+/// let listener = config.listener().unwrap();
+/// for stream in listener.incoming() {
+///     // ...
+/// }
+/// ```
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionConfig {
+	#[serde(default = "default_sam")]
+	pub sam: String,
+	pub user: Option<String>,
+	pub password: Option<String>,
+	pub min_version: Option<String>,
+	pub max_version: Option<String>,
+	pub nickname: Option<String>,
+	#[serde(default = "default_style")]
+	pub style: SessionStyle,
+	/// the base64 private key of the destination
+	pub destination: Option<String>,
+	/// a file holding the base64 private key of the destination
+	pub keys: Option<PathBuf>,
+	pub preset: Option<Preset>,
+	#[serde(default, with = "sam_options::dotted")]
+	pub options: SAMOptions,
+}
+
+/// Leaves the password and the private key out.
+impl fmt::Debug for SessionConfig {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let hidden = |value: &Option<String>| value.as_ref().map(|_| "<hidden>");
+		f.debug_struct("SessionConfig")
+			.field("sam", &self.sam)
+			.field("user", &self.user)
+			.field("password", &hidden(&self.password))
+			.field("min_version", &self.min_version)
+			.field("max_version", &self.max_version)
+			.field("nickname", &self.nickname)
+			.field("style", &self.style)
+			.field("destination", &hidden(&self.destination))
+			.field("keys", &self.keys)
+			.field("preset", &self.preset)
+			.field("options", &self.options)
+			.finish()
+	}
+}
+
+/// The presets of `SAMOptions`, by name.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Preset {
+	LowLatency,
+	Balanced,
+	HighAnonymity,
+	BulkTransfer,
+	ClientOnly,
+}
+
+impl Preset {
+	pub fn options(self) -> SAMOptions {
+		match self {
+			Preset::LowLatency => SAMOptions::low_latency(),
+			Preset::Balanced => SAMOptions::balanced(),
+			Preset::HighAnonymity => SAMOptions::high_anonymity(),
+			Preset::BulkTransfer => SAMOptions::bulk_transfer(),
+			Preset::ClientOnly => SAMOptions::client_only(),
+		}
+	}
+}
+
+fn default_sam() -> String {
+	DEFAULT_API.to_string()
+}
+
+fn default_style() -> SessionStyle {
+	SessionStyle::Stream
+}
+
+fn parse_version(version: &Option<String>, default: SamVersion) -> Result<SamVersion, Error> {
+	match version {
+		Some(version) => version
+			.parse()
+			.map_err(|_| ErrorKind::InvalidConfig(format!("bad SAM version {}", version)).into()),
+		None => Ok(default),
+	}
+}
+
+impl SessionConfig {
+	/// Loads the config in `path`, as JSON if it has a `.json` extension
+	/// and as TOML otherwise. `keys` is relative to the directory of `path`.
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<SessionConfig, Error> {
+		let path = path.as_ref();
+		let text = fs::read_to_string(path)?;
+		let mut config = match path.extension() {
+			Some(ext) if ext == "json" => SessionConfig::from_json(&text)?,
+			_ => SessionConfig::from_toml(&text)?,
+		};
+		if let (Some(keys), Some(dir)) = (&config.keys, path.parent()) {
+			config.keys = Some(dir.join(keys));
+		}
+		Ok(config)
+	}
+
+	#[cfg(feature = "toml")]
+	pub fn from_toml(text: &str) -> Result<SessionConfig, Error> {
+		toml::from_str(text).map_err(|e| ErrorKind::InvalidConfig(e.to_string()).into())
+	}
+
+	#[cfg(not(feature = "toml"))]
+	pub fn from_toml(_text: &str) -> Result<SessionConfig, Error> {
+		Err(ErrorKind::InvalidConfig("built without the toml feature".to_string()).into())
+	}
+
+	#[cfg(feature = "json")]
+	pub fn from_json(text: &str) -> Result<SessionConfig, Error> {
+		serde_json::from_str(text).map_err(|e| ErrorKind::InvalidConfig(e.to_string()).into())
+	}
+
+	#[cfg(not(feature = "json"))]
+	pub fn from_json(_text: &str) -> Result<SessionConfig, Error> {
+		Err(ErrorKind::InvalidConfig("built without the json feature".to_string()).into())
+	}
+
+	/// Returns the options of the session: the preset, if any, with
+	/// `options` applied on top.
+	pub fn sam_options(&self) -> SAMOptions {
+		match self.preset {
+			Some(preset) => {
+				let mut options = preset.options();
+				options.overlay(&self.options);
+				options
+			}
+			None => self.options.clone(),
+		}
+	}
+
+	/// Returns the private key of the destination, or `TRANSIENT` for a new
+	/// one.
+	fn destination(&self) -> Result<String, Error> {
+		match (&self.destination, &self.keys) {
+			(Some(_), Some(_)) => Err(ErrorKind::InvalidConfig(
+				"only one of destination and keys can be set".to_string(),
+			)
+			.into()),
+			(Some(destination), None) => Ok(destination.clone()),
+			(None, Some(keys)) => Ok(fs::read_to_string(keys)?.trim().to_string()),
+			(None, None) => Ok("TRANSIENT".to_string()),
+		}
+	}
+
+	fn connect_options(&self) -> Result<ConnectOptions, Error> {
+		if self.user.is_some() != self.password.is_some() {
+			return Err(ErrorKind::InvalidConfig(
+				"user and password must be set together".to_string(),
+			)
+			.into());
+		}
+		let defaults = ConnectOptions::default();
+		Ok(ConnectOptions {
+			min_version: parse_version(&self.min_version, defaults.min_version)?,
+			max_version: parse_version(&self.max_version, defaults.max_version)?,
+			user: self.user.clone(),
+			password: self.password.clone(),
+			..defaults
+		})
+	}
+
+	/// Creates the session described. Datagram and raw sessions are only
+	/// created this way: there is no datagram socket to build from a config
+	/// while `I2pDatagramSocket` is unimplemented.
+	pub fn session(&self) -> Result<Session, Error> {
+		let mut builder = SessionBuilder::default()
+			.with_addrs(self.sam.as_str())?
+			.with_destination(&self.destination()?)
+			.with_style(self.style.clone())
+			.with_options(self.sam_options())
+			.with_connect_options(self.connect_options()?)?;
+		if let Some(ref nickname) = self.nickname {
+			builder = builder.with_nickname(nickname);
+		}
+		builder.build()
+	}
+
+	/// Creates the session described, which must be a stream one, and
+	/// listens for streams on it.
+	pub fn listener(&self) -> Result<I2pListener, Error> {
+		if let SessionStyle::Stream = self.style {
+			I2pListener::bind_with_session(&self.session()?)
+		} else {
+			Err(ErrorKind::InvalidConfig("listeners need a stream session".to_string()).into())
+		}
+	}
+}
+
+#[cfg(all(test, feature = "toml", feature = "json"))]
+mod tests {
+	use super::{Preset, SessionConfig};
+	use crate::sam::SessionStyle;
+	use crate::test::{default_reply, mock_sam};
+
+	const TOML: &str = r#"
+		sam = "127.0.0.1:7000"
+		style = "datagram"
+		preset = "high_anonymity"
+
+		[options]
+		"inbound.length" = 2
+		"i2cp.dontPublishLeaseSet" = true
+		"i2p.streaming.accessList" = ["h1", "h2"]
+		"outbound.nickname" = "service"
+	"#;
+
+	#[test]
+	fn loads_toml() {
+		let config = SessionConfig::from_toml(TOML).unwrap();
+		assert_eq!(config.sam, "127.0.0.1:7000");
+		assert!(matches!(config.style, SessionStyle::Datagram));
+		assert!(matches!(config.preset, Some(Preset::HighAnonymity)));
+
+		let options = config.sam_options().options();
+		// the preset, with the config on top
+		assert!(options.contains("inbound.length=2 "));
+		assert!(options.contains("outbound.length=3 "));
+		assert!(options.contains("i2cp.dontPublishLeaseSet=true "));
+		assert!(options.contains("i2p.streaming.accessList=h1,h2 "));
+		assert!(options.contains("outbound.nickname=service "));
+	}
+
+	#[test]
+	fn loads_json_like_toml() {
+		let json = r#"{
+			"sam": "127.0.0.1:7000",
+			"style": "datagram",
+			"preset": "high_anonymity",
+			"options": {
+				"inbound.length": 2,
+				"i2cp.dontPublishLeaseSet": true,
+				"i2p.streaming.accessList": ["h1", "h2"],
+				"outbound.nickname": "service"
+			}
+		}"#;
+		let json = SessionConfig::from_json(json).unwrap();
+		let toml = SessionConfig::from_toml(TOML).unwrap();
+		assert_eq!(json.sam_options().options(), toml.sam_options().options());
+	}
+
+	#[test]
+	fn round_trips() {
+		let config = SessionConfig::from_toml(TOML).unwrap();
+		let text = toml::to_string(&config).unwrap();
+		assert!(text.contains("\"inbound.length\" = 2"), "{}", text);
+		let again = SessionConfig::from_toml(&text).unwrap();
+		assert_eq!(
+			again.sam_options().options(),
+			config.sam_options().options()
+		);
+	}
+
+	#[test]
+	fn rejects_bad_config() {
+		for text in &[
+			"[options]\n\"inbound.length\" = \"two\"",
+			"[options]\n\"outbound.nickname\" = \"two words\"",
+			"style = \"carrier-pigeon\"",
+			"sam_addr = \"127.0.0.1:7656\"",
+		] {
+			assert!(SessionConfig::from_toml(text).is_err(), "{}", text);
+		}
+		for text in &[
+			"destination = \"key\"\nkeys = \"file\"",
+			"user = \"alice\"",
+			"max_version = \"3.x\"",
+			"min_version = \"3.2\"\nmax_version = \"3.1\"",
+		] {
+			let config = SessionConfig::from_toml(text).unwrap();
+			assert!(config.session().is_err(), "{}", text);
+		}
+	}
+
+	#[test]
+	fn debug_hides_secrets() {
+		let config = SessionConfig::from_toml(
+			"user = \"alice\"\npassword = \"hunter2\"\ndestination = \"c2VjcmV0\"",
+		)
+		.unwrap();
+		let debug = format!("{:?}", config);
+		assert!(debug.contains("alice"));
+		assert!(!debug.contains("hunter2") && !debug.contains("c2VjcmV0"));
+	}
+
+	#[test]
+	fn creates_session() {
+		let sam = mock_sam(default_reply);
+		let config =
+			SessionConfig::from_toml(&format!("sam = \"{}\"\nnickname = \"configured\"", sam))
+				.unwrap();
+		let session = config.session().unwrap();
+		assert_eq!(session.nickname, "configured");
+	}
+}
@@ -39,10 +39,33 @@ pub enum ErrorKind {
 	SAMTimeout(String),
 	#[fail(display = "Unknown I2P/SAM error: {}", _0)]
 	SAMI2PError(String),
+	#[fail(display = "SAM authentication failed: {}", _0)]
+	SAMAuthFailed(String),
+	#[fail(display = "SAM AUTH command failed: {}", _0)]
+	SAMAuthError(String),
+	/// The bridge and we have no SAM version in common, or the one
+	/// negotiated lacks a feature
+	#[fail(display = "No suitable SAM version: {}", _0)]
+	NoVersion(String),
 	#[fail(display = "I2P address isn't a valid b32 or b64 encoding: {}", _0)]
 	BadAddressEncoding(String),
 	#[fail(display = "Accept encountered error, and session was recreated. try operation again")]
 	SessionRecreated,
+	#[fail(display = "SAM connection pool exhausted: {}", _0)]
+	PoolExhausted(String),
+	#[fail(display = "Listener was shut down")]
+	ListenerClosed,
+	/// A non-blocking operation needs to be retried
+	#[fail(display = "Operation would block")]
+	WouldBlock,
+	#[fail(display = "Invalid option: {}", _0)]
+	InvalidOption(String),
+	#[fail(display = "Invalid configuration: {}", _0)]
+	InvalidConfig(String),
+	#[fail(display = "Invalid SAM transcript: {}", _0)]
+	InvalidTranscript(String),
+	#[fail(display = "TLS error: {}", _0)]
+	Tls(String),
 }
 
 impl ErrorKind {
@@ -102,8 +125,12 @@ impl From<Context<ErrorKind>> for Error {
 
 impl From<io::Error> for Error {
 	fn from(err: io::Error) -> Error {
+		let kind = match err.kind() {
+			io::ErrorKind::WouldBlock => ErrorKind::WouldBlock,
+			_ => ErrorKind::Io(err.to_string()),
+		};
 		Error {
-			inner: Context::new(ErrorKind::Io(err.to_string())),
+			inner: Context::new(kind),
 		}
 	}
 }
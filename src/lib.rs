@@ -1,10 +1,18 @@
+pub mod config;
 pub mod error;
 pub mod net;
 pub mod sam;
 pub mod sam_options;
+pub mod sam_pool;
 pub mod session_watcher;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod tunnel;
 
 mod parsers;
+#[cfg(test)]
+mod test;
 
 pub use crate::error::{Error, ErrorKind};
-pub use crate::sam::{SamConnection, Session};
+pub use crate::sam::{Credentials, SamConnection, Session, SessionBuilder};
+pub use crate::sam_pool::SamConnectionPool;
@@ -30,6 +30,19 @@ lazy_static! {
 	};
 }
 
+/// Encodes `data` with I2P's base64 alphabet, as used for destinations and
+/// private keys.
+pub(crate) fn b64_encode(data: &[u8]) -> String {
+	BASE64_I2P.encode(data)
+}
+
+/// Decodes I2P base64, e.g. a private key returned by the SAM bridge.
+pub(crate) fn b64_decode(data: &str) -> Result<Vec<u8>, Error> {
+	BASE64_I2P
+		.decode(data.as_bytes())
+		.map_err(|_| ErrorKind::BadAddressEncoding(data.to_string()).into())
+}
+
 /// An I2P address, as a Destination, B32 address or hostname.
 ///
 /// # Examples
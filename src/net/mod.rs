@@ -5,7 +5,11 @@ use std::net::{SocketAddr, ToSocketAddrs};
 pub use self::addr::{I2pSocketAddr, ToI2pSocketAddrs};
 pub use self::datagram::I2pDatagramSocket;
 pub use self::i2p::I2pAddr;
-pub use self::streaming::{I2pListenerBuilder, I2pListener, I2pStream};
+pub(crate) use self::i2p::{b64_decode, b64_encode};
+pub use self::streaming::{
+	I2pForwardListener, I2pListener, I2pListenerBuilder, I2pPendingAccept, I2pStream,
+};
+pub use crate::sam::ShutdownHandle;
 
 mod addr;
 mod datagram;
@@ -3,12 +3,23 @@ use std::io::prelude::*;
 use std::fmt;
 use std::io;
 use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, IntoRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
 
 use crate::error::{Error, ErrorKind};
 use crate::net::{I2pAddr, I2pSocketAddr, ToI2pSocketAddrs};
-use crate::sam::{Session, StreamConnect, StreamForward, DEFAULT_API};
+use crate::sam::{
+	remaining, ConnectOptions, Credentials, ForwardListener, PendingAccept, Session,
+	SessionBuilder, ShutdownHandle, StreamConnect, StreamForward, DEFAULT_API,
+};
 use crate::sam_options::SAMOptions;
+use crate::sam_pool::SamConnectionPool;
+#[cfg(feature = "tls")]
+use crate::tls::TlsConfig;
 
 /// A structure which represents an I2P stream between a local socket and a
 /// remote socket.
@@ -36,14 +47,16 @@ pub struct I2pStream {
 	inner: StreamConnect,
 }
 
-/// An infinite iterator over the connections from an `I2pListener`.
+/// An iterator over the connections from an `I2pListener`.
 ///
-/// This iterator will infinitely yield [`Some`] of the accepted connections. It
-/// is equivalent to calling `accept` in a loop.
+/// This iterator will yield [`Some`] of the accepted connections until the
+/// listener is shut down, after which it returns [`None`]. It is equivalent
+/// to calling `accept` in a loop.
 ///
 /// This `struct` is created by the [`incoming`] method on [`I2pListener`].
 ///
 /// [`Some`]: ../../std/option/enum.Option.html#variant.Some
+/// [`None`]: ../../std/option/enum.Option.html#variant.None
 /// [`incoming`]: struct.I2pListener.html#method.incoming
 /// [`I2pListener`]: struct.I2pListener.html
 pub struct Incoming<'a> {
@@ -53,6 +66,56 @@ pub struct Incoming<'a> {
 	listener: &'a I2pListener,
 }
 
+/// An incoming connection being accepted without blocking.
+///
+/// This `struct` is created by the [`start_accept`] method on
+/// [`I2pListener`]. Register it with an event loop such as mio or epoll
+/// through its raw socket, and call [`try_accept`] each time it becomes
+/// readable. Every `I2pPendingAccept` yields at most one stream.
+///
+/// [`start_accept`]: struct.I2pListener.html#method.start_accept
+/// [`I2pListener`]: struct.I2pListener.html
+/// [`try_accept`]: #method.try_accept
+pub struct I2pPendingAccept {
+	inner: PendingAccept,
+}
+
+/// A listener the router pushes incoming streams to with SAM's
+/// `STREAM FORWARD`, instead of one `STREAM ACCEPT` control connection per
+/// stream as with [`I2pListener`].
+///
+/// Streams arrive on a local TCP listener bound to the interface facing
+/// the SAM bridge. They stop coming in once this is dropped or shut down.
+///
+/// # Examples
+///
+/// ```no_run
+/// use i2p::net::I2pForwardListener;
+///
+/// let listener = I2pForwardListener::bind().unwrap();
+/// for stream in listener.incoming() {
+///     match stream {
+///         Ok(stream) => println!("new client: {:?}", stream.peer_addr()),
+///         Err(e) => { /* connection failed */ }
+///     }
+/// }
+/// ```
+///
+/// [`I2pListener`]: struct.I2pListener.html
+pub struct I2pForwardListener {
+	inner: ForwardListener,
+}
+
+/// An iterator over the streams forwarded to an `I2pForwardListener`,
+/// created by its [`incoming`] method. It returns [`None`] once the listener
+/// is shut down.
+///
+/// [`incoming`]: struct.I2pForwardListener.html#method.incoming
+/// [`None`]: ../../std/option/enum.Option.html#variant.None
+pub struct ForwardIncoming<'a> {
+	listener: &'a I2pForwardListener,
+}
+
 impl I2pStream {
 	/// Opens a TCP-like connection to a remote host.
 	///
@@ -78,6 +141,52 @@ impl I2pStream {
 		I2pStream::connect_via(DEFAULT_API, addr, SAMOptions::default())
 	}
 
+	/// Opens a TCP-like connection to a remote host with a timeout.
+	///
+	/// Unlike `connect`, `connect_timeout` takes a single `I2pSocketAddr`
+	/// since a timeout must be applied to individual addresses.
+	///
+	/// The timeout covers creating the transient session, which includes
+	/// building its tunnels, as well as the router's lookup of the remote
+	/// leaseset. When it expires, an error of kind `ErrorKind::SAMTimeout`
+	/// is returned; a zero `Duration` fails right away.
+	///
+	/// # Examples
+	///
+	/// ```no_run
+	/// use std::time::Duration;
+	/// use i2p::net::{I2pAddr, I2pSocketAddr, I2pStream};
+	///
+	/// let addr = I2pSocketAddr::new(I2pAddr::new("example.i2p"), 80);
+	/// let stream = I2pStream::connect_timeout(&addr, Duration::from_secs(60));
+	/// ```
+	pub fn connect_timeout(addr: &I2pSocketAddr, timeout: Duration) -> Result<I2pStream, Error> {
+		I2pStream::connect_via_timeout(DEFAULT_API, addr, SAMOptions::default(), timeout)
+	}
+
+	/// Same as `connect_timeout` but uses the SAM bridge at `sam_addr`. If
+	/// it resolves to several addresses, the timeout is shared by all the
+	/// attempts rather than restarted for each one.
+	pub fn connect_via_timeout<A: ToSocketAddrs>(
+		sam_addr: A,
+		addr: &I2pSocketAddr,
+		options: SAMOptions,
+		timeout: Duration,
+	) -> Result<I2pStream, Error> {
+		// the timeout is for the whole call, not for each bridge address
+		let deadline = Instant::now() + timeout;
+		super::each_i2p_addr(sam_addr, addr, options, |sam_addr, addr, options| {
+			let stream = StreamConnect::new_timeout(
+				sam_addr,
+				&addr.dest().string(),
+				addr.port(),
+				options,
+				remaining(deadline)?,
+			)?;
+			Ok(I2pStream { inner: stream })
+		})
+	}
+
 	/// Same as `connect` but reuses an existing SAM session.
 	pub fn connect_with_session<A: ToI2pSocketAddrs>(
 		session: &Session,
@@ -90,6 +199,40 @@ impl I2pStream {
 		I2pStream::connect_addr_with_session(session, &addr?)
 	}
 
+	/// Same as `connect_timeout` but reuses an existing SAM session, so the
+	/// timeout only covers establishing the stream.
+	pub fn connect_with_session_timeout(
+		session: &Session,
+		addr: &I2pSocketAddr,
+		timeout: Duration,
+	) -> Result<I2pStream, Error> {
+		let stream = StreamConnect::with_session_timeout(
+			session,
+			&addr.dest().string(),
+			addr.port(),
+			timeout,
+		)?;
+
+		Ok(I2pStream { inner: stream })
+	}
+
+	/// Same as `connect_with_session` but takes the control connection for
+	/// the stream from `pool` instead of opening and handshaking a new one.
+	pub fn connect_with_pool<A: ToI2pSocketAddrs>(
+		pool: &SamConnectionPool,
+		session: &Session,
+		addr: A,
+	) -> Result<I2pStream, Error> {
+		let addr: Result<_, Error> = addr
+			.to_socket_addrs()?
+			.next()
+			.ok_or(ErrorKind::UnresolvableAddress.into());
+		let addr = addr?;
+		let stream = pool.connect(session, &addr.dest().string(), addr.port())?;
+
+		Ok(I2pStream { inner: stream })
+	}
+
 	pub fn connect_via<A: ToSocketAddrs, B: ToI2pSocketAddrs>(
 		sam_addr: A,
 		addr: B,
@@ -98,6 +241,40 @@ impl I2pStream {
 		super::each_i2p_addr(sam_addr, addr, options, I2pStream::connect_addr).map_err(|e| e.into())
 	}
 
+	/// Same as `connect_via`, but talks to the SAM bridge over TLS, for
+	/// the session as well as the stream.
+	#[cfg(feature = "tls")]
+	pub fn connect_via_tls<A: ToSocketAddrs, B: ToI2pSocketAddrs>(
+		sam_addr: A,
+		addr: B,
+		options: SAMOptions,
+		tls: TlsConfig,
+	) -> Result<I2pStream, Error> {
+		let session = SessionBuilder::default()
+			.with_addrs(sam_addr)?
+			.with_options(options)
+			.with_tls(tls)
+			.build()?;
+		I2pStream::connect_with_session(&session, addr)
+	}
+
+	/// Same as `connect_via`, but reaches the SAM bridge with the versions,
+	/// credentials and timeout in `connect`, for the session as well as the
+	/// stream.
+	pub fn connect_via_with<A: ToSocketAddrs, B: ToI2pSocketAddrs>(
+		sam_addr: A,
+		addr: B,
+		options: SAMOptions,
+		connect: ConnectOptions,
+	) -> Result<I2pStream, Error> {
+		let session = SessionBuilder::default()
+			.with_addrs(sam_addr)?
+			.with_options(options)
+			.with_connect_options(connect)?
+			.build()?;
+		I2pStream::connect_with_session(&session, addr)
+	}
+
 	fn connect_addr(sam_addr: &SocketAddr, addr: &I2pSocketAddr, options: SAMOptions) -> Result<I2pStream, Error> {
 		let stream = StreamConnect::new(sam_addr, &addr.dest().string(), addr.port(), options)?;
 
@@ -223,6 +400,39 @@ impl Write for I2pStream {
 	}
 }
 
+/// Exposes the data socket of the stream, e.g. for `splice(2)` or
+/// `sendfile(2)`. Bytes read from or written to it are the peer's, except
+/// on a session with TLS to the bridge, where it carries ciphertext: polling
+/// it still works, but the data must go through `Read` and `Write`.
+#[cfg(unix)]
+impl AsRawFd for I2pStream {
+	fn as_raw_fd(&self) -> RawFd {
+		self.inner.as_raw_fd()
+	}
+}
+
+#[cfg(unix)]
+impl AsFd for I2pStream {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		self.inner.as_fd()
+	}
+}
+
+/// Gives up the data socket of the stream, e.g. to hand it to a child
+/// process. Over TLS to the bridge, the socket carries ciphertext and the
+/// TLS session is lost, so this is only of use for plain TCP.
+///
+/// The stream must have been opened on a session that outlives it, as with
+/// `I2pStream::connect_with_session`. A stream opened by `I2pStream::connect`
+/// owns its transient session, which is closed here, and the router tears
+/// down the stream with it.
+#[cfg(unix)]
+impl IntoRawFd for I2pStream {
+	fn into_raw_fd(self) -> RawFd {
+		self.inner.into_raw_fd()
+	}
+}
+
 impl fmt::Debug for I2pStream {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		let mut res = f.debug_struct("I2pStream");
@@ -363,11 +573,125 @@ impl I2pListener {
 		Ok((I2pStream { inner: i2p_stream }, addr))
 	}
 
+	/// Accept a new incoming connection from this listener, waiting at most
+	/// `timeout` for one to come in.
+	///
+	/// When the timeout expires, an error of kind `ErrorKind::SAMTimeout` is
+	/// returned and the pending `STREAM ACCEPT` is cancelled.
+	///
+	/// # Examples
+	///
+	/// ```no_run
+	/// use std::time::Duration;
+	/// use i2p::net::I2pListener;
+	///
+	/// let listener = I2pListener::bind().unwrap();
+	/// match listener.accept_timeout(Duration::from_secs(30)) {
+	///     Ok((_socket, addr)) => println!("new client: {:?}", addr),
+	///     Err(e) => println!("no client within 30 seconds: {:?}", e),
+	/// }
+	/// ```
+	pub fn accept_timeout(&self, timeout: Duration) -> Result<(I2pStream, I2pSocketAddr), Error> {
+		let (i2p_stream, addr) = self.forward.accept_timeout(timeout)?;
+		Ok((I2pStream { inner: i2p_stream }, addr))
+	}
+
+	/// Same as `accept` but takes the control connection used for
+	/// `STREAM ACCEPT` from `pool` instead of opening a new one.
+	pub fn accept_with_pool(
+		&self,
+		pool: &SamConnectionPool,
+	) -> Result<(I2pStream, I2pSocketAddr), Error> {
+		let (i2p_stream, addr) = self.forward.accept_with_pool(pool)?;
+		Ok((I2pStream { inner: i2p_stream }, addr))
+	}
+
+	/// Starts accepting a new incoming connection without blocking until a
+	/// peer connects.
+	///
+	/// Connecting to the SAM bridge still blocks briefly, but waiting for
+	/// the peer does not: drive the returned `I2pPendingAccept` from an event
+	/// loop instead.
+	///
+	/// # Examples
+	///
+	/// ```no_run
+	/// use i2p::net::I2pListener;
+	/// use i2p::ErrorKind;
+	///
+	/// let listener = I2pListener::bind().unwrap();
+	/// let mut pending = listener.start_accept().unwrap();
+	/// loop {
+	///     // wait for the socket of `pending` to become readable
+	///     match pending.try_accept() {
+	///         Ok((_stream, addr)) => {
+	///             println!("new client: {:?}", addr);
+	///             break;
+	///         }
+	///         Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue,
+	///         Err(e) => panic!("accept failed: {:?}", e),
+	///     }
+	/// }
+	/// ```
+	pub fn start_accept(&self) -> Result<I2pPendingAccept, Error> {
+		let inner = self.forward.start_accept()?;
+		Ok(I2pPendingAccept { inner })
+	}
+
+	/// Stops accepting connections.
+	///
+	/// Calls to `accept` blocked in other threads, including those on clones
+	/// created with `try_clone`, return immediately with
+	/// `ErrorKind::ListenerClosed`, and iterators returned by `incoming` end.
+	/// Streams that were already accepted stay open; use `drain` to wait for
+	/// them.
+	///
+	/// # Examples
+	///
+	/// ```no_run
+	/// use std::thread;
+	/// use std::time::Duration;
+	/// use i2p::net::I2pListener;
+	///
+	/// let listener = I2pListener::bind().unwrap();
+	/// let handle = listener.shutdown_handle();
+	/// let server = thread::spawn(move || {
+	///     for stream in listener.incoming() {
+	///         // ...
+	///     }
+	/// });
+	///
+	/// handle.shutdown();
+	/// server.join().unwrap();
+	/// handle.drain(Duration::from_secs(30));
+	/// ```
+	pub fn shutdown(&self) {
+		self.forward.shutdown()
+	}
+
+	/// Returns whether `shutdown` was called on this listener or a clone.
+	pub fn is_shutdown(&self) -> bool {
+		self.forward.is_shutdown()
+	}
+
+	/// Waits up to `timeout` for the streams accepted by this listener to be
+	/// dropped, then shuts down the ones still open. Returns the number of
+	/// streams that had to be closed.
+	pub fn drain(&self, timeout: Duration) -> usize {
+		self.forward.drain(timeout)
+	}
+
+	/// Returns a handle which can shut down and drain this listener from
+	/// another thread, without keeping a clone of the listener around.
+	pub fn shutdown_handle(&self) -> ShutdownHandle {
+		self.forward.shutdown_handle()
+	}
+
 	/// Returns an iterator over the connections being received on this
 	/// listener.
 	///
-	/// The returned iterator will never return [`None`] and will also not yield
-	/// the peer's [`I2pSocketAddr`] structure.
+	/// The returned iterator will return [`None`] once the listener is shut
+	/// down, and will not yield the peer's [`I2pSocketAddr`] structure.
 	///
 	/// [`None`]: ../../std/option/enum.Option.html#variant.None
 	/// [`I2pSocketAddr`]: ../../std/net/struct.I2pSocketAddr.html
@@ -393,10 +717,141 @@ impl I2pListener {
 	}
 }
 
+impl I2pListener {
+	/// Switches to receiving streams through `STREAM FORWARD` on the same
+	/// destination. See `I2pForwardListener`.
+	pub fn forward(&self) -> Result<I2pForwardListener, Error> {
+		let inner = self.forward.forward()?;
+		Ok(I2pForwardListener { inner })
+	}
+}
+
+impl I2pForwardListener {
+	/// Creates a listener on a new transient destination, through the SAM
+	/// bridge at `crate::sam::DEFAULT_API`.
+	pub fn bind() -> Result<I2pForwardListener, Error> {
+		I2pForwardListener::bind_via(DEFAULT_API)
+	}
+
+	pub fn bind_via<A: ToSocketAddrs>(sam_addr: A) -> Result<I2pForwardListener, Error> {
+		let inner = StreamForward::new(sam_addr, SAMOptions::default())?.forward()?;
+		Ok(I2pForwardListener { inner })
+	}
+
+	pub fn bind_with_session(session: &Session) -> Result<I2pForwardListener, Error> {
+		let inner = StreamForward::with_session(session)?.forward()?;
+		Ok(I2pForwardListener { inner })
+	}
+
+	/// Returns the I2P address peers connect to.
+	pub fn local_addr(&self) -> Result<I2pSocketAddr, Error> {
+		self.inner
+			.local_addr()
+			.map(|(d, p)| I2pSocketAddr::new(I2pAddr::new(&d), p))
+	}
+
+	/// Waits for the router to forward a stream, returning it along with
+	/// the address of the peer.
+	pub fn accept(&self) -> Result<(I2pStream, I2pSocketAddr), Error> {
+		let (i2p_stream, addr) = self.inner.accept()?;
+		Ok((I2pStream { inner: i2p_stream }, addr))
+	}
+
+	/// Stops accepting streams, see `I2pListener::shutdown`.
+	pub fn shutdown(&self) {
+		self.inner.shutdown()
+	}
+
+	/// Returns whether `shutdown` was called on this listener.
+	pub fn is_shutdown(&self) -> bool {
+		self.inner.is_shutdown()
+	}
+
+	/// Waits up to `timeout` for the streams forwarded to this listener to
+	/// be dropped, then shuts down the ones still open. Returns the number
+	/// of streams that had to be closed.
+	pub fn drain(&self, timeout: Duration) -> usize {
+		self.inner.drain(timeout)
+	}
+
+	/// Returns a handle which can shut down and drain this listener from
+	/// another thread.
+	pub fn shutdown_handle(&self) -> ShutdownHandle {
+		self.inner.shutdown_handle()
+	}
+
+	/// Returns an iterator over the forwarded streams.
+	pub fn incoming(&self) -> ForwardIncoming<'_> {
+		ForwardIncoming { listener: self }
+	}
+}
+
+impl<'a> Iterator for ForwardIncoming<'a> {
+	type Item = Result<I2pStream, Error>;
+	fn next(&mut self) -> Option<Result<I2pStream, Error>> {
+		match self.listener.accept() {
+			Err(ref e) if e.kind() == ErrorKind::ListenerClosed => None,
+			res => Some(res.map(|p| p.0)),
+		}
+	}
+}
+
+/// Exposes the control socket of the listener's session. It does not become
+/// readable on incoming connections; see `start_accept` for that. Closing it
+/// ends the session.
+#[cfg(unix)]
+impl AsRawFd for I2pListener {
+	fn as_raw_fd(&self) -> RawFd {
+		self.forward.as_raw_fd()
+	}
+}
+
+#[cfg(unix)]
+impl AsFd for I2pListener {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		self.forward.as_fd()
+	}
+}
+
+#[cfg(unix)]
+impl IntoRawFd for I2pListener {
+	fn into_raw_fd(self) -> RawFd {
+		self.forward.into_raw_fd()
+	}
+}
+
+impl I2pPendingAccept {
+	/// Completes the accept if the peer has connected, or returns an error
+	/// of kind `ErrorKind::WouldBlock` if it has not yet.
+	///
+	/// The accepted stream is in nonblocking mode.
+	pub fn try_accept(&mut self) -> Result<(I2pStream, I2pSocketAddr), Error> {
+		let (i2p_stream, addr) = self.inner.try_accept()?;
+		Ok((I2pStream { inner: i2p_stream }, addr))
+	}
+}
+
+#[cfg(unix)]
+impl AsRawFd for I2pPendingAccept {
+	fn as_raw_fd(&self) -> RawFd {
+		self.inner.as_raw_fd()
+	}
+}
+
+#[cfg(windows)]
+impl AsRawSocket for I2pPendingAccept {
+	fn as_raw_socket(&self) -> RawSocket {
+		self.inner.as_raw_socket()
+	}
+}
+
 impl<'a> Iterator for Incoming<'a> {
 	type Item = Result<I2pStream, Error>;
 	fn next(&mut self) -> Option<Result<I2pStream, Error>> {
-		Some(self.listener.accept().map(|p| p.0))
+		match self.listener.accept() {
+			Err(ref e) if e.kind() == ErrorKind::ListenerClosed => None,
+			res => Some(res.map(|p| p.0)),
+		}
 	}
 }
 
@@ -408,7 +863,7 @@ impl<'a> Iterator for Incoming<'a> {
 /// ```no_run
 /// use i2p::net::{I2pListenerBuilder, I2pStream};
 ///
-/// let listener = I2pListenerBuilder::new().build()?;
+/// let listener = I2pListenerBuilder::default().build().unwrap();
 ///
 /// fn handle_client(stream: I2pStream) {
 ///     // ...
@@ -428,6 +883,8 @@ pub struct I2pListenerBuilder {
 	session: Option<Session>,
 	addrs: Vec<SocketAddr>,
 	options: SAMOptions,
+	// credentials and TLS settings, if any
+	connect: Option<SessionBuilder>,
 }
 
 impl Default for I2pListenerBuilder {
@@ -435,7 +892,8 @@ impl Default for I2pListenerBuilder {
 		I2pListenerBuilder {
 			session: None,
 			addrs: vec![],
-			options: Default::default()
+			options: Default::default(),
+			connect: None,
 		}
 	}
 }
@@ -457,6 +915,13 @@ impl I2pListenerBuilder {
 				forward: StreamForward::with_session(&s)?
 			})
 		}
+		else if let Some(connect) = self.connect {
+			let session = connect
+				.with_addrs(self.addrs.as_slice())?
+				.with_options(self.options)
+				.build()?;
+			I2pListener::bind_with_session(&session)
+		}
 		else {
 			// Default to DEFAULT_API if no socket address has been set manually
 			if self.addrs.len() == 0 {
@@ -498,4 +963,69 @@ impl I2pListenerBuilder {
 		self.options = opts;
 		self
 	}
-}
\ No newline at end of file
+
+	/// Authenticate to the SAM bridge with `credentials`. Ignored if
+	/// recreating a listener using a previous session, which keeps its own.
+	pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+		self.connect = Some(
+			self.connect
+				.unwrap_or_default()
+				.with_credentials(credentials),
+		);
+		self
+	}
+
+	/// Reach the SAM bridge with the versions, credentials and timeout in
+	/// `options`, see `SessionBuilder::with_connect_options`. Ignored if
+	/// recreating a listener using a previous session.
+	pub fn with_connect_options(mut self, options: ConnectOptions) -> Result<Self, Error> {
+		self.connect = Some(
+			self.connect
+				.unwrap_or_default()
+				.with_connect_options(options)?,
+		);
+		Ok(self)
+	}
+
+	/// Connect to the SAM bridge over TLS, see `SessionBuilder::with_tls`.
+	/// Ignored if recreating a listener using a previous session.
+	#[cfg(feature = "tls")]
+	pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+		self.connect = Some(self.connect.unwrap_or_default().with_tls(tls));
+		self
+	}
+}
+#[cfg(test)]
+mod tests {
+	use std::thread;
+	use std::time::{Duration, Instant};
+
+	use super::I2pStream;
+	use crate::net::{I2pAddr, I2pSocketAddr};
+	use crate::sam_options::SAMOptions;
+	use crate::test::{default_reply, mock_sam};
+
+	#[test]
+	fn connect_timeout_spans_every_bridge_address() {
+		let stalled = || {
+			mock_sam(|line: &str| {
+				if line.starts_with("SESSION CREATE") {
+					// the router is still building tunnels
+					thread::sleep(Duration::from_secs(2));
+				}
+				default_reply(line)
+			})
+		};
+		let bridges = [stalled(), stalled(), stalled()];
+		let addr = I2pSocketAddr::new(I2pAddr::new("example.i2p"), 0);
+		let start = Instant::now();
+		let result = I2pStream::connect_via_timeout(
+			&bridges[..],
+			&addr,
+			SAMOptions::default(),
+			Duration::from_millis(300),
+		);
+		assert!(result.is_err());
+		assert!(start.elapsed() < Duration::from_millis(800));
+	}
+}
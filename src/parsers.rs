@@ -83,9 +83,22 @@ named!(pub sam_dest_reply <&str, Vec<(&str, &str)> >,
 	)
 );
 
+named!(pub sam_auth_status <&str, Vec<(&str, &str)> >,
+	do_parse!(
+			  tag!("AUTH STATUS ") >>
+		opts: keys_and_values        >>
+			  tag!("\n")           >>
+		(opts)
+	)
+);
+
 #[cfg(test)]
 mod tests {
 	use nom::ErrorKind;
+	use proptest::collection::vec;
+	use proptest::prelude::*;
+
+	use super::{sam_dest_reply, sam_hello, sam_naming_reply, sam_stream_status};
 
 	#[test]
 	fn hello() {
@@ -183,4 +196,47 @@ mod tests {
 			Ok(("", vec![("PUB", "foo"), ("PRIV", "foobar")]))
 		);
 	}
+
+	#[test]
+	fn auth_status() {
+		use crate::parsers::sam_auth_status;
+
+		assert_eq!(
+			sam_auth_status("AUTH STATUS RESULT=OK\n"),
+			Ok(("", vec![("RESULT", "OK")]))
+		);
+		assert_eq!(
+			sam_auth_status("AUTH STATUS RESULT=I2P_ERROR MESSAGE=\"no such user\"\n"),
+			Ok((
+				"",
+				vec![("RESULT", "I2P_ERROR"), ("MESSAGE", "no such user")]
+			))
+		);
+	}
+
+	proptest! {
+		#[test]
+		fn parses_what_bridges_send(
+			pairs in vec(("[A-Za-z0-9]{1,10}", "[^ \t\n\"][^ \t\n]{0,20}|\"[^\"\n]{0,20}\""), 0..8),
+		) {
+			let options: Vec<String> = pairs.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+			let reply = format!("NAMING REPLY {}\n", options.join(" "));
+			let expected: Vec<(&str, &str)> = pairs
+				.iter()
+				.map(|(k, v)| match v.strip_prefix('"') {
+					Some(quoted) => (k.as_str(), &quoted[..quoted.len() - 1]),
+					None => (k.as_str(), v.as_str()),
+				})
+				.collect();
+			prop_assert_eq!(sam_naming_reply(&reply), Ok(("", expected)));
+		}
+
+		#[test]
+		fn never_panics(reply in "\\PC{0,200}\n?") {
+			let _ = sam_hello(&reply);
+			let _ = sam_naming_reply(&reply);
+			let _ = sam_dest_reply(&reply);
+			let _ = sam_stream_status(&reply);
+		}
+	}
 }
@@ -1,29 +1,62 @@
 use std::io::prelude::*;
 
-use std::clone::Clone;
 use std::collections::HashMap;
-use std::io::{self, BufReader};
-use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
-use std::time::Duration;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
 
 use log::debug;
 use nom::IResult;
 use rand::distributions::Alphanumeric;
 use rand::{self, Rng};
+use serde_derive::{Deserialize, Serialize};
 
 use crate::error::{Error, ErrorKind};
 use crate::net::{I2pAddr, I2pSocketAddr};
 use crate::parsers::{
-	sam_dest_reply, sam_hello, sam_naming_reply, sam_session_status, sam_stream_status,
+	sam_auth_status, sam_dest_reply, sam_hello, sam_naming_reply, sam_session_status,
+	sam_stream_status,
 };
 use crate::sam_options::{SAMOptions, SignatureType};
+use crate::sam_pool::{PoolLease, SamConnectionPool};
+#[cfg(feature = "tls")]
+use crate::tls::{TlsConfig, TlsStream};
+
+mod capabilities;
+#[cfg(test)]
+mod fuzz;
+mod transcript;
+mod transport;
+
+pub use self::capabilities::{Capabilities, Router, SamVersion};
+
+use self::transcript::Recording;
+pub use self::transcript::{Direction, Entry, Recorder, ReplayServer, Transcript};
+#[cfg(unix)]
+pub use self::transport::UnixConnector;
+pub use self::transport::{Connector, SamTransport};
 
 pub static DEFAULT_API: &'static str = "127.0.0.1:7656";
 
-static SAM_MIN: &'static str = "3.0";
-static SAM_MAX: &'static str = "3.2";
+const SAM_MIN: SamVersion = SamVersion::V3_0;
+const SAM_MAX: SamVersion = SamVersion::V3_2;
+/// how long `SamConnection::probe` waits for `PONG`
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// how long a forwarded connection may take to send the destination line
+const FORWARD_HEADER_TIMEOUT: Duration = Duration::from_secs(30);
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum SessionStyle {
 	Datagram,
 	Raw,
@@ -31,37 +64,262 @@ pub enum SessionStyle {
 }
 
 #[derive(Debug)]
-pub struct SamConnection {
+pub struct SamConnection<T: SamTransport = TcpStream> {
+	/// The socket to the bridge. Over TLS, reading or writing it directly
+	/// bypasses the TLS session; use the `Read` and `Write` impls instead.
 	#[cfg(feature = "public-conn")]
-	pub conn: TcpStream,
+	pub conn: T,
 	#[cfg(not(feature = "public-conn"))]
-	conn: TcpStream,
+	conn: T,
+	/// where `conn` came from, to open more connections to the same bridge
+	connector: Option<Arc<dyn Connector<Transport = T>>>,
+	params: ConnectParams,
+	#[cfg(feature = "tls")]
+	tls: Option<TlsStream>,
+	recording: Option<Recording>,
+	/// shared with the other connections of the session, which learn from
+	/// the bridge too
+	capabilities: Arc<Mutex<Capabilities>>,
+	/// the pool slot of a connection taken from a `SamConnectionPool`,
+	/// released when the connection is dropped
+	pub(crate) lease: Option<PoolLease>,
+	/// the place of an accepted stream among its listener's, given up when
+	/// the connection is dropped
+	accepted: Option<AcceptedStream>,
+}
+
+/// How to reach a SAM bridge. Kept on each connection, so that sessions
+/// open their extra connections the same way.
+#[derive(Clone, Debug)]
+struct ConnectParams {
+	min_version: SamVersion,
+	max_version: SamVersion,
+	auth: Option<Credentials>,
+	#[cfg(feature = "tls")]
+	tls: Option<TlsConfig>,
+	recorder: Option<Recorder>,
+}
+
+/// How `SamConnection::connect_with` reaches the bridge.
+///
+/// # Examples
+///
+/// An old router that misbehaves with anything newer than SAM 3.1:
+///
+/// ```no_run
+/// use i2p::sam::{ConnectOptions, SamConnection, SamVersion};
+///
+/// let options = ConnectOptions {
+///     max_version: SamVersion::V3_1,
+///     ..Default::default()
+/// };
+/// let sam = SamConnection::connect_with("127.0.0.1:7656", options).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+	/// the oldest SAM version to accept, 3.0 by default
+	pub min_version: SamVersion,
+	/// the newest SAM version to ask for, 3.2 by default
+	pub max_version: SamVersion,
+	/// `user` and `password` are both set for a bridge with authentication
+	/// enabled, or both left out
+	pub user: Option<String>,
+	pub password: Option<String>,
+	/// give up with `ErrorKind::SAMTimeout` if connecting and the `HELLO`
+	/// exchange take longer
+	pub timeout: Option<Duration>,
+}
+
+/// A user and password for a SAM bridge with authentication enabled, sent
+/// along with `HELLO`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+	pub user: String,
+	pub password: String,
+}
+
+/// A helper struct for creating `Session`s.
+///
+/// # Examples
+///
+/// ```no_run
+/// use i2p::sam::{Credentials, SessionBuilder};
+/// use i2p::sam_options::SAMOptions;
+///
+/// let session = SessionBuilder::default()
+///     .with_nickname("eepsite")
+///     .with_options(SAMOptions::balanced())
+///     .with_credentials(Credentials::new("alice", "secret"))
+///     .build()
+///     .unwrap();
+/// ```
+///
+/// Any other [Connector] can be used to reach the bridge, for instance one
+/// on a Unix domain socket:
+///
+/// ```no_run
+/// # #[cfg(unix)]
+/// # {
+/// use i2p::sam::{SessionBuilder, StreamConnect, UnixConnector};
+///
+/// let session = SessionBuilder::default()
+///     .with_connector(UnixConnector::new("/run/i2p/sam.sock"))
+///     .build()
+///     .unwrap();
+/// let stream = StreamConnect::with_session(&session, "example.i2p", 0).unwrap();
+/// # }
+/// ```
+pub struct SessionBuilder<T: SamTransport = TcpStream> {
+	connectors: Vec<Arc<dyn Connector<Transport = T>>>,
+	/// `connectors` only holds `DEFAULT_API`, to be replaced by `with_addr`
+	default_api: bool,
+	destination: String,
+	nickname: Option<String>,
+	style: SessionStyle,
+	options: SAMOptions,
+	params: ConnectParams,
+	timeout: Option<Duration>,
 }
 
 #[derive(Debug)]
-pub struct Session {
+pub struct Session<T: SamTransport = TcpStream> {
 	#[cfg(feature = "public-conn")]
-	pub sam: SamConnection,
+	pub sam: SamConnection<T>,
 	#[cfg(not(feature = "public-conn"))]
-	sam: SamConnection,
+	sam: SamConnection<T>,
 	pub local_dest: String,
 	pub nickname: String,
 }
 #[derive(Debug)]
-pub struct StreamConnect {
+pub struct StreamConnect<T: SamTransport = TcpStream> {
 	#[cfg(feature = "public-conn")]
-	pub sam: SamConnection,
+	pub sam: SamConnection<T>,
 	#[cfg(not(feature = "public-conn"))]
-	sam: SamConnection,
+	sam: SamConnection<T>,
 	#[cfg(feature = "public-conn")]
-	pub session: Session,
+	pub session: Session<T>,
 	#[cfg(not(feature = "public-conn"))]
-	session: Session,
+	session: Session<T>,
 	pub peer_dest: String,
 	pub peer_port: u16,
 	pub local_port: u16,
 }
 
+impl ConnectParams {
+	fn with_auth(auth: Option<Credentials>) -> ConnectParams {
+		ConnectParams {
+			auth,
+			..Default::default()
+		}
+	}
+
+	fn check(&self) -> Result<(), Error> {
+		if self.min_version > self.max_version {
+			return Err(ErrorKind::InvalidOption(format!(
+				"SAM version range {} to {} is empty",
+				self.min_version, self.max_version
+			))
+			.into());
+		}
+		match self.auth {
+			Some(ref auth) => auth.check(),
+			None => Ok(()),
+		}
+	}
+}
+
+impl Default for ConnectParams {
+	fn default() -> ConnectParams {
+		ConnectParams {
+			min_version: SAM_MIN,
+			max_version: SAM_MAX,
+			auth: None,
+			#[cfg(feature = "tls")]
+			tls: None,
+			recorder: None,
+		}
+	}
+}
+
+impl ConnectOptions {
+	fn credentials(&self) -> Result<Option<Credentials>, Error> {
+		match (&self.user, &self.password) {
+			(Some(user), Some(password)) => Ok(Some(Credentials::new(user, password))),
+			(None, None) => Ok(None),
+			_ => Err(ErrorKind::InvalidOption(
+				"user and password must be set together".to_string(),
+			)
+			.into()),
+		}
+	}
+
+	fn params(&self) -> Result<ConnectParams, Error> {
+		let params = ConnectParams {
+			min_version: self.min_version,
+			max_version: self.max_version,
+			..ConnectParams::with_auth(self.credentials()?)
+		};
+		params.check()?;
+		Ok(params)
+	}
+}
+
+impl Default for ConnectOptions {
+	fn default() -> ConnectOptions {
+		ConnectOptions {
+			min_version: SAM_MIN,
+			max_version: SAM_MAX,
+			user: None,
+			password: None,
+			timeout: None,
+		}
+	}
+}
+
+impl Credentials {
+	pub fn new(user: &str, password: &str) -> Credentials {
+		Credentials {
+			user: user.to_string(),
+			password: password.to_string(),
+		}
+	}
+
+	fn check(&self) -> Result<(), Error> {
+		check_credential("USER", &self.user)?;
+		check_credential("PASSWORD", &self.password)
+	}
+
+	fn options(&self) -> String {
+		format!(
+			"USER=\"{user}\" PASSWORD=\"{password}\"",
+			user = self.user,
+			password = self.password,
+		)
+	}
+}
+
+/// Credentials are sent quoted, so they can't hold quotes, line breaks or
+/// the backslashes that escape them.
+fn check_credential(key: &str, value: &str) -> Result<(), Error> {
+	if value.is_empty() || value.contains(['"', '\\', '\n', '\r']) {
+		return Err(ErrorKind::InvalidOption(format!(
+			"{} must be non-empty and free of quotes, backslashes and line breaks",
+			key
+		))
+		.into());
+	}
+	Ok(())
+}
+
+/// Leaves the password out.
+impl fmt::Debug for Credentials {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Credentials")
+			.field("user", &self.user)
+			.finish_non_exhaustive()
+	}
+}
+
 impl SessionStyle {
 	fn string(&self) -> &str {
 		match *self {
@@ -72,11 +330,10 @@ impl SessionStyle {
 	}
 }
 
-fn verify_response<'a>(vec: &'a [(&str, &str)]) -> Result<HashMap<&'a str, &'a str>, Error> {
-	let new_vec = vec.clone();
-	let map: HashMap<&str, &str> = new_vec.iter().map(|&(k, v)| (k, v)).collect();
-	let res = map.get("RESULT").unwrap_or(&"OK").clone();
-	let msg = map.get("MESSAGE").unwrap_or(&"").clone();
+fn verify_response<'a>(vec: &[(&'a str, &'a str)]) -> Result<HashMap<&'a str, &'a str>, Error> {
+	let map: HashMap<&str, &str> = vec.iter().cloned().collect();
+	let res = map.get("RESULT").cloned().unwrap_or("OK");
+	let msg = map.get("MESSAGE").cloned().unwrap_or("");
 	match res {
 		"OK" => Ok(map),
 		"CANT_REACH_PEER" => Err(ErrorKind::SAMCantReachPeer(msg.to_string()).into()),
@@ -87,66 +344,327 @@ fn verify_response<'a>(vec: &'a [(&str, &str)]) -> Result<HashMap<&'a str, &'a s
 		"INVALID_ID" => Err(ErrorKind::SAMInvalidId(msg.to_string()).into()),
 		"TIMEOUT" => Err(ErrorKind::SAMTimeout(msg.to_string()).into()),
 		"I2P_ERROR" => Err(ErrorKind::SAMI2PError(msg.to_string()).into()),
+		"NOVERSION" => Err(ErrorKind::NoVersion(msg.to_string()).into()),
 		_ => Err(ErrorKind::SAMInvalidMessage(msg.to_string()).into()),
 	}
 }
 
-impl SamConnection {
+impl<T: SamTransport> SamConnection<T> {
 	fn send<F>(&mut self, msg: String, reply_parser: F) -> Result<HashMap<String, String>, Error>
 	where
 		F: Fn(&str) -> IResult<&str, Vec<(&str, &str)>>,
 	{
-		debug!("-> {}", &msg);
-		self.conn.write_all(&msg.into_bytes())?;
-
-		let mut reader = BufReader::new(&self.conn);
-		let mut buffer = String::new();
-		reader.read_line(&mut buffer)?;
-		debug!("<- {}", &buffer);
-
-		let vec_opts = reply_parser(&buffer)?.1;
-		verify_response(&vec_opts).map(|m| {
-			m.iter()
-				.map(|(k, v)| (k.to_string(), v.to_string()))
-				.collect()
-		})
+		self.write_command(&msg).map_err(|e| self.io_error(e))?;
+
+		let mut buffer = Vec::new();
+		let reply = self.read_line(&mut buffer).map_err(|e| self.io_error(e))?;
+		debug!("<- {}", &reply);
+
+		parse_reply(&reply, reply_parser)
 	}
 
 	fn handshake(&mut self) -> Result<HashMap<String, String>, Error> {
-		let hello_msg = format!(
-			"HELLO VERSION MIN={min} MAX={max} \n",
-			min = SAM_MIN,
-			max = SAM_MAX
-		);
-		self.send(hello_msg, sam_hello)
+		let (min, max) = (self.params.min_version, self.params.max_version);
+		let mut hello_msg = format!("HELLO VERSION MIN={min} MAX={max} ", min = min, max = max);
+		if let Some(ref auth) = self.params.auth {
+			hello_msg.push_str(&auth.options());
+			hello_msg.push(' ');
+		}
+		hello_msg.push('\n');
+		let reply = self
+			.send(hello_msg, sam_hello)
+			.map_err(|e| match e.kind() {
+				ErrorKind::SAMI2PError(ref msg) if is_auth_failure(msg) => {
+					Error::from(ErrorKind::SAMAuthFailed(msg.clone()))
+				}
+				ErrorKind::NoVersion(_) => Error::from(ErrorKind::NoVersion(format!(
+					"the bridge speaks none of SAM {} to {}",
+					min, max
+				))),
+				_ => e,
+			})?;
+		// VERSION was only added to the reply with SAM 3.1
+		let version = match reply.get("VERSION") {
+			Some(version) => version.parse()?,
+			None => SamVersion::V3_0,
+		};
+		if version < min || version > max {
+			return Err(ErrorKind::NoVersion(format!(
+				"the bridge picked SAM {}, outside of {} to {}",
+				version, min, max
+			))
+			.into());
+		}
+		self.capabilities = Arc::new(Mutex::new(Capabilities::for_version(version)));
+		Ok(reply)
 	}
 
-	pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<SamConnection, Error> {
-		let tcp_stream = TcpStream::connect(addr)?;
+	/// Connects to the bridge through `connector`. Sessions created on the
+	/// connection open their other connections through it too.
+	pub fn connect_via<C: Connector<Transport = T>>(
+		connector: C,
+	) -> Result<SamConnection<T>, Error> {
+		Self::open(Arc::new(connector), ConnectParams::default(), None)
+	}
+
+	fn open(
+		connector: Arc<dyn Connector<Transport = T>>,
+		params: ConnectParams,
+		deadline: Option<Instant>,
+	) -> Result<SamConnection<T>, Error> {
+		params.check()?;
+		let timeout = match deadline {
+			Some(deadline) => Some(remaining(deadline)?),
+			None => None,
+		};
+		let conn = connector.connect(timeout).map_err(|e| match e.kind() {
+			io::ErrorKind::TimedOut => ErrorKind::SAMTimeout(e.to_string()).into(),
+			_ => Error::from(e),
+		})?;
+		Self::establish(conn, Some(connector), params, deadline)
+	}
 
-		let mut socket = SamConnection { conn: tcp_stream };
+	/// Runs the TLS handshake, if any, and the HELLO exchange on a new
+	/// connection.
+	fn establish(
+		conn: T,
+		connector: Option<Arc<dyn Connector<Transport = T>>>,
+		params: ConnectParams,
+		deadline: Option<Instant>,
+	) -> Result<SamConnection<T>, Error> {
+		let mut socket = SamConnection::new(conn, connector, params);
+		socket.arm(deadline)?;
+		socket.start_tls()?;
 		socket.handshake()?;
+		socket.disarm(deadline)?;
 
 		Ok(socket)
 	}
 
+	fn new(
+		conn: T,
+		connector: Option<Arc<dyn Connector<Transport = T>>>,
+		params: ConnectParams,
+	) -> SamConnection<T> {
+		let recording = params.recorder.as_ref().map(Recorder::recording);
+		SamConnection {
+			conn,
+			connector,
+			params,
+			#[cfg(feature = "tls")]
+			tls: None,
+			recording,
+			capabilities: Default::default(),
+			lease: None,
+			accepted: None,
+		}
+	}
+
+	/// Opens another connection to the same bridge, with the same
+	/// credentials and TLS settings.
+	fn reconnect(&self, deadline: Option<Instant>) -> Result<SamConnection<T>, Error> {
+		let mut conn = match self.connector {
+			Some(ref connector) => Self::open(connector.clone(), self.params.clone(), deadline)?,
+			None => {
+				return Err(
+					ErrorKind::Io("no way to reconnect to the SAM bridge".to_string()).into(),
+				)
+			}
+		};
+		if conn.version() == self.version() {
+			conn.capabilities = self.capabilities.clone();
+		}
+		Ok(conn)
+	}
+
+	/// Runs the TLS handshake if the connection is to go over TLS.
+	#[cfg(feature = "tls")]
+	fn start_tls(&mut self) -> Result<(), Error> {
+		if let Some(ref config) = self.params.tls {
+			let tcp = self.conn.as_tcp().ok_or_else(|| {
+				Error::from(ErrorKind::Tls("TLS needs a TCP transport".to_string()))
+			})?;
+			self.tls = Some(config.connect(tcp.try_clone()?)?);
+		}
+		Ok(())
+	}
+
+	#[cfg(not(feature = "tls"))]
+	fn start_tls(&mut self) -> Result<(), Error> {
+		Ok(())
+	}
+
+	#[cfg(feature = "tls")]
+	fn uses_tls(&self) -> bool {
+		self.tls.is_some()
+	}
+
+	#[cfg(not(feature = "tls"))]
+	fn uses_tls(&self) -> bool {
+		false
+	}
+
+	/// Sends a command line to the bridge.
+	fn write_command(&mut self, msg: &str) -> io::Result<()> {
+		debug!("-> {}", msg);
+		if let Some(ref recording) = self.recording {
+			recording.record(Direction::Client, msg);
+		}
+		self.write_all(msg.as_bytes())
+	}
+
+	/// Reads up to and including the next newline, see
+	/// `SamTransport::read_line`.
+	fn read_line(&mut self, buffer: &mut Vec<u8>) -> io::Result<String> {
+		#[cfg(feature = "tls")]
+		let line = match self.tls {
+			Some(ref tls) => tls.read_line(buffer),
+			None => self.conn.read_line(buffer),
+		}?;
+		#[cfg(not(feature = "tls"))]
+		let line = self.conn.read_line(buffer)?;
+		if let Some(ref recording) = self.recording {
+			recording.record(Direction::Bridge, &line);
+		}
+		Ok(line)
+	}
+
+	/// Records the commands and replies exchanged from now on to `recorder`,
+	/// along with those of the connections later opened on behalf of a
+	/// session created on this one. Stream data is not recorded.
+	pub fn record(&mut self, recorder: &Recorder) {
+		self.recording = Some(recorder.recording());
+		self.params.recorder = Some(recorder.clone());
+	}
+
 	// TODO: Implement a lookup table
 	pub fn naming_lookup(&mut self, name: &str) -> Result<String, Error> {
 		let naming_lookup_msg = format!("NAMING LOOKUP NAME={name} \n", name = name);
-		let ret = self.send(naming_lookup_msg, sam_naming_reply)?;
-		Ok(ret["VALUE"].clone())
+		let mut ret = self.send(naming_lookup_msg, sam_naming_reply)?;
+		reply_value(&mut ret, "VALUE")
 	}
 
 	pub fn generate_destination(
 		&mut self,
 		signature_type: SignatureType,
 	) -> Result<(String, String), Error> {
-		let dest_gen_msg = format!(
-			"DEST GENERATE SIGNATURE_TYPE={signature_type} \n",
-			signature_type = signature_type.to_string(),
-		);
-		let ret = self.send(dest_gen_msg, sam_dest_reply)?;
-		Ok((ret["PUB"].clone(), ret["PRIV"].clone()))
+		// DSA_SHA1 is all there was before SIGNATURE_TYPE came with SAM 3.1
+		let dest_gen_msg = if matches!(signature_type, SignatureType::DsaSha1)
+			&& self.version() < SamVersion::V3_1
+		{
+			"DEST GENERATE \n".to_string()
+		} else {
+			self.require(SamVersion::V3_1, "SIGNATURE_TYPE")?;
+			format!(
+				"DEST GENERATE SIGNATURE_TYPE={signature_type} \n",
+				signature_type = signature_type,
+			)
+		};
+		let mut ret = self.send(dest_gen_msg, sam_dest_reply)?;
+		Ok((
+			reply_value(&mut ret, "PUB")?,
+			reply_value(&mut ret, "PRIV")?,
+		))
+	}
+
+	/// The credentials this connection authenticated with, if any.
+	pub fn credentials(&self) -> Option<&Credentials> {
+		self.params.auth.as_ref()
+	}
+
+	/// The SAM version negotiated with the bridge.
+	pub fn version(&self) -> SamVersion {
+		self.capabilities().version
+	}
+
+	/// What the bridge supports, going by the negotiated version and what
+	/// it did so far.
+	pub fn capabilities(&self) -> Capabilities {
+		*self.capabilities.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	/// Finds out whether the bridge answers `PING`, as SAM 3.2 allows.
+	///
+	/// A bridge that doesn't know the command may drop the connection, so
+	/// probe a connection of its own, see `Session::probe`.
+	pub fn probe(&mut self) -> Result<Capabilities, Error> {
+		if self.capabilities().ping {
+			let deadline = Some(Instant::now() + PROBE_TIMEOUT);
+			self.arm(deadline)?;
+			self.write_command("PING probe\n")
+				.map_err(|e| self.io_error(e))?;
+			let mut buffer = Vec::new();
+			let ping = match self.read_line(&mut buffer) {
+				Ok(reply) => reply.trim_end() == "PONG probe",
+				// dropped or left unanswered
+				Err(_) => false,
+			};
+			let _ = self.disarm(deadline);
+			self.capabilities
+				.lock()
+				.unwrap_or_else(|e| e.into_inner())
+				.ping = ping;
+		}
+		Ok(self.capabilities())
+	}
+
+	/// The ports on the destination line of an accepted stream, see
+	/// `Capabilities::accept_ports`.
+	fn peer_ports(&self, line: &str) -> (u16, u16) {
+		self.capabilities
+			.lock()
+			.unwrap_or_else(|e| e.into_inner())
+			.observe_peer_line(line)
+	}
+
+	/// Fails with `ErrorKind::NoVersion` unless at least SAM `version` was
+	/// negotiated, which `feature` needs.
+	pub fn require(&self, version: SamVersion, feature: &str) -> Result<(), Error> {
+		if self.version() < version {
+			return Err(ErrorKind::NoVersion(format!(
+				"{} needs SAM {}, the bridge speaks {}",
+				feature,
+				version,
+				self.version()
+			))
+			.into());
+		}
+		Ok(())
+	}
+
+	/// Requires clients of the bridge to authenticate from now on. The bridge
+	/// refuses unless at least one user has been added with `auth_add`.
+	/// Like the other `AUTH` commands, needs SAM 3.2.
+	pub fn auth_enable(&mut self) -> Result<(), Error> {
+		self.auth_command("AUTH ENABLE\n".to_string())
+	}
+
+	/// Lets clients use the bridge without authenticating.
+	pub fn auth_disable(&mut self) -> Result<(), Error> {
+		self.auth_command("AUTH DISABLE\n".to_string())
+	}
+
+	/// Adds a user to the bridge.
+	pub fn auth_add(&mut self, credentials: &Credentials) -> Result<(), Error> {
+		credentials.check()?;
+		self.auth_command(format!("AUTH ADD {}\n", credentials.options()))
+	}
+
+	/// Removes a user from the bridge.
+	pub fn auth_remove(&mut self, user: &str) -> Result<(), Error> {
+		check_credential("USER", user)?;
+		self.auth_command(format!("AUTH REMOVE USER=\"{user}\"\n", user = user))
+	}
+
+	fn auth_command(&mut self, msg: String) -> Result<(), Error> {
+		self.require(SamVersion::V3_2, "AUTH")?;
+		match self.send(msg, sam_auth_status) {
+			Ok(_) => Ok(()),
+			Err(e) => match e.kind() {
+				ErrorKind::SAMI2PError(msg) => Err(ErrorKind::SAMAuthError(msg).into()),
+				_ => Err(e),
+			},
+		}
 	}
 
 	pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
@@ -158,18 +676,166 @@ impl SamConnection {
 	pub fn set_write_timeout(&self, duration: Option<Duration>) -> std::io::Result<()> {
 		self.conn.set_write_timeout(duration)
 	}
-	pub fn duplicate(&self) -> Result<SamConnection, Error> {
+	/// Sets the read and write timeouts to the time left until `deadline`,
+	/// failing with `SAMTimeout` once it has passed. Leaves the timeouts
+	/// alone when there is no deadline.
+	fn arm(&self, deadline: Option<Instant>) -> Result<(), Error> {
+		if let Some(deadline) = deadline {
+			let timeout = remaining(deadline)?;
+			self.conn.set_read_timeout(Some(timeout))?;
+			self.conn.set_write_timeout(Some(timeout))?;
+		}
+		Ok(())
+	}
+
+	/// Clears the timeouts set by `arm` once the exchange is complete.
+	fn disarm(&self, deadline: Option<Instant>) -> Result<(), Error> {
+		if deadline.is_some() {
+			self.conn.set_read_timeout(None)?;
+			self.conn.set_write_timeout(None)?;
+		}
+		Ok(())
+	}
+
+	/// Reports an expired socket timeout as `SAMTimeout` rather than `Io`.
+	fn io_error(&self, e: io::Error) -> Error {
+		let timed_out = match e.kind() {
+			io::ErrorKind::TimedOut => true,
+			// how an expired SO_RCVTIMEO shows up on unix
+			io::ErrorKind::WouldBlock => match self.conn.read_timeout() {
+				Ok(timeout) => timeout.is_some(),
+				Err(_) => false,
+			},
+			_ => false,
+		};
+		if timed_out {
+			ErrorKind::SAMTimeout(e.to_string()).into()
+		} else {
+			e.into()
+		}
+	}
+
+	pub fn duplicate(&self) -> Result<SamConnection<T>, Error> {
 		self.conn
 			.try_clone()
-			.map(|s| SamConnection { conn: s })
+			.map(|s| SamConnection {
+				conn: s,
+				connector: self.connector.clone(),
+				params: self.params.clone(),
+				#[cfg(feature = "tls")]
+				tls: self.tls.clone(),
+				recording: self.recording.clone(),
+				capabilities: self.capabilities.clone(),
+				lease: None,
+				accepted: None,
+			})
 			.map_err(|e| e.into())
 	}
-	/// attempts to return a handle to the underlying socket
-	pub fn try_clone(&self) -> std::io::Result<TcpStream> {
+	/// attempts to return a handle to the underlying socket, which bypasses
+	/// TLS if the connection uses it
+	pub fn try_clone(&self) -> std::io::Result<T> {
 		self.conn.try_clone()
 	}
 }
 
+impl SamConnection {
+	pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<SamConnection, Error> {
+		Self::connect_params(addr, ConnectParams::default(), None)
+	}
+
+	/// Same as `connect`, with the SAM versions, credentials and timeout in
+	/// `options`. Fails with `ErrorKind::NoVersion` if the bridge speaks none
+	/// of the versions allowed.
+	pub fn connect_with<A: ToSocketAddrs>(
+		addr: A,
+		options: ConnectOptions,
+	) -> Result<SamConnection, Error> {
+		let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+		Self::connect_params(addr, options.params()?, deadline)
+	}
+
+	/// Same as `connect`, but authenticates to the bridge with `credentials`.
+	/// Fails with `ErrorKind::SAMAuthFailed` if the bridge rejects them.
+	pub fn connect_with_credentials<A: ToSocketAddrs>(
+		addr: A,
+		credentials: Credentials,
+	) -> Result<SamConnection, Error> {
+		Self::connect_params(addr, ConnectParams::with_auth(Some(credentials)), None)
+	}
+
+	/// Same as `connect`, but over TLS, authenticating the bridge as set in
+	/// `tls`. Fails with `ErrorKind::Tls` if the bridge can't be trusted.
+	#[cfg(feature = "tls")]
+	pub fn connect_tls<A: ToSocketAddrs>(
+		addr: A,
+		tls: TlsConfig,
+		credentials: Option<Credentials>,
+	) -> Result<SamConnection, Error> {
+		let mut params = ConnectParams::with_auth(credentials);
+		params.tls = Some(tls);
+		Self::connect_params(addr, params, None)
+	}
+
+	/// Tries each address of `addr` in turn.
+	fn connect_params<A: ToSocketAddrs>(
+		addr: A,
+		params: ConnectParams,
+		deadline: Option<Instant>,
+	) -> Result<SamConnection, Error> {
+		params.check()?;
+		let mut last_err = None;
+		for addr in addr.to_socket_addrs()? {
+			match Self::open(Arc::new(addr), params.clone(), deadline) {
+				Ok(conn) => return Ok(conn),
+				Err(e) => last_err = Some(e),
+			}
+		}
+		Err(last_err.unwrap_or_else(|| ErrorKind::UnresolvableAddress.into()))
+	}
+
+	/// Same as `connect`, but gives up with `ErrorKind::SAMTimeout` if the
+	/// TCP connection and HELLO exchange take longer than `timeout`.
+	pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> Result<SamConnection, Error> {
+		let deadline = Instant::now() + timeout;
+		Self::connect_params(addr, ConnectParams::default(), Some(deadline))
+	}
+}
+
+/// Over TLS, reads and writes go through the TLS session rather than
+/// straight to `conn`. Duplicates of the connection share that session.
+impl<T: SamTransport> Read for SamConnection<T> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		#[cfg(feature = "tls")]
+		{
+			if let Some(ref tls) = self.tls {
+				return tls.read(buf);
+			}
+		}
+		self.conn.read(buf)
+	}
+}
+
+impl<T: SamTransport> Write for SamConnection<T> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		#[cfg(feature = "tls")]
+		{
+			if let Some(ref tls) = self.tls {
+				return tls.write(buf);
+			}
+		}
+		self.conn.write(buf)
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		#[cfg(feature = "tls")]
+		{
+			if let Some(ref tls) = self.tls {
+				return tls.flush();
+			}
+		}
+		self.conn.flush()
+	}
+}
+
 impl Session {
 	/// Create a new session using all provided parameters
 	pub fn create<A: ToSocketAddrs>(
@@ -179,27 +845,27 @@ impl Session {
 		style: SessionStyle,
 		options: SAMOptions,
 	) -> Result<Session, Error> {
-		let mut sam = SamConnection::connect(sam_addr)?;
-		let create_session_msg = format!(
-			// values for SIGNATURE_TYPE and leaseSetEncType taken from
-			// https://github.com/eyedeekay/goSam/blob/62cade9ebc26e48ff32a517ef94212fc90aa92cd/client.go#L169
-			// https://github.com/eyedeekay/goSam/blob/62cade9ebc26e48ff32a517ef94212fc90aa92cd/client.go#L166
-			"SESSION CREATE STYLE={style} ID={nickname} DESTINATION={destination} {options}\n",
-			style = style.string(),
-			nickname = nickname,
-			destination = destination,
-			options = options.options(),
-		);
-
-		sam.send(create_session_msg, sam_session_status)?;
-
-		let local_dest = sam.naming_lookup("ME")?;
+		options.check()?;
+		let sam = SamConnection::connect(sam_addr)?;
+		Self::create_on(sam, destination, nickname, style, options, None)
+	}
 
-		Ok(Session {
-			sam: sam,
-			local_dest: local_dest,
-			nickname: nickname.to_string(),
-		})
+	/// Same as `create`, but gives up with `ErrorKind::SAMTimeout` if the
+	/// router does not have the session ready within `timeout`, which
+	/// includes building its tunnels.
+	pub fn create_timeout(
+		sam_addr: &SocketAddr,
+		destination: &str,
+		nickname: &str,
+		style: SessionStyle,
+		options: SAMOptions,
+		timeout: Duration,
+	) -> Result<Session, Error> {
+		options.check()?;
+		let deadline = Instant::now() + timeout;
+		let sam =
+			SamConnection::connect_params(sam_addr, ConnectParams::default(), Some(deadline))?;
+		Self::create_on(sam, destination, nickname, style, options, Some(deadline))
 	}
 
 	/// Create a new session identified by the provided destination. Auto-generates
@@ -233,12 +899,84 @@ impl Session {
 	pub fn sam_api(&self) -> Result<SocketAddr, Error> {
 		self.sam.conn.peer_addr().map_err(|e| e.into())
 	}
+}
+
+impl<T: SamTransport> Session<T> {
+	fn create_on(
+		mut sam: SamConnection<T>,
+		destination: &str,
+		nickname: &str,
+		style: SessionStyle,
+		options: SAMOptions,
+		deadline: Option<Instant>,
+	) -> Result<Session<T>, Error> {
+		if options.from_port.is_some() || options.to_port.is_some() {
+			sam.require(SamVersion::V3_2, "FROM_PORT and TO_PORT")?;
+		}
+		let create_session_msg = format!(
+			// values for SIGNATURE_TYPE and leaseSetEncType taken from
+			// https://github.com/eyedeekay/goSam/blob/62cade9ebc26e48ff32a517ef94212fc90aa92cd/client.go#L169
+			// https://github.com/eyedeekay/goSam/blob/62cade9ebc26e48ff32a517ef94212fc90aa92cd/client.go#L166
+			"SESSION CREATE STYLE={style} ID={nickname} DESTINATION={destination} {options}\n",
+			style = style.string(),
+			nickname = nickname,
+			destination = destination,
+			options = options.options(),
+		);
+
+		sam.arm(deadline)?;
+		sam.send(create_session_msg, sam_session_status)?;
+
+		sam.arm(deadline)?;
+		let local_dest = sam.naming_lookup("ME")?;
+		sam.disarm(deadline)?;
+
+		Ok(Session {
+			sam: sam,
+			local_dest: local_dest,
+			nickname: nickname.to_string(),
+		})
+	}
 
 	pub fn naming_lookup(&mut self, name: &str) -> Result<String, Error> {
 		self.sam.naming_lookup(name)
 	}
 
-	pub fn duplicate(&self) -> Result<Session, Error> {
+	/// The credentials the session was created with, if any.
+	pub fn credentials(&self) -> Option<&Credentials> {
+		self.sam.credentials()
+	}
+
+	/// What the bridge of the session supports.
+	pub fn capabilities(&self) -> Capabilities {
+		self.sam.capabilities()
+	}
+
+	/// Probes the bridge on a connection of its own, see
+	/// `SamConnection::probe`.
+	pub fn probe(&self) -> Result<Capabilities, Error> {
+		self.connect_sam()?.probe()
+	}
+
+	/// See `SamConnection::require`.
+	pub fn require(&self, version: SamVersion, feature: &str) -> Result<(), Error> {
+		self.sam.require(version, feature)
+	}
+
+	/// Opens another connection to the bridge of the session, through the
+	/// same `Connector` and with the same credentials and TLS settings.
+	pub(crate) fn connect_sam(&self) -> Result<SamConnection<T>, Error> {
+		self.sam.reconnect(None)
+	}
+
+	pub(crate) fn connect_sam_deadline(
+		&self,
+		deadline: Instant,
+	) -> Result<SamConnection<T>, Error> {
+		self.sam.reconnect(Some(deadline))
+	}
+
+	pub fn duplicate(&self) -> Result<Session<T>, Error> {
 		self.sam
 			.duplicate()
 			.map(|s| Session {
@@ -249,43 +987,263 @@ impl Session {
 			.map_err(|e| e.into())
 	}
 	/// attempts to return a handle to the underlying socket
-	pub fn try_clone(&self) -> std::io::Result<TcpStream> {
+	pub fn try_clone(&self) -> std::io::Result<T> {
 		self.sam.try_clone()
 	}
 }
 
-impl StreamConnect {
-	/// Create a new SAM client connection to the provided destination and port.
-	/// Also creates a new transient session to support the connection.
-	pub fn new<A: ToSocketAddrs>(
-		sam_addr: A,
-		destination: &str,
-		port: u16,
-		options: SAMOptions,
-	) -> Result<StreamConnect, Error> {
-		let session = Session::transient(sam_addr, options)?;
-		Self::with_session(&session, destination, port)
+impl Default for SessionBuilder {
+	fn default() -> Self {
+		let connectors = DEFAULT_API
+			.to_socket_addrs()
+			.into_iter()
+			.flatten()
+			.map(|addr| Arc::new(addr) as Arc<dyn Connector<Transport = TcpStream>>)
+			.collect();
+		SessionBuilder {
+			connectors,
+			default_api: true,
+			destination: "TRANSIENT".to_string(),
+			nickname: None,
+			style: SessionStyle::Stream,
+			options: Default::default(),
+			params: Default::default(),
+			timeout: None,
+		}
 	}
+}
 
-	/// Create a new SAM client connection to the provided destination and port
-	/// using the provided session.
-	pub fn with_session(session: &Session, dest: &str, port: u16) -> Result<StreamConnect, Error> {
-		let mut sam = SamConnection::connect(session.sam_api()?).unwrap();
-		let dest = sam.naming_lookup(dest)?;
-
-		let mut stream_msg = format!(
-			"STREAM CONNECT ID={nickname} DESTINATION={destination} SILENT=false",
-			nickname = session.nickname,
-			destination = dest,
-		);
-		if port > 0 {
-			stream_msg.push_str(&format!(" TO_PORT={port}\n", port = port));
-		} else {
-			stream_msg.push_str("\n");
+impl SessionBuilder {
+	/// Add `address` to the list of socket addresses to try
+	pub fn with_addr(mut self, address: SocketAddr) -> Self {
+		if self.default_api {
+			self.connectors.clear();
+			self.default_api = false;
 		}
+		self.connectors.push(Arc::new(address));
+		self
+	}
+
+	/// Add all addresses derived from `addresses` to the list of socket
+	/// addresses to try
+	pub fn with_addrs<A: ToSocketAddrs>(self, addresses: A) -> Result<Self, Error> {
+		Ok(addresses.to_socket_addrs()?.fold(self, Self::with_addr))
+	}
+}
+
+impl<T: SamTransport> SessionBuilder<T> {
+	/// Create the session, trying each of the socket addresses in turn.
+	///
+	/// Defaults to a transient stream session with a generated nickname on
+	/// `DEFAULT_API`.
+	pub fn build(self) -> Result<Session<T>, Error> {
+		self.options.check()?;
+		let nickname = self.nickname.clone().unwrap_or_else(nickname);
+		let mut last_err = None;
+		for connector in &self.connectors {
+			match self.create_at(connector, &nickname) {
+				Ok(session) => return Ok(session),
+				Err(e) => last_err = Some(e),
+			}
+		}
+		Err(last_err.unwrap_or_else(|| ErrorKind::UnresolvableAddress.into()))
+	}
+
+	fn create_at(
+		&self,
+		connector: &Arc<dyn Connector<Transport = T>>,
+		nickname: &str,
+	) -> Result<Session<T>, Error> {
+		let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+		let sam = SamConnection::open(connector.clone(), self.params.clone(), deadline)?;
+		Session::create_on(
+			sam,
+			&self.destination,
+			nickname,
+			self.style.clone(),
+			self.options.clone(),
+			deadline,
+		)
+	}
+
+	/// Reach the bridge through `connector` instead of the socket addresses.
+	/// Connections opened later on behalf of the session go through it too.
+	pub fn with_connector<C: Connector>(self, connector: C) -> SessionBuilder<C::Transport> {
+		SessionBuilder {
+			connectors: vec![Arc::new(connector)],
+			default_api: false,
+			destination: self.destination,
+			nickname: self.nickname,
+			style: self.style,
+			options: self.options,
+			params: self.params,
+			timeout: self.timeout,
+		}
+	}
+
+	/// Use the base64 private key `destination` instead of a transient one
+	pub fn with_destination(mut self, destination: &str) -> Self {
+		self.destination = destination.to_string();
+		self
+	}
+
+	pub fn with_nickname(mut self, nickname: &str) -> Self {
+		self.nickname = Some(nickname.to_string());
+		self
+	}
+
+	pub fn with_style(mut self, style: SessionStyle) -> Self {
+		self.style = style;
+		self
+	}
+
+	pub fn with_options(mut self, options: SAMOptions) -> Self {
+		self.options = options;
+		self
+	}
+
+	/// Authenticate to the bridge with `credentials`. Connections opened
+	/// later on behalf of the session use them too.
+	pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+		self.params.auth = Some(credentials);
+		self
+	}
+
+	/// Use the SAM versions and credentials in `options` for the session
+	/// and the connections opened on its behalf, see
+	/// `SamConnection::connect_with`. The timeout, if any, is the one of
+	/// `with_timeout`, so it covers creating the session too.
+	pub fn with_connect_options(mut self, options: ConnectOptions) -> Result<Self, Error> {
+		self.params.min_version = options.min_version;
+		self.params.max_version = options.max_version;
+		self.params.auth = options.credentials()?;
+		self.params.check()?;
+		self.timeout = options.timeout.or(self.timeout);
+		Ok(self)
+	}
+
+	/// Connect to the bridge over TLS. Connections opened later on behalf of
+	/// the session, including the data connections of its streams, use TLS
+	/// too.
+	#[cfg(feature = "tls")]
+	pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+		self.params.tls = Some(tls);
+		self
+	}
+
+	/// Record the exchanges with the bridge, for the session and all the
+	/// connections opened on its behalf, see `SamConnection::record`.
+	pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+		self.params.recorder = Some(recorder);
+		self
+	}
+
+	/// Give up with `ErrorKind::SAMTimeout` if the session is not ready
+	/// within `timeout`, see `Session::create_timeout`.
+	pub fn with_timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = Some(timeout);
+		self
+	}
+}
+
+impl StreamConnect {
+	/// Create a new SAM client connection to the provided destination and port.
+	/// Also creates a new transient session to support the connection.
+	pub fn new<A: ToSocketAddrs>(
+		sam_addr: A,
+		destination: &str,
+		port: u16,
+		options: SAMOptions,
+	) -> Result<StreamConnect, Error> {
+		let session = Session::transient(sam_addr, options)?;
+		Self::with_session(&session, destination, port)
+	}
+
+	/// Same as `new`, but gives up with `ErrorKind::SAMTimeout` if creating
+	/// the session and connecting to the destination take longer than
+	/// `timeout` altogether.
+	pub fn new_timeout(
+		sam_addr: &SocketAddr,
+		destination: &str,
+		port: u16,
+		options: SAMOptions,
+		timeout: Duration,
+	) -> Result<StreamConnect, Error> {
+		let deadline = Instant::now() + timeout;
+		let session = Session::create_timeout(
+			sam_addr,
+			"TRANSIENT",
+			&nickname(),
+			SessionStyle::Stream,
+			options,
+			timeout,
+		)?;
+		let sam =
+			SamConnection::connect_params(sam_addr, ConnectParams::default(), Some(deadline))?;
+		Self::connect_on(sam, &session, destination, port, Some(deadline))
+	}
+}
+
+impl<T: SamTransport> StreamConnect<T> {
+	/// Create a new SAM client connection to the provided destination and port
+	/// using the provided session.
+	pub fn with_session(
+		session: &Session<T>,
+		dest: &str,
+		port: u16,
+	) -> Result<StreamConnect<T>, Error> {
+		let sam = session.connect_sam()?;
+		Self::connect_on(sam, session, dest, port, None)
+	}
+
+	/// Same as `with_session`, but gives up with `ErrorKind::SAMTimeout` if
+	/// the stream is not established within `timeout`. The timeout covers
+	/// the connection to the SAM bridge, the name lookup and the router's
+	/// tunnel and leaseset lookups.
+	pub fn with_session_timeout(
+		session: &Session<T>,
+		dest: &str,
+		port: u16,
+		timeout: Duration,
+	) -> Result<StreamConnect<T>, Error> {
+		let deadline = Instant::now() + timeout;
+		let sam = session.connect_sam_deadline(deadline)?;
+		Self::connect_on(sam, session, dest, port, Some(deadline))
+	}
+
+	/// Issue `STREAM CONNECT` on an already handshaked control connection,
+	/// which becomes the data socket of the returned stream.
+	pub(crate) fn connect_on(
+		mut sam: SamConnection<T>,
+		session: &Session<T>,
+		dest: &str,
+		port: u16,
+		deadline: Option<Instant>,
+	) -> Result<StreamConnect<T>, Error> {
+		// a full destination needs no lookup, saving a round-trip
+		let dest = if is_b64_destination(dest) {
+			dest.to_string()
+		} else {
+			sam.arm(deadline)?;
+			sam.naming_lookup(dest)?
+		};
+
+		let mut stream_msg = format!(
+			"STREAM CONNECT ID={nickname} DESTINATION={destination} SILENT=false",
+			nickname = session.nickname,
+			destination = dest,
+		);
+		if port > 0 {
+			sam.require(SamVersion::V3_2, "TO_PORT")?;
+			stream_msg.push_str(&format!(" TO_PORT={port}\n", port = port));
+		} else {
+			stream_msg.push_str("\n");
+		}
+
+		sam.arm(deadline)?;
+		sam.send(stream_msg, sam_stream_status)?;
+		sam.disarm(deadline)?;
 
-		sam.send(stream_msg, sam_stream_status)?;
-
 		Ok(StreamConnect {
 			sam: sam,
 			session: session.duplicate()?,
@@ -295,6 +1253,46 @@ impl StreamConnect {
 		})
 	}
 
+	/// Issue `STREAM ACCEPT` on an already handshaked control connection and
+	/// wait for the peer destination line of the incoming stream.
+	pub(crate) fn accept_on(
+		mut sam: SamConnection<T>,
+		session: &Session<T>,
+		deadline: Option<Instant>,
+	) -> Result<(StreamConnect<T>, I2pSocketAddr), Error> {
+		let accept_stream_msg = format!(
+			"STREAM ACCEPT ID={nickname} SILENT=false\n",
+			nickname = session.nickname,
+		);
+		sam.arm(deadline)?;
+		sam.send(accept_stream_msg, sam_stream_status)?;
+
+		let mut stream = StreamConnect {
+			sam,
+			session: session.duplicate()?,
+			peer_dest: "".to_string(),
+			peer_port: 0,
+			local_port: 0,
+		};
+
+		stream.sam.arm(deadline)?;
+		let mut buffer = Vec::new();
+		let dest_line = stream
+			.sam
+			.read_line(&mut buffer)
+			.map_err(|e| stream.sam.io_error(e))?;
+		stream.sam.disarm(deadline)?;
+
+		let (destination, addr) = parse_peer_line(&dest_line)?;
+		stream.peer_dest = destination;
+		// port only provided with SAM v3.2+ (not on i2pd)
+		let (from_port, to_port) = stream.sam.peer_ports(&dest_line);
+		stream.peer_port = from_port;
+		stream.local_port = to_port;
+
+		Ok((stream, addr))
+	}
+
 	pub fn peer_addr(&self) -> Result<(String, u16), Error> {
 		Ok((self.peer_dest.clone(), self.peer_port))
 	}
@@ -316,7 +1314,7 @@ impl StreamConnect {
 		self.sam.conn.shutdown(how).map_err(|e| e.into())
 	}
 
-	pub fn duplicate(&self) -> Result<StreamConnect, Error> {
+	pub fn duplicate(&self) -> Result<StreamConnect<T>, Error> {
 		Ok(StreamConnect {
 			sam: self.sam.duplicate()?,
 			session: self.session.duplicate()?,
@@ -326,95 +1324,916 @@ impl StreamConnect {
 		})
 	}
 	/// calls try_clone against the Session object
-	pub fn try_clone_session(&self) -> std::io::Result<TcpStream> {
+	pub fn try_clone_session(&self) -> std::io::Result<T> {
 		self.session.try_clone()
 	}
 	/// calls try_clone against the SamConnection object
-	pub fn try_clone_sam(&self) -> std::io::Result<TcpStream> {
+	pub fn try_clone_sam(&self) -> std::io::Result<T> {
 		self.sam.try_clone()
 	}
 }
 
-impl Read for StreamConnect {
+impl<T: SamTransport> Read for StreamConnect<T> {
 	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-		self.sam.conn.read(buf)
+		self.sam.read(buf)
 	}
 }
 
-impl Write for StreamConnect {
+impl<T: SamTransport> Write for StreamConnect<T> {
 	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-		self.sam.conn.write(buf)
+		self.sam.write(buf)
 	}
 	fn flush(&mut self) -> io::Result<()> {
-		self.sam.conn.flush()
+		self.sam.flush()
 	}
 }
 
-pub struct StreamForward {
+pub struct StreamForward<T: SamTransport = TcpStream> {
+	session: Session<T>,
+	tracker: Arc<AcceptTracker>,
+}
+
+/// Incoming streams pushed by the router with `STREAM FORWARD`, created by
+/// `StreamForward::forward`.
+///
+/// Each stream arrives as a new connection to a local listener, starting
+/// with the destination line of the peer, so unlike `StreamForward::accept`
+/// no control connection is needed per stream. Destination lines are read
+/// on a thread per connection, so a connection that never sends one holds
+/// up nothing else. Forwarding stops when this is dropped, or when the
+/// `StreamForward` it came from is shut down.
+pub struct ForwardListener {
+	/// control connection holding the `STREAM FORWARD` registration
+	sam: SamConnection,
 	session: Session,
+	listener_addr: SocketAddr,
+	tracker: Arc<AcceptTracker>,
+	/// the entry in `tracker` waking up `accept` on shutdown
+	id: u64,
+	/// streams whose destination line came in
+	incoming: Mutex<Receiver<Forwarded>>,
+	stopped: Arc<AtomicBool>,
+}
+
+type Forwarded = Result<(StreamConnect, I2pSocketAddr), Error>;
+
+/// A `STREAM ACCEPT` in progress, created by `StreamForward::start_accept`.
+///
+/// Its control socket is non-blocking. Register it with an event loop
+/// (through `AsRawFd` on unix) for readability and call `try_accept` when it
+/// becomes readable, until it yields a stream or fails with anything other
+/// than `ErrorKind::WouldBlock`. Each pending accept yields at most one
+/// stream; start a new one for the next.
+pub struct PendingAccept<T: SamTransport = TcpStream> {
+	sam: SamConnection<T>,
+	session: Session<T>,
+	tracker: Arc<AcceptTracker>,
+	id: u64,
+	state: PendingState,
+	/// partial reply line received so far
+	buffer: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PendingState {
+	/// waiting for `STREAM STATUS`
+	Status,
+	/// waiting for the destination line of the incoming peer
+	Peer,
+	/// a stream was accepted, or the accept failed
+	Done,
+}
+
+/// A handle to shut down a `StreamForward`, or the `I2pListener` wrapping
+/// it, from another thread.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+	tracker: Arc<AcceptTracker>,
+}
+
+/// The accepts in progress and the streams accepted through a
+/// `StreamForward` and its duplicates.
+#[derive(Default)]
+struct AcceptTracker {
+	state: Mutex<AcceptState>,
+	drained: Condvar,
+}
+
+#[derive(Default)]
+struct AcceptState {
+	closed: bool,
+	next_id: u64,
+	/// control sockets blocked in `STREAM ACCEPT`
+	pending: HashMap<u64, Closer>,
+	/// data sockets of accepted streams that are still alive
+	active: HashMap<u64, Closer>,
+}
+
+/// Shuts down a socket tracked by an `AcceptTracker`, whatever its transport.
+type Closer = Box<dyn Fn() + Send>;
+
+/// Unregisters an accepted stream from its tracker when dropped.
+struct AcceptedStream {
+	tracker: Weak<AcceptTracker>,
+	id: u64,
 }
 
 impl StreamForward {
 	pub fn new<A: ToSocketAddrs>(sam_addr: A, options: SAMOptions) -> Result<StreamForward, Error> {
 		Ok(StreamForward {
 			session: Session::transient(sam_addr, options)?,
+			tracker: Default::default(),
 		})
 	}
 
+	/// Same as `accept` but issues `STREAM ACCEPT` on a connection taken
+	/// from `pool`.
+	pub fn accept_with_pool(
+		&self,
+		pool: &SamConnectionPool,
+	) -> Result<(StreamConnect, I2pSocketAddr), Error> {
+		self.tracker.check_open()?;
+		let (mut sam_conn, lease) = pool.get()?.detach_with_lease();
+		sam_conn.lease = lease;
+		self.accept_on(sam_conn, None)
+	}
+
+	/// Bind a local listener and have the router push incoming streams to
+	/// it, see `ForwardListener`.
+	pub fn forward(&self) -> Result<ForwardListener, Error> {
+		self.tracker.check_open()?;
+		let bridge = self.session.sam_api()?;
+		let sam = self.session.connect_sam()?;
+		// listen on the interface the bridge reaches us through
+		let local = sam.conn.local_addr()?.ip();
+		let listener = TcpListener::bind((local, 0))?;
+		let port = listener.local_addr()?.port();
+		let sam = self.forward_on(sam, &local.to_string(), port, false, false)?;
+
+		let listener_addr = listener.local_addr()?;
+		let stopped = Arc::new(AtomicBool::new(false));
+		let (forwarded, incoming) = mpsc::channel();
+		let wake = forwarded.clone();
+		let stop = stopped.clone();
+		let id = self.tracker.watch(Box::new(move || {
+			stop.store(true, Ordering::SeqCst);
+			let _ = TcpStream::connect(listener_addr);
+			let _ = wake.send(Err(ErrorKind::ListenerClosed.into()));
+		}))?;
+		let acceptor = ForwardAcceptor {
+			listener,
+			bridge: bridge.ip(),
+			session: self.session.duplicate()?,
+			capabilities: sam.capabilities.clone(),
+			tracker: self.tracker.clone(),
+			stopped: stopped.clone(),
+			forwarded,
+		};
+		thread::spawn(move || acceptor.run());
+		Ok(ForwardListener {
+			sam,
+			session: self.session.duplicate()?,
+			listener_addr,
+			tracker: self.tracker.clone(),
+			id,
+			incoming: Mutex::new(incoming),
+			stopped,
+		})
+	}
+}
+
+impl<T: SamTransport> StreamForward<T> {
 	/// Create a new SAM client connection to the provided destination and port
 	/// using the provided session.
-	pub fn with_session(session: &Session) -> Result<StreamForward, Error> {
+	pub fn with_session(session: &Session<T>) -> Result<StreamForward<T>, Error> {
 		Ok(StreamForward {
 			session: session.duplicate()?,
+			tracker: Default::default(),
 		})
 	}
 
-	pub fn accept(&self) -> Result<(StreamConnect, I2pSocketAddr), Error> {
-		let mut sam_conn = SamConnection::connect(self.session.sam_api()?).unwrap();
+	pub fn accept(&self) -> Result<(StreamConnect<T>, I2pSocketAddr), Error> {
+		self.tracker.check_open()?;
+		let sam_conn = self.session.connect_sam()?;
+		self.accept_on(sam_conn, None)
+	}
 
+	/// Same as `accept`, but gives up with `ErrorKind::SAMTimeout` if no
+	/// stream comes in within `timeout`.
+	pub fn accept_timeout(
+		&self,
+		timeout: Duration,
+	) -> Result<(StreamConnect<T>, I2pSocketAddr), Error> {
+		self.tracker.check_open()?;
+		let deadline = Instant::now() + timeout;
+		let sam_conn = self.session.connect_sam_deadline(deadline)?;
+		self.accept_on(sam_conn, Some(deadline))
+	}
+
+	/// Start accepting a stream without blocking on it.
+	///
+	/// The connection to the SAM bridge is made and `STREAM ACCEPT` is sent
+	/// right away; the returned `PendingAccept` is then driven to completion
+	/// by calling `try_accept` whenever its socket becomes readable.
+	pub fn start_accept(&self) -> Result<PendingAccept<T>, Error> {
+		self.tracker.check_open()?;
+		let sam = self.session.connect_sam()?;
+		let id = self.tracker.begin(&sam)?;
+		let mut pending = PendingAccept {
+			sam,
+			session: self.session.duplicate()?,
+			tracker: self.tracker.clone(),
+			id,
+			state: PendingState::Status,
+			buffer: Vec::new(),
+		};
 		let accept_stream_msg = format!(
 			"STREAM ACCEPT ID={nickname} SILENT=false\n",
 			nickname = self.session.nickname,
 		);
-		sam_conn.send(accept_stream_msg, sam_stream_status)?;
+		pending.sam.write_command(&accept_stream_msg)?;
+		pending.sam.set_nonblocking(true)?;
+		Ok(pending)
+	}
 
-		let mut stream = StreamConnect {
-			sam: sam_conn,
+	fn accept_on(
+		&self,
+		sam_conn: SamConnection<T>,
+		deadline: Option<Instant>,
+	) -> Result<(StreamConnect<T>, I2pSocketAddr), Error> {
+		let id = self.tracker.begin(&sam_conn)?;
+		match StreamConnect::accept_on(sam_conn, &self.session, deadline) {
+			Ok((mut stream, addr)) => {
+				stream.sam.accepted = Some(self.tracker.finish(id, &stream.sam)?);
+				Ok((stream, addr))
+			}
+			Err(e) => {
+				self.tracker.abort(id);
+				// the accept was most likely unblocked by the shutdown
+				self.tracker.check_open()?;
+				Err(e)
+			}
+		}
+	}
+
+	pub fn local_addr(&self) -> Result<(String, u16), Error> {
+		Ok((self.session.local_dest.clone(), 0))
+	}
+
+	/// Stop accepting streams. Pending and future calls to `accept` fail
+	/// with `ErrorKind::ListenerClosed`; streams already accepted are left
+	/// open, see `drain`.
+	pub fn shutdown(&self) {
+		self.tracker.shutdown()
+	}
+
+	pub fn is_shutdown(&self) -> bool {
+		self.tracker.lock().closed
+	}
+
+	/// Wait up to `timeout` for all streams accepted by this forward, or any
+	/// of its duplicates, to be dropped. Streams still open after that are
+	/// shut down. Returns the number of streams that had to be closed.
+	pub fn drain(&self, timeout: Duration) -> usize {
+		self.tracker.drain(timeout)
+	}
+
+	/// Returns a handle that shuts down this forward and all its duplicates.
+	pub fn shutdown_handle(&self) -> ShutdownHandle {
+		ShutdownHandle {
+			tracker: self.tracker.clone(),
+		}
+	}
+
+	/// Duplicates share their shutdown state: shutting down one of them
+	/// shuts down all of them.
+	pub fn duplicate(&self) -> Result<StreamForward<T>, Error> {
+		Ok(StreamForward {
 			session: self.session.duplicate()?,
-			peer_dest: "".to_string(),
-			// port only provided with SAM v3.2+ (not on i2pd)
-			peer_port: 0,
-			local_port: 0,
-		};
+			tracker: self.tracker.clone(),
+		})
+	}
 
-		// TODO use a parser combinator, perhaps move down to sam.rs
-		let destination: String = {
-			let mut buf_read = io::BufReader::new(stream.duplicate()?);
-			let mut dest_line = String::new();
-			buf_read.read_line(&mut dest_line)?;
-			dest_line.split(" ").next().unwrap_or("").trim().to_string()
-		};
-		if destination.is_empty() {
-			return Err(
-				ErrorKind::SAMKeyNotFound("No b64 destination in accept".to_string()).into(),
-			);
+	/// Have the router push incoming streams to `host:port` with
+	/// `STREAM FORWARD`, for as long as the returned control connection is
+	/// open.
+	///
+	/// Unless `silent` is set, every forwarded connection starts with the
+	/// destination line of the peer. With `ssl`, the router connects to
+	/// `host:port` over TLS (SAM v3.3, which the session has to ask for with
+	/// `ConnectOptions::max_version`). Most callers want `forward`, which
+	/// runs the local listener too.
+	pub fn forward_to(
+		&self,
+		host: &str,
+		port: u16,
+		silent: bool,
+		ssl: bool,
+	) -> Result<SamConnection<T>, Error> {
+		self.tracker.check_open()?;
+		let sam = self.session.connect_sam()?;
+		self.forward_on(sam, host, port, silent, ssl)
+	}
+
+	fn forward_on(
+		&self,
+		mut sam: SamConnection<T>,
+		host: &str,
+		port: u16,
+		silent: bool,
+		ssl: bool,
+	) -> Result<SamConnection<T>, Error> {
+		if ssl {
+			sam.require(SamVersion::V3_3, "SSL forwarding")?;
+		} else if sam.uses_tls() {
+			// the router would push the streams of a TLS session in the clear
+			return Err(ErrorKind::Tls(
+				"STREAM FORWARD connections are plain TCP, use SSL forwarding or STREAM ACCEPT"
+					.to_string(),
+			)
+			.into());
+		}
+		let mut forward_msg = format!(
+			"STREAM FORWARD ID={nickname} PORT={port} HOST={host} SILENT={silent}",
+			nickname = self.session.nickname,
+			port = port,
+			host = host,
+			silent = silent,
+		);
+		if ssl {
+			forward_msg.push_str(" SSL=true");
+		}
+		forward_msg.push('\n');
+		sam.send(forward_msg, sam_stream_status)?;
+		Ok(sam)
+	}
+}
+
+impl ForwardListener {
+	/// Wait for the router to forward a stream.
+	///
+	/// Connections to the listener from anywhere but the SAM bridge are
+	/// dropped, and so are those that send no destination line within 30
+	/// seconds. Note that with a bridge on localhost, any local process can
+	/// still connect and claim to be forwarding a stream from any peer.
+	/// Fails with `ErrorKind::ListenerClosed` once shut down.
+	pub fn accept(&self) -> Result<(StreamConnect, I2pSocketAddr), Error> {
+		self.tracker.check_open()?;
+		let incoming = self.incoming.lock().unwrap_or_else(|e| e.into_inner());
+		self.tracker.check_open()?;
+		match incoming.recv() {
+			Ok(forwarded) => forwarded,
+			Err(_) => Err(ErrorKind::ListenerClosed.into()),
 		}
+	}
 
-		let addr = I2pSocketAddr::new(I2pAddr::from_b64(&destination)?, 0);
-		stream.peer_dest = destination;
+	/// See `StreamForward::shutdown`.
+	pub fn shutdown(&self) {
+		self.tracker.shutdown()
+	}
 
-		Ok((stream, addr))
+	pub fn is_shutdown(&self) -> bool {
+		self.tracker.lock().closed
+	}
+
+	/// See `StreamForward::drain`.
+	pub fn drain(&self, timeout: Duration) -> usize {
+		self.tracker.drain(timeout)
+	}
+
+	/// Returns a handle that shuts down this listener, and the
+	/// `StreamForward` it came from.
+	pub fn shutdown_handle(&self) -> ShutdownHandle {
+		ShutdownHandle {
+			tracker: self.tracker.clone(),
+		}
 	}
 
 	pub fn local_addr(&self) -> Result<(String, u16), Error> {
 		Ok((self.session.local_dest.clone(), 0))
 	}
 
-	pub fn duplicate(&self) -> Result<StreamForward, Error> {
-		Ok(StreamForward {
-			session: self.session.duplicate()?,
+	/// Returns the address of the local listener the router connects to.
+	pub fn listener_addr(&self) -> Result<SocketAddr, Error> {
+		Ok(self.listener_addr)
+	}
+
+	/// Returns the control connection holding the forward registration.
+	pub fn control(&self) -> &SamConnection {
+		&self.sam
+	}
+}
+
+impl Drop for ForwardListener {
+	fn drop(&mut self) {
+		self.tracker.abort(self.id);
+		self.stopped.store(true, Ordering::SeqCst);
+		// wake up the acceptor so that it sees the flag
+		let _ = TcpStream::connect(self.listener_addr);
+	}
+}
+
+/// Takes the connections forwarded to a `ForwardListener`, on a thread of
+/// its own.
+struct ForwardAcceptor {
+	listener: TcpListener,
+	/// only the SAM bridge may connect to the listener
+	bridge: IpAddr,
+	session: Session,
+	capabilities: Arc<Mutex<Capabilities>>,
+	tracker: Arc<AcceptTracker>,
+	stopped: Arc<AtomicBool>,
+	forwarded: Sender<Forwarded>,
+}
+
+impl ForwardAcceptor {
+	fn run(self) {
+		for conn in self.listener.incoming() {
+			if self.stopped.load(Ordering::SeqCst) {
+				return;
+			}
+			let conn = match conn {
+				Ok(conn) => conn,
+				Err(e) => {
+					debug!("failed to accept forwarded connection: {}", e);
+					continue;
+				}
+			};
+			match conn.peer_addr() {
+				Ok(from) if from.ip() == self.bridge => {}
+				from => {
+					debug!("dropping forwarded connection from {:?}", from);
+					continue;
+				}
+			}
+			let session = match self.session.duplicate() {
+				Ok(session) => session,
+				Err(e) => {
+					let _ = self.forwarded.send(Err(e));
+					continue;
+				}
+			};
+			let capabilities = self.capabilities.clone();
+			let tracker = self.tracker.clone();
+			let forwarded = self.forwarded.clone();
+			thread::spawn(move || {
+				let stream = Self::stream(conn, session, &capabilities, &tracker);
+				if let Some(stream) = stream.transpose() {
+					let _ = forwarded.send(stream);
+				}
+			});
+		}
+	}
+
+	/// Reads the destination line of a forwarded connection. `None` if it
+	/// didn't come in time.
+	fn stream(
+		conn: TcpStream,
+		session: Session,
+		capabilities: &Mutex<Capabilities>,
+		tracker: &Arc<AcceptTracker>,
+	) -> Result<Option<(StreamConnect, I2pSocketAddr)>, Error> {
+		conn.set_read_timeout(Some(FORWARD_HEADER_TIMEOUT))?;
+		let mut buffer = Vec::new();
+		let dest_line = match read_line_exact(&conn, &mut buffer) {
+			Ok(line) => line,
+			Err(ref e)
+				if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+			{
+				debug!("dropping forwarded connection without a destination line");
+				return Ok(None);
+			}
+			Err(e) => return Err(e.into()),
+		};
+		conn.set_read_timeout(None)?;
+		debug!("<- {} (forwarded)", dest_line.trim_end());
+		let (destination, addr) = parse_peer_line(&dest_line)?;
+		let (from_port, to_port) = capabilities
+			.lock()
+			.unwrap_or_else(|e| e.into_inner())
+			.observe_peer_line(&dest_line);
+		let mut sam = SamConnection::new(conn, None, ConnectParams::default());
+		sam.accepted = Some(tracker.track(&sam)?);
+		Ok(Some((
+			StreamConnect {
+				sam,
+				session,
+				peer_dest: destination,
+				peer_port: from_port,
+				local_port: to_port,
+			},
+			addr,
+		)))
+	}
+}
+
+impl<T: SamTransport> PendingAccept<T> {
+	/// Make progress on the accept without blocking.
+	///
+	/// Returns `ErrorKind::WouldBlock` until the peer has connected. The
+	/// accepted stream is left in non-blocking mode.
+	pub fn try_accept(&mut self) -> Result<(StreamConnect<T>, I2pSocketAddr), Error> {
+		if self.state == PendingState::Done {
+			return Err(ErrorKind::Io("accept already completed".to_string()).into());
+		}
+		match self.poll() {
+			Err(ref e) if e.kind() == ErrorKind::WouldBlock => Err(ErrorKind::WouldBlock.into()),
+			Err(e) => {
+				self.state = PendingState::Done;
+				self.tracker.abort(self.id);
+				self.tracker.check_open()?;
+				Err(e)
+			}
+			ok => ok,
+		}
+	}
+
+	fn poll(&mut self) -> Result<(StreamConnect<T>, I2pSocketAddr), Error> {
+		if self.state == PendingState::Status {
+			let reply = self.sam.read_line(&mut self.buffer)?;
+			debug!("<- {}", &reply);
+			parse_reply(&reply, sam_stream_status)?;
+			self.state = PendingState::Peer;
+		}
+		let dest_line = self.sam.read_line(&mut self.buffer)?;
+		let (destination, addr) = parse_peer_line(&dest_line)?;
+		let (from_port, to_port) = self.sam.peer_ports(&dest_line);
+
+		let mut sam = self.sam.duplicate()?;
+		sam.accepted = Some(self.tracker.finish(self.id, &sam)?);
+		self.state = PendingState::Done;
+		Ok((
+			StreamConnect {
+				sam,
+				session: self.session.duplicate()?,
+				peer_dest: destination,
+				peer_port: from_port,
+				local_port: to_port,
+			},
+			addr,
+		))
+	}
+}
+
+impl<T: SamTransport> Drop for PendingAccept<T> {
+	fn drop(&mut self) {
+		if self.state != PendingState::Done {
+			self.tracker.abort(self.id);
+		}
+	}
+}
+
+#[cfg(unix)]
+impl<T: SamTransport + AsRawFd> AsRawFd for PendingAccept<T> {
+	fn as_raw_fd(&self) -> RawFd {
+		self.sam.conn.as_raw_fd()
+	}
+}
+
+#[cfg(windows)]
+impl<T: SamTransport + AsRawSocket> AsRawSocket for PendingAccept<T> {
+	fn as_raw_socket(&self) -> RawSocket {
+		self.sam.conn.as_raw_socket()
+	}
+}
+
+#[cfg(unix)]
+impl<T: SamTransport + AsRawFd> AsRawFd for SamConnection<T> {
+	fn as_raw_fd(&self) -> RawFd {
+		self.conn.as_raw_fd()
+	}
+}
+
+#[cfg(unix)]
+impl<T: SamTransport + AsFd> AsFd for SamConnection<T> {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		self.conn.as_fd()
+	}
+}
+
+#[cfg(unix)]
+impl<T: SamTransport + IntoRawFd> IntoRawFd for SamConnection<T> {
+	fn into_raw_fd(self) -> RawFd {
+		self.conn.into_raw_fd()
+	}
+}
+
+/// The file descriptor must be a connected TCP socket on which the HELLO
+/// exchange with the SAM bridge has already taken place, e.g. one obtained
+/// from `IntoRawFd` in another process.
+#[cfg(unix)]
+impl FromRawFd for SamConnection {
+	unsafe fn from_raw_fd(fd: RawFd) -> SamConnection {
+		let conn = TcpStream::from_raw_fd(fd);
+		let connector = conn
+			.peer_addr()
+			.ok()
+			.map(|addr| Arc::new(addr) as Arc<dyn Connector<Transport = TcpStream>>);
+		SamConnection::new(conn, connector, ConnectParams::default())
+	}
+}
+
+/// The control socket of the session; it is not readable until the router
+/// sends a reply, and closing it ends the session along with its streams.
+#[cfg(unix)]
+impl<T: SamTransport + AsRawFd> AsRawFd for Session<T> {
+	fn as_raw_fd(&self) -> RawFd {
+		self.sam.as_raw_fd()
+	}
+}
+
+#[cfg(unix)]
+impl<T: SamTransport + AsFd> AsFd for Session<T> {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		self.sam.as_fd()
+	}
+}
+
+/// The data socket of the stream, which carries the peer's bytes as is,
+/// unless the connection to the bridge uses TLS: the socket then carries
+/// ciphertext, and only the `Read` and `Write` impls give the peer's bytes.
+#[cfg(unix)]
+impl<T: SamTransport + AsRawFd> AsRawFd for StreamConnect<T> {
+	fn as_raw_fd(&self) -> RawFd {
+		self.sam.as_raw_fd()
+	}
+}
+
+#[cfg(unix)]
+impl<T: SamTransport + AsFd> AsFd for StreamConnect<T> {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		self.sam.as_fd()
+	}
+}
+
+/// Gives up the data socket of the stream. The stream's handle on its
+/// session is closed, so the session must be kept alive elsewhere: a stream
+/// opened with its own transient session, as `StreamConnect::new` does, is
+/// torn down by the router along with that session.
+#[cfg(unix)]
+impl<T: SamTransport + IntoRawFd> IntoRawFd for StreamConnect<T> {
+	fn into_raw_fd(self) -> RawFd {
+		self.sam.into_raw_fd()
+	}
+}
+
+#[cfg(unix)]
+impl<T: SamTransport + AsRawFd> AsRawFd for StreamForward<T> {
+	fn as_raw_fd(&self) -> RawFd {
+		self.session.as_raw_fd()
+	}
+}
+
+#[cfg(unix)]
+impl<T: SamTransport + AsFd> AsFd for StreamForward<T> {
+	fn as_fd(&self) -> BorrowedFd<'_> {
+		self.session.as_fd()
+	}
+}
+
+#[cfg(unix)]
+impl<T: SamTransport + IntoRawFd> IntoRawFd for StreamForward<T> {
+	fn into_raw_fd(self) -> RawFd {
+		self.session.sam.into_raw_fd()
+	}
+}
+
+impl ShutdownHandle {
+	/// See `StreamForward::shutdown`.
+	pub fn shutdown(&self) {
+		self.tracker.shutdown()
+	}
+
+	pub fn is_shutdown(&self) -> bool {
+		self.tracker.lock().closed
+	}
+
+	/// See `StreamForward::drain`.
+	pub fn drain(&self, timeout: Duration) -> usize {
+		self.tracker.drain(timeout)
+	}
+}
+
+impl AcceptTracker {
+	fn lock(&self) -> MutexGuard<'_, AcceptState> {
+		self.state.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	fn check_open(&self) -> Result<(), Error> {
+		if self.lock().closed {
+			return Err(ErrorKind::ListenerClosed.into());
+		}
+		Ok(())
+	}
+
+	/// Register a control socket about to block in `STREAM ACCEPT`.
+	fn begin<T: SamTransport>(&self, sam_conn: &SamConnection<T>) -> Result<u64, Error> {
+		self.watch(closer(sam_conn)?)
+	}
+
+	/// Register something to unblock on shutdown.
+	fn watch(&self, close: Closer) -> Result<u64, Error> {
+		let mut state = self.lock();
+		if state.closed {
+			return Err(ErrorKind::ListenerClosed.into());
+		}
+		state.next_id += 1;
+		let id = state.next_id;
+		state.pending.insert(id, close);
+		Ok(id)
+	}
+
+	/// Register a stream that came in without an accept, e.g. a forwarded
+	/// one.
+	fn track<T: SamTransport>(
+		self: &Arc<Self>,
+		sam_conn: &SamConnection<T>,
+	) -> Result<AcceptedStream, Error> {
+		let conn = closer(sam_conn)?;
+		let mut state = self.lock();
+		if state.closed {
+			return Err(ErrorKind::ListenerClosed.into());
+		}
+		state.next_id += 1;
+		let id = state.next_id;
+		state.active.insert(id, conn);
+		Ok(AcceptedStream {
+			tracker: Arc::downgrade(self),
+			id,
 		})
 	}
+
+	/// Move a completed accept over to the active streams.
+	fn finish<T: SamTransport>(
+		self: &Arc<Self>,
+		id: u64,
+		sam_conn: &SamConnection<T>,
+	) -> Result<AcceptedStream, Error> {
+		let conn = closer(sam_conn);
+		let mut state = self.lock();
+		state.pending.remove(&id);
+		state.active.insert(id, conn?);
+		Ok(AcceptedStream {
+			tracker: Arc::downgrade(self),
+			id,
+		})
+	}
+
+	fn abort(&self, id: u64) {
+		self.lock().pending.remove(&id);
+	}
+
+	fn shutdown(&self) {
+		let mut state = self.lock();
+		state.closed = true;
+		for (_, close) in state.pending.drain() {
+			close();
+		}
+	}
+
+	fn drain(&self, timeout: Duration) -> usize {
+		let deadline = Instant::now() + timeout;
+		let mut state = self.lock();
+		while !state.active.is_empty() {
+			let now = Instant::now();
+			if now >= deadline {
+				break;
+			}
+			state = self
+				.drained
+				.wait_timeout(state, deadline - now)
+				.unwrap_or_else(|e| e.into_inner())
+				.0;
+		}
+		let forced = state.active.len();
+		for (_, close) in state.active.drain() {
+			close();
+		}
+		forced
+	}
+}
+
+fn closer<T: SamTransport>(sam_conn: &SamConnection<T>) -> io::Result<Closer> {
+	let conn = sam_conn.try_clone()?;
+	Ok(Box::new(move || {
+		let _ = conn.shutdown(Shutdown::Both);
+	}))
+}
+
+impl Drop for AcceptedStream {
+	fn drop(&mut self) {
+		if let Some(tracker) = self.tracker.upgrade() {
+			tracker.lock().active.remove(&self.id);
+			tracker.drained.notify_all();
+		}
+	}
+}
+
+impl std::fmt::Debug for AcceptedStream {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("AcceptedStream")
+			.field("id", &self.id)
+			.finish()
+	}
+}
+
+/// The longest line accepted from a bridge. Replies and destination lines
+/// are a few KB at most, so anything longer is a bridge gone wrong.
+const MAX_LINE: usize = 64 * 1024;
+
+/// Fails once `buffer` holds more than `MAX_LINE` bytes without a newline.
+pub(crate) fn check_line_length(buffer: &[u8]) -> io::Result<()> {
+	if buffer.len() > MAX_LINE {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			"SAM bridge sent an overlong line",
+		));
+	}
+	Ok(())
+}
+
+/// Reads from `conn` up to and including the next newline, without
+/// consuming anything past it since whatever follows belongs to the stream.
+/// A partial line is kept in `buffer` across calls, so this can be retried
+/// after `WouldBlock` on a non-blocking socket.
+fn read_line_exact(mut conn: &TcpStream, buffer: &mut Vec<u8>) -> io::Result<String> {
+	let mut chunk = [0_u8; 1024];
+	loop {
+		let n = conn.peek(&mut chunk)?;
+		if n == 0 {
+			return Err(io::Error::new(
+				io::ErrorKind::UnexpectedEof,
+				"SAM bridge closed the connection",
+			));
+		}
+		let (len, complete) = match chunk[..n].iter().position(|&b| b == b'\n') {
+			Some(i) => (i + 1, true),
+			None => (n, false),
+		};
+		// already peeked, so this neither blocks nor comes up short
+		conn.read_exact(&mut chunk[..len])?;
+		buffer.extend_from_slice(&chunk[..len]);
+		check_line_length(buffer)?;
+		if complete {
+			let line = String::from_utf8_lossy(buffer).into_owned();
+			buffer.clear();
+			return Ok(line);
+		}
+	}
+}
+
+fn parse_reply<F>(reply: &str, reply_parser: F) -> Result<HashMap<String, String>, Error>
+where
+	F: Fn(&str) -> IResult<&str, Vec<(&str, &str)>>,
+{
+	let vec_opts = reply_parser(reply)?.1;
+	verify_response(&vec_opts).map(|m| {
+		m.iter()
+			.map(|(k, v)| (k.to_string(), v.to_string()))
+			.collect()
+	})
+}
+
+/// Takes `key` out of a reply, failing if the bridge left it out.
+fn reply_value(reply: &mut HashMap<String, String>, key: &str) -> Result<String, Error> {
+	reply.remove(key).ok_or_else(|| {
+		ErrorKind::SAMInvalidMessage(format!("{} missing from the reply", key)).into()
+	})
+}
+
+/// Extracts the peer destination from the line a router sends on an
+/// accepted stream before any of the peer's data.
+// TODO use a parser combinator
+fn parse_peer_line(line: &str) -> Result<(String, I2pSocketAddr), Error> {
+	let destination = line.split(" ").next().unwrap_or("").trim().to_string();
+	if destination.is_empty() {
+		return Err(ErrorKind::SAMKeyNotFound("No b64 destination in accept".to_string()).into());
+	}
+	let addr = I2pSocketAddr::new(I2pAddr::from_b64(&destination)?, 0);
+	Ok((destination, addr))
+}
+
+/// Time left until `deadline`, or `SAMTimeout` if it has passed.
+pub(crate) fn remaining(deadline: Instant) -> Result<Duration, Error> {
+	let now = Instant::now();
+	if now >= deadline {
+		return Err(ErrorKind::SAMTimeout("deadline expired".to_string()).into());
+	}
+	Ok(deadline - now)
+}
+
+/// Whether an `I2P_ERROR` reply to HELLO is the bridge refusing the
+/// credentials, or asking for some. Only Java I2P has authentication, and
+/// these are its messages: anything else is some other HELLO failure.
+fn is_auth_failure(msg: &str) -> bool {
+	["USER and PASSWORD required", "Authorization failed"].contains(&msg)
+}
+
+/// Whether `dest` is a full base64 destination rather than a hostname or
+/// b32 address that has to be resolved with a NAMING LOOKUP first.
+fn is_b64_destination(dest: &str) -> bool {
+	// 387 bytes is the smallest possible destination, 516 base64 characters
+	dest.len() >= 516 && I2pAddr::from_b64(dest).is_ok()
 }
 
 pub fn nickname() -> String {
@@ -424,3 +2243,610 @@ pub fn nickname() -> String {
 		.collect();
 	format!("i2prs-{}", suffix)
 }
+
+#[cfg(test)]
+mod tests {
+	use std::io::{Read, Write};
+	use std::net::{TcpListener, TcpStream};
+	use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+	use std::sync::{mpsc, Arc, Mutex};
+	use std::thread;
+	use std::time::Duration;
+
+	use crate::error::{Error, ErrorKind};
+	use crate::sam::{
+		read_line_exact, ConnectOptions, Connector, Credentials, Router, SamConnection, SamVersion,
+		Session, SessionBuilder, StreamConnect, StreamForward,
+	};
+	use crate::sam_options::{SAMOptions, SignatureType};
+	use crate::test::{default_reply, mock_sam};
+
+	// STREAM ACCEPT is answered, but no peer ever connects
+	fn idle_forward() -> StreamForward {
+		let sam = mock_sam(default_reply);
+		let session = Session::transient(sam, SAMOptions::default()).unwrap();
+		StreamForward::with_session(&session).unwrap()
+	}
+
+	#[test]
+	fn shutdown_unblocks_accept() {
+		let forward = idle_forward();
+		let handle = forward.shutdown_handle();
+		let acceptor = thread::spawn(move || forward.accept().map(|_| ()));
+		thread::sleep(Duration::from_millis(100));
+		handle.shutdown();
+
+		let err = acceptor.join().unwrap().unwrap_err();
+		assert_eq!(err.kind(), ErrorKind::ListenerClosed);
+	}
+
+	#[test]
+	fn accept_after_shutdown_fails() {
+		let forward = idle_forward();
+		forward.duplicate().unwrap().shutdown();
+		assert!(forward.is_shutdown());
+		assert_eq!(
+			forward.accept().unwrap_err().kind(),
+			ErrorKind::ListenerClosed
+		);
+	}
+
+	#[test]
+	fn accept_timeout_expires() {
+		let forward = idle_forward();
+		let err = forward
+			.accept_timeout(Duration::from_millis(100))
+			.unwrap_err();
+		match err.kind() {
+			ErrorKind::SAMTimeout(_) => {}
+			kind => panic!("unexpected error {:?}", kind),
+		}
+	}
+
+	#[test]
+	fn connect_timeout_expires() {
+		let sam = mock_sam(|line: &str| {
+			if line.starts_with("STREAM CONNECT") {
+				// the router is still looking up the leaseset
+				thread::sleep(Duration::from_secs(2));
+			}
+			default_reply(line)
+		});
+		let session = Session::transient(sam, SAMOptions::default()).unwrap();
+		let err = StreamConnect::with_session_timeout(
+			&session,
+			"example.i2p",
+			0,
+			Duration::from_millis(100),
+		)
+		.unwrap_err();
+		match err.kind() {
+			ErrorKind::SAMTimeout(_) => {}
+			kind => panic!("unexpected error {:?}", kind),
+		}
+	}
+
+	#[test]
+	fn forward_parses_peer_line() {
+		let commands = Arc::new(Mutex::new(Vec::new()));
+		let seen = commands.clone();
+		let sam = mock_sam(move |line: &str| {
+			if line.starts_with("HELLO") {
+				// ports come with SAM 3.2
+				return Some("HELLO REPLY RESULT=OK VERSION=3.2\n".to_string());
+			}
+			if line.starts_with("STREAM FORWARD") {
+				seen.lock().unwrap().push(line.to_string());
+			}
+			default_reply(line)
+		});
+		let session = Session::transient(sam, SAMOptions::default()).unwrap();
+		let listener = StreamForward::with_session(&session)
+			.unwrap()
+			.forward()
+			.unwrap();
+
+		let port = listener.listener_addr().unwrap().port();
+		let command = commands.lock().unwrap().pop().unwrap();
+		assert!(command.contains(&format!(" PORT={} HOST=127.0.0.1 SILENT=false", port)));
+
+		// what the router sends on each forwarded stream
+		let dest = "A".repeat(516);
+		let mut router = TcpStream::connect(("127.0.0.1", port)).unwrap();
+		write!(router, "{} FROM_PORT=1234 TO_PORT=80\npeer data", dest).unwrap();
+		drop(router);
+
+		let (mut stream, addr) = listener.accept().unwrap();
+		assert_eq!(stream.peer_dest, dest);
+		assert_eq!((stream.peer_port, stream.local_port), (1234, 80));
+		assert!(addr.dest().string().ends_with(".b32.i2p"));
+		let mut data = String::new();
+		stream.read_to_string(&mut data).unwrap();
+		assert_eq!(data, "peer data");
+	}
+
+	#[test]
+	fn forward_listener_outlives_silent_connectors() {
+		let sam = mock_sam(default_reply);
+		let session = Session::transient(sam, SAMOptions::default()).unwrap();
+		let forward = StreamForward::with_session(&session).unwrap();
+		let listener = forward.forward().unwrap();
+		let addr = listener.listener_addr().unwrap();
+
+		// never sends its destination line
+		let _silent = TcpStream::connect(addr).unwrap();
+		let mut router = TcpStream::connect(addr).unwrap();
+		writeln!(router, "{}", "A".repeat(516)).unwrap();
+		let (stream, _) = listener.accept().unwrap();
+		assert_eq!(stream.peer_dest, "A".repeat(516));
+
+		let handle = listener.shutdown_handle();
+		let (done, accepted) = mpsc::channel();
+		thread::spawn(move || {
+			let err = listener.accept().unwrap_err();
+			done.send(err.kind()).unwrap();
+		});
+		handle.shutdown();
+		assert_eq!(
+			accepted.recv_timeout(Duration::from_secs(5)),
+			Ok(ErrorKind::ListenerClosed)
+		);
+		assert!(forward.is_shutdown());
+		// the stream accepted before is still tracked
+		assert_eq!(handle.drain(Duration::from_millis(0)), 1);
+		drop(stream);
+	}
+
+	#[test]
+	fn capabilities_are_learnt_from_the_bridge() {
+		let sam = mock_sam(default_reply);
+		let session = Session::transient(sam, SAMOptions::default()).unwrap();
+		let caps = session.capabilities();
+		assert_eq!(
+			(caps.version, caps.router),
+			(SamVersion::V3_1, Router::Unknown)
+		);
+		assert!(!caps.accept_ports && !caps.ping);
+		let err = StreamForward::with_session(&session)
+			.unwrap()
+			.forward_to("127.0.0.1", 1234, false, true)
+			.unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::NoVersion(_)));
+
+		// answers PING, and sends ports with accepted streams
+		let ports = Arc::new(AtomicBool::new(true));
+		let with_ports = ports.clone();
+		let sam = mock_sam(move |line: &str| {
+			if line.starts_with("HELLO") {
+				Some("HELLO REPLY RESULT=OK VERSION=3.2\n".to_string())
+			} else if line.starts_with("PING") {
+				Some(line.replacen("PING", "PONG", 1))
+			} else if line.starts_with("STREAM ACCEPT") {
+				let ports = if with_ports.load(Ordering::SeqCst) {
+					" FROM_PORT=1234 TO_PORT=80"
+				} else {
+					""
+				};
+				Some(format!("STREAM STATUS RESULT=OK\nAAAA{}\n", ports))
+			} else {
+				default_reply(line)
+			}
+		});
+		let session = Session::transient(sam, SAMOptions::default()).unwrap();
+		let caps = session.probe().unwrap();
+		assert!(caps.ping && caps.accept_ports);
+		let forward = StreamForward::with_session(&session).unwrap();
+		let (stream, _) = forward.accept().unwrap();
+		assert_eq!((stream.peer_port, stream.local_port), (1234, 80));
+		assert_eq!(session.capabilities().router, Router::JavaI2p);
+		// the way i2pd does
+		ports.store(false, Ordering::SeqCst);
+		let (stream, _) = forward.accept().unwrap();
+		assert_eq!((stream.peer_port, stream.local_port), (0, 0));
+		let caps = session.capabilities();
+		assert_eq!(caps.router, Router::I2pd);
+		assert!(!caps.accept_ports);
+
+		// a bridge that hangs up on PING doesn't answer it
+		let sam = mock_sam(|line: &str| {
+			if line.starts_with("HELLO") {
+				Some("HELLO REPLY RESULT=OK VERSION=3.2\n".to_string())
+			} else {
+				default_reply(line)
+			}
+		});
+		let session = Session::transient(sam, SAMOptions::default()).unwrap();
+		assert!(session.capabilities().ping);
+		assert!(!session.probe().unwrap().ping);
+		assert!(!session.capabilities().ping);
+
+		assert_eq!("3".parse::<SamVersion>().unwrap(), SamVersion::V3_0);
+		assert!("3.x".parse::<SamVersion>().is_err());
+	}
+
+	#[test]
+	fn bridge_restart_is_an_error() {
+		let down = Arc::new(AtomicBool::new(false));
+		let flag = down.clone();
+		let sam = mock_sam(move |line: &str| {
+			if flag.load(Ordering::SeqCst) {
+				None
+			} else {
+				default_reply(line)
+			}
+		});
+		let session = Session::transient(sam, SAMOptions::default()).unwrap();
+		let forward = StreamForward::with_session(&session).unwrap();
+		down.store(true, Ordering::SeqCst);
+		assert!(StreamConnect::with_session(&session, "example.i2p", 0).is_err());
+		assert!(forward.accept().is_err());
+	}
+
+	#[test]
+	fn older_versions_get_no_newer_options() {
+		let commands = Arc::new(Mutex::new(Vec::new()));
+		let seen = commands.clone();
+		let sam = mock_sam(move |line: &str| {
+			seen.lock().unwrap().push(line.to_string());
+			if line.starts_with("HELLO") && line.contains("MAX=3.0") {
+				Some("HELLO REPLY RESULT=OK\n".to_string())
+			} else if line.starts_with("DEST GENERATE") {
+				Some("DEST REPLY PUB=pub PRIV=priv\n".to_string())
+			} else {
+				default_reply(line)
+			}
+		});
+		let no_version = |e: Error| matches!(e.kind(), ErrorKind::NoVersion(_));
+
+		// the mock speaks 3.1, ports need 3.2
+		let session = Session::transient(sam, SAMOptions::default()).unwrap();
+		let err = StreamConnect::with_session(&session, "example.i2p", 80).unwrap_err();
+		assert!(no_version(err));
+		StreamConnect::with_session(&session, "example.i2p", 0).unwrap();
+		let options = SAMOptions {
+			from_port: Some(80),
+			..Default::default()
+		};
+		assert!(no_version(Session::transient(sam, options).unwrap_err()));
+
+		// and SIGNATURE_TYPE 3.1
+		let pinned = ConnectOptions {
+			max_version: SamVersion::V3_0,
+			..Default::default()
+		};
+		let mut conn = SamConnection::connect_with(sam, pinned).unwrap();
+		let err = conn
+			.generate_destination(SignatureType::EdDsaSha512Ed25519)
+			.unwrap_err();
+		assert!(no_version(err));
+		conn.generate_destination(SignatureType::DsaSha1).unwrap();
+
+		let commands = commands.lock().unwrap();
+		assert!(commands.contains(&"DEST GENERATE \n".to_string()));
+		for command in commands.iter() {
+			assert!(!command.contains("PORT="), "{}", command);
+			assert!(!command.starts_with("DEST GENERATE SIG"), "{}", command);
+		}
+	}
+
+	#[test]
+	fn default_sessions_work_on_older_versions() {
+		let sam = mock_sam(|line: &str| {
+			if line.starts_with("HELLO") && line.contains("MAX=3.0") {
+				Some("HELLO REPLY RESULT=OK\n".to_string())
+			} else {
+				default_reply(line)
+			}
+		});
+		for version in &[SamVersion::V3_0, SamVersion::V3_1] {
+			let pinned = ConnectOptions {
+				max_version: *version,
+				..Default::default()
+			};
+			let session = SessionBuilder::default()
+				.with_addr(sam)
+				.with_connect_options(pinned)
+				.unwrap()
+				.build()
+				.unwrap();
+			assert_eq!(session.sam.version(), *version);
+		}
+	}
+
+	#[test]
+	fn connect_options_pin_versions() {
+		// picks the newest version asked for, and knows nothing older than 3.1
+		let sam = mock_sam(|line: &str| {
+			if line.starts_with("HELLO") {
+				let max = line
+					.split_whitespace()
+					.find_map(|word| word.strip_prefix("MAX="))
+					.unwrap();
+				Some(if max < "3.1" {
+					"HELLO REPLY RESULT=NOVERSION\n".to_string()
+				} else {
+					format!("HELLO REPLY RESULT=OK VERSION={}\n", max)
+				})
+			} else {
+				default_reply(line)
+			}
+		});
+		let pinned = ConnectOptions {
+			max_version: SamVersion::V3_1,
+			..Default::default()
+		};
+		let mut conn = SamConnection::connect_with(sam, pinned.clone()).unwrap();
+		assert_eq!(conn.version(), SamVersion::V3_1);
+		let err = conn.auth_disable().unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::NoVersion(_)));
+		let session = SessionBuilder::default()
+			.with_addr(sam)
+			.with_connect_options(pinned)
+			.unwrap()
+			.build()
+			.unwrap();
+		assert_eq!(session.capabilities().version, SamVersion::V3_1);
+		let conn = SamConnection::connect_with(sam, ConnectOptions::default()).unwrap();
+		assert_eq!(conn.version(), SamVersion::V3_2);
+
+		let too_old = ConnectOptions {
+			max_version: SamVersion::V3_0,
+			..Default::default()
+		};
+		match SamConnection::connect_with(sam, too_old)
+			.unwrap_err()
+			.kind()
+		{
+			ErrorKind::NoVersion(msg) => assert!(msg.contains("3.0 to 3.0"), "{}", msg),
+			kind => panic!("unexpected error {:?}", kind),
+		}
+		// a bridge answering with a version it wasn't offered
+		let too_new = ConnectOptions {
+			min_version: SamVersion::V3_2,
+			..Default::default()
+		};
+		let err = SamConnection::connect_with(mock_sam(default_reply), too_new).unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::NoVersion(_)));
+
+		// refused before connecting
+		for options in &[
+			ConnectOptions {
+				min_version: SamVersion::V3_2,
+				max_version: SamVersion::V3_1,
+				..Default::default()
+			},
+			ConnectOptions {
+				user: Some("alice".to_string()),
+				..Default::default()
+			},
+		] {
+			let err = SamConnection::connect_with(sam, options.clone()).unwrap_err();
+			assert!(matches!(err.kind(), ErrorKind::InvalidOption(_)));
+		}
+	}
+
+	/// Counts the connections made on behalf of a session.
+	#[derive(Debug)]
+	struct Counting {
+		addr: std::net::SocketAddr,
+		count: Arc<AtomicUsize>,
+	}
+
+	impl Connector for Counting {
+		type Transport = TcpStream;
+
+		fn connect(&self, timeout: Option<Duration>) -> std::io::Result<TcpStream> {
+			self.count.fetch_add(1, Ordering::SeqCst);
+			self.addr.connect(timeout)
+		}
+	}
+
+	#[test]
+	fn session_connects_through_its_connector() {
+		let count = Arc::new(AtomicUsize::new(0));
+		let session = SessionBuilder::default()
+			.with_connector(Counting {
+				addr: mock_sam(default_reply),
+				count: count.clone(),
+			})
+			.build()
+			.unwrap();
+		StreamConnect::with_session(&session, "example.i2p", 0).unwrap();
+		StreamForward::with_session(&session)
+			.unwrap()
+			.forward_to("127.0.0.1", 1234, false, false)
+			.unwrap();
+		assert_eq!(count.load(Ordering::SeqCst), 3);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn session_over_unix_socket() {
+		use crate::sam::UnixConnector;
+		use crate::test::mock_sam_unix;
+
+		let path = mock_sam_unix(|line: &str| {
+			if line.starts_with("HELLO") {
+				// ports come with SAM 3.2
+				Some("HELLO REPLY RESULT=OK VERSION=3.2\n".to_string())
+			} else {
+				default_reply(line)
+			}
+		});
+		let session = SessionBuilder::default()
+			.with_connector(UnixConnector::new(&path))
+			.build()
+			.unwrap();
+		assert_eq!(session.local_dest, "dest");
+		let stream = StreamConnect::with_session(&session, "example.i2p", 80).unwrap();
+		assert_eq!(stream.peer_addr().unwrap(), ("dest".to_string(), 80));
+		let _ = std::fs::remove_file(&path);
+	}
+
+	#[test]
+	fn read_line_exact_leaves_stream_data() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let (server, _) = listener.accept().unwrap();
+		client
+			.write_all(b"STREAM STATUS RESULT=OK\nAAAA\npeer data")
+			.unwrap();
+		drop(client);
+
+		let mut buffer = Vec::new();
+		assert_eq!(
+			read_line_exact(&server, &mut buffer).unwrap(),
+			"STREAM STATUS RESULT=OK\n"
+		);
+		assert_eq!(read_line_exact(&server, &mut buffer).unwrap(), "AAAA\n");
+		let mut rest = String::new();
+		(&server).read_to_string(&mut rest).unwrap();
+		assert_eq!(rest, "peer data");
+	}
+
+	#[test]
+	fn try_accept_would_block_until_peer() {
+		// the bridge answers once the test lets the peer in
+		let (peer, arrived) = mpsc::channel::<()>();
+		let arrived = Arc::new(Mutex::new(arrived));
+		let sam = mock_sam(move |line: &str| {
+			if line.starts_with("STREAM ACCEPT") {
+				arrived.lock().unwrap().recv().ok()?;
+				Some("STREAM STATUS RESULT=OK\nAAAA FROM_PORT=0 TO_PORT=0\n".to_string())
+			} else {
+				default_reply(line)
+			}
+		});
+		let session = Session::transient(sam, SAMOptions::default()).unwrap();
+		let forward = StreamForward::with_session(&session).unwrap();
+
+		let mut pending = forward.start_accept().unwrap();
+		assert_eq!(
+			pending.try_accept().unwrap_err().kind(),
+			ErrorKind::WouldBlock
+		);
+		peer.send(()).unwrap();
+		let (stream, _) = loop {
+			match pending.try_accept() {
+				Ok(accepted) => break accepted,
+				Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+					thread::sleep(Duration::from_millis(5))
+				}
+				Err(e) => panic!("accept failed: {}", e),
+			}
+		};
+		assert_eq!(stream.peer_dest, "AAAA");
+		assert!(pending.try_accept().is_err());
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn raw_fd_round_trip() {
+		use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+
+		let sam = SamConnection::connect(mock_sam(default_reply)).unwrap();
+		let fd = sam.as_raw_fd();
+		assert_eq!(sam.into_raw_fd(), fd);
+		let mut sam = unsafe { SamConnection::from_raw_fd(fd) };
+		assert_eq!(sam.naming_lookup("ME").unwrap(), "dest");
+	}
+
+	#[test]
+	fn drain_closes_remaining_streams() {
+		let sam = mock_sam(|line: &str| {
+			if line.starts_with("STREAM ACCEPT") {
+				Some("STREAM STATUS RESULT=OK\nAAAA FROM_PORT=0 TO_PORT=0\n".to_string())
+			} else {
+				default_reply(line)
+			}
+		});
+		let session = Session::transient(sam, SAMOptions::default()).unwrap();
+		let forward = StreamForward::with_session(&session).unwrap();
+
+		let (first, _) = forward.accept().unwrap();
+		let (second, _) = forward.accept().unwrap();
+		drop(first);
+		forward.shutdown();
+		assert_eq!(forward.drain(Duration::from_millis(50)), 1);
+		drop(second);
+		assert_eq!(forward.drain(Duration::from_millis(50)), 0);
+	}
+
+	// a bridge with authentication enabled for alice
+	fn auth_reply(line: &str) -> Option<String> {
+		if line.starts_with("HELLO") && !line.contains(" USER=") {
+			Some(
+				"HELLO REPLY RESULT=I2P_ERROR MESSAGE=\"USER and PASSWORD required\"\n".to_string(),
+			)
+		} else if line.starts_with("HELLO")
+			&& !line.contains(" USER=\"alice\" PASSWORD=\"secret\" ")
+		{
+			Some("HELLO REPLY RESULT=I2P_ERROR MESSAGE=\"Authorization failed\"\n".to_string())
+		} else if line.starts_with("HELLO") {
+			// only Java I2P has authentication
+			Some("HELLO REPLY RESULT=OK VERSION=3.2\n".to_string())
+		} else if line.starts_with("AUTH REMOVE") && !line.contains("USER=\"alice\"") {
+			Some("AUTH STATUS RESULT=I2P_ERROR MESSAGE=\"no such user\"\n".to_string())
+		} else if line.starts_with("AUTH") {
+			Some("AUTH STATUS RESULT=OK\n".to_string())
+		} else {
+			default_reply(line)
+		}
+	}
+
+	#[test]
+	fn session_authenticates_every_connection() {
+		let sam = mock_sam(auth_reply);
+		let session = SessionBuilder::default()
+			.with_addr(sam)
+			.with_credentials(Credentials::new("alice", "secret"))
+			.build()
+			.unwrap();
+		assert_eq!(session.credentials().unwrap().user, "alice");
+		// the stream has to HELLO on a new connection
+		StreamConnect::with_session(&session, "example.i2p", 0).unwrap();
+
+		for credentials in &[None, Some(Credentials::new("alice", "wrong"))] {
+			let result = match credentials {
+				Some(credentials) => {
+					SamConnection::connect_with_credentials(sam, credentials.clone())
+				}
+				None => SamConnection::connect(sam),
+			};
+			match result.unwrap_err().kind() {
+				ErrorKind::SAMAuthFailed(_) => {}
+				kind => panic!("unexpected error {:?}", kind),
+			}
+		}
+		// other HELLO failures that happen to mention a user
+		assert!(!super::is_auth_failure("Unknown user agent"));
+	}
+
+	#[test]
+	fn auth_commands() {
+		let sam = mock_sam(auth_reply);
+		let mut conn =
+			SamConnection::connect_with_credentials(sam, Credentials::new("alice", "secret"))
+				.unwrap();
+		conn.auth_add(&Credentials::new("bob", "hunter2")).unwrap();
+		conn.auth_enable().unwrap();
+		conn.auth_disable().unwrap();
+		conn.auth_remove("alice").unwrap();
+		match conn.auth_remove("bob").unwrap_err().kind() {
+			ErrorKind::SAMAuthError(msg) => assert_eq!(msg, "no such user"),
+			kind => panic!("unexpected error {:?}", kind),
+		}
+		// refused before anything is sent
+		let err = conn
+			.auth_add(&Credentials::new("mallory", "x\" USER=\"root"))
+			.unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::InvalidOption(_)));
+		let err = conn
+			.auth_add(&Credentials::new("mallory", "x\\"))
+			.unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::InvalidOption(_)));
+		let err = conn.auth_remove("bob\" USER=\"alice").unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::InvalidOption(_)));
+		assert!(!format!("{:?}", Credentials::new("alice", "secret")).contains("secret"));
+	}
+}
@@ -0,0 +1,136 @@
+//! what the SAM bridge at the other end supports
+//!
+//! SAM has no command to ask a bridge what it is or what it can do. The
+//! version negotiated with `HELLO` says what the protocol allows, and the
+//! rest is learnt from what the bridge does: whether it answers `PING`, see
+//! `SamConnection::probe`, and whether it puts ports on the destination line
+//! of accepted streams, which Java I2P does from SAM 3.2 on and i2pd doesn't.
+//! All connections of a session share what was learnt.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::{Error, ErrorKind};
+
+/// A version of the SAM protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SamVersion {
+	pub major: u8,
+	pub minor: u8,
+}
+
+/// The router behind a SAM bridge, as far as it gave itself away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Router {
+	JavaI2p,
+	I2pd,
+	Unknown,
+}
+
+/// What a SAM bridge supports, see `SamConnection::capabilities`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+	/// the version negotiated with `HELLO`
+	pub version: SamVersion,
+	pub router: Router,
+	/// `FROM_PORT` and `TO_PORT` on the destination line of accepted
+	/// streams. Cleared once an accepted stream comes without them.
+	pub accept_ports: bool,
+	/// `PRIMARY` sessions with subsessions
+	pub primary: bool,
+	/// `PING` and `PONG`. Cleared if the bridge didn't answer a probe.
+	pub ping: bool,
+	/// the `DATAGRAM2` and `DATAGRAM3` styles. Java I2P only has them from
+	/// 2.9.0 on, which SAM does not reveal, so a session may still be refused.
+	pub datagram2: bool,
+	/// the `AUTH` commands
+	pub auth: bool,
+}
+
+impl SamVersion {
+	pub const V3_0: SamVersion = SamVersion::new(3, 0);
+	pub const V3_1: SamVersion = SamVersion::new(3, 1);
+	pub const V3_2: SamVersion = SamVersion::new(3, 2);
+	pub const V3_3: SamVersion = SamVersion::new(3, 3);
+
+	pub const fn new(major: u8, minor: u8) -> SamVersion {
+		SamVersion { major, minor }
+	}
+}
+
+/// Parses `3.1`, or `3` for `3.0`.
+impl FromStr for SamVersion {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<SamVersion, Error> {
+		let invalid = || {
+			Error::from(ErrorKind::SAMInvalidMessage(format!(
+				"bad SAM version {}",
+				s
+			)))
+		};
+		let (major, minor) = s.split_once('.').unwrap_or((s, "0"));
+		Ok(SamVersion {
+			major: major.parse().map_err(|_| invalid())?,
+			minor: minor.parse().map_err(|_| invalid())?,
+		})
+	}
+}
+
+impl fmt::Display for SamVersion {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}.{}", self.major, self.minor)
+	}
+}
+
+impl Capabilities {
+	/// What the protocol allows once `version` is negotiated, before the
+	/// bridge showed what it actually does.
+	pub fn for_version(version: SamVersion) -> Capabilities {
+		Capabilities {
+			version,
+			router: Router::Unknown,
+			accept_ports: version >= SamVersion::V3_2,
+			primary: version >= SamVersion::V3_3,
+			ping: version >= SamVersion::V3_2,
+			datagram2: version >= SamVersion::V3_3,
+			auth: version >= SamVersion::V3_2,
+		}
+	}
+
+	/// Learns from the destination line of an accepted stream, returning its
+	/// `(FROM_PORT, TO_PORT)`, zero if the bridge doesn't send them.
+	pub(crate) fn observe_peer_line(&mut self, line: &str) -> (u16, u16) {
+		if !self.accept_ports {
+			return (0, 0);
+		}
+		let mut ports = (None, None);
+		for option in line.split_whitespace().skip(1) {
+			match option.split_once('=') {
+				Some(("FROM_PORT", port)) => ports.0 = Some(port.parse().unwrap_or(0)),
+				Some(("TO_PORT", port)) => ports.1 = Some(port.parse().unwrap_or(0)),
+				_ => {}
+			}
+		}
+		match ports {
+			(None, None) => {
+				// i2pd leaves them out whatever the version
+				self.accept_ports = false;
+				self.router = Router::I2pd;
+				(0, 0)
+			}
+			(from, to) => {
+				self.router = Router::JavaI2p;
+				(from.unwrap_or(0), to.unwrap_or(0))
+			}
+		}
+	}
+}
+
+/// What a SAM 3.0 bridge can be relied on for, for connections that skipped
+/// `HELLO` on our side, e.g. from `FromRawFd`.
+impl Default for Capabilities {
+	fn default() -> Capabilities {
+		Capabilities::for_version(SamVersion::V3_0)
+	}
+}
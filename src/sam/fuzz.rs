@@ -0,0 +1,140 @@
+//! hostile SAM replies
+//!
+//! Whatever a bridge sends, talking to it must end in `Ok` or `Err`, never
+//! in a panic. Replies are fed to connections through an in-memory
+//! transport, so every case runs without sockets or threads.
+
+use std::io::{self, Cursor, Read, Write};
+use std::net::Shutdown;
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+use super::{
+	check_line_length, parse_peer_line, Capabilities, ConnectParams, SamConnection, SamTransport,
+	SamVersion, MAX_LINE, SAM_MAX, SAM_MIN,
+};
+use crate::sam_options::SignatureType;
+
+/// A bridge that answers anything with the same canned bytes.
+#[derive(Clone, Debug)]
+struct Canned(Cursor<Vec<u8>>);
+
+impl Read for Canned {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.0.read(buf)
+	}
+}
+
+impl Write for Canned {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		Ok(buf.len())
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl SamTransport for Canned {
+	fn try_clone(&self) -> io::Result<Self> {
+		Ok(self.clone())
+	}
+	fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+fn bridge(reply: &[u8]) -> SamConnection<Canned> {
+	SamConnection::new(
+		Canned(Cursor::new(reply.to_vec())),
+		None,
+		ConnectParams::default(),
+	)
+}
+
+/// Replies that look right at first glance, with keys missing or repeated
+/// and values out of range, and plain garbage.
+fn reply() -> impl Strategy<Value = Vec<u8>> {
+	let head = prop_oneof![
+		Just("HELLO REPLY "),
+		Just("NAMING REPLY "),
+		Just("DEST REPLY "),
+		Just("SESSION STATUS "),
+		Just("STREAM STATUS "),
+		Just(""),
+	];
+	let key = prop_oneof![
+		Just("RESULT".to_string()),
+		Just("VERSION".to_string()),
+		Just("VALUE".to_string()),
+		Just("PUB".to_string()),
+		Just("PRIV".to_string()),
+		Just("MESSAGE".to_string()),
+		"[A-Za-z0-9]{0,8}",
+	];
+	let value = prop_oneof![
+		Just("OK".to_string()),
+		Just("NOVERSION".to_string()),
+		Just("I2P_ERROR".to_string()),
+		"[0-9]{0,4}(\\.[0-9]{0,4}){0,2}",
+		"\"[^\"]{0,20}\"?",
+		"\\PC{0,20}",
+	];
+	let line = (head, vec((key, value), 0..6), any::<bool>()).prop_map(|(head, pairs, newline)| {
+		let pairs: Vec<String> = pairs.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+		let mut line = format!("{}{}", head, pairs.join(" "));
+		if newline {
+			line.push('\n');
+		}
+		line.into_bytes()
+	});
+	prop_oneof![line, vec(any::<u8>(), 0..256)]
+}
+
+proptest! {
+	#[test]
+	fn hello_never_panics(reply in reply()) {
+		let mut sam = bridge(&reply);
+		if sam.handshake().is_ok() {
+			prop_assert!(sam.version() >= SAM_MIN && sam.version() <= SAM_MAX);
+		}
+	}
+
+	#[test]
+	fn replies_never_panic(reply in reply()) {
+		if let Ok(value) = bridge(&reply).naming_lookup("example.i2p") {
+			prop_assert!(String::from_utf8_lossy(&reply).contains(&value));
+		}
+		let _ = bridge(&reply).generate_destination(SignatureType::EdDsaSha512Ed25519);
+	}
+
+	#[test]
+	fn accept_lines_never_panic(line in "\\PC{0,600}") {
+		if let Ok((destination, _)) = parse_peer_line(&line) {
+			prop_assert!(!destination.is_empty());
+		}
+		Capabilities::for_version(SamVersion::V3_3).observe_peer_line(&line);
+	}
+
+	#[test]
+	fn read_line_leaves_the_stream_alone(
+		line in "[^\n]{0,300}",
+		rest in vec(any::<u8>(), 0..64),
+	) {
+		let mut sent = format!("{}\n", line).into_bytes();
+		sent.extend_from_slice(&rest);
+		let mut sam = bridge(&sent);
+		let mut buffer = Vec::new();
+		prop_assert_eq!(sam.read_line(&mut buffer).unwrap(), format!("{}\n", line));
+		let mut data = Vec::new();
+		sam.read_to_end(&mut data).unwrap();
+		prop_assert_eq!(data, rest);
+	}
+}
+
+#[test]
+fn overlong_lines_are_refused() {
+	let mut sam = bridge(&vec![b'A'; MAX_LINE + 1]);
+	assert!(sam.handshake().is_err());
+	assert!(check_line_length(&[b'A'; MAX_LINE]).is_ok());
+}
@@ -0,0 +1,556 @@
+//! recording SAM exchanges and playing them back
+//!
+//! A [Recorder] writes every command sent to the bridge and every line it
+//! answers with, along with when it happened, as a [Transcript]. A
+//! [ReplayServer] plays a transcript back as a fake bridge, so a session
+//! against a real router can be reproduced without one.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use i2p::sam::{Recorder, ReplayServer, SessionBuilder, Transcript};
+//!
+//! let recorder = Recorder::to_file("session.sam").unwrap();
+//! let session = SessionBuilder::default()
+//!     .with_recorder(recorder)
+//!     .build()
+//!     .unwrap();
+//! drop(session);
+//!
+//! let replay = ReplayServer::start(&Transcript::load("session.sam").unwrap()).unwrap();
+//! let session = SessionBuilder::default()
+//!     .with_addr(replay.addr())
+//!     .build()
+//!     .unwrap();
+//! assert!(replay.mismatches().is_empty());
+//! ```
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use super::read_line_exact;
+use crate::error::{Error, ErrorKind};
+
+static HEADER: &str =
+	"# SAM transcript: <milliseconds> <connection> <'>' sent | '<' received> <line>";
+
+/// Which end of a SAM connection a line came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+	/// a command sent to the bridge
+	Client,
+	/// a line sent by the bridge
+	Bridge,
+}
+
+/// One line exchanged with the bridge.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+	/// time since the recording started
+	pub at: Duration,
+	/// the connection the line went over, numbered from 1 in the order they
+	/// were opened
+	pub conn: u64,
+	pub direction: Direction,
+	/// the line without its line break
+	pub line: String,
+}
+
+/// A recorded SAM exchange, one `Entry` per line of its text form.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Transcript {
+	pub entries: Vec<Entry>,
+}
+
+/// Records SAM connections to a transcript. Clones write to the same one.
+///
+/// Passwords, private keys and secret session options are replaced with
+/// `***`, so transcripts can be shared.
+#[derive(Clone)]
+pub struct Recorder {
+	inner: Arc<Mutex<RecorderState>>,
+}
+
+struct RecorderState {
+	start: Instant,
+	last_conn: u64,
+	out: Box<dyn Write + Send>,
+}
+
+/// A recorded connection, see `SamConnection::record`.
+#[derive(Clone, Debug)]
+pub(crate) struct Recording {
+	recorder: Recorder,
+	conn: u64,
+}
+
+/// A fake SAM bridge playing back a transcript on a local port.
+///
+/// The n-th connection made to the server plays the n-th connection of the
+/// transcript: each recorded command is expected in turn, matched on its
+/// first two words since nicknames and the like change from run to run, and
+/// answered with the lines the bridge sent back. Once a connection's script
+/// is over, anything sent on it is ignored until the client closes it, which
+/// leaves streams and sessions open as long as the client needs them.
+///
+/// The server stops when dropped.
+pub struct ReplayServer {
+	addr: SocketAddr,
+	mismatches: Arc<Mutex<Vec<String>>>,
+	stopped: Arc<AtomicBool>,
+}
+
+impl fmt::Display for Direction {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match *self {
+			Direction::Client => ">",
+			Direction::Bridge => "<",
+		})
+	}
+}
+
+impl fmt::Display for Entry {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{:>8} {} {} {}",
+			self.at.as_millis(),
+			self.conn,
+			self.direction,
+			self.line
+		)
+	}
+}
+
+impl FromStr for Entry {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Entry, Error> {
+		let invalid = || Error::from(ErrorKind::InvalidTranscript(s.to_string()));
+		let mut fields = s.trim_start().splitn(4, ' ');
+		let at = fields
+			.next()
+			.and_then(|at| at.parse().ok())
+			.ok_or_else(invalid)?;
+		let conn = fields
+			.next()
+			.and_then(|c| c.parse().ok())
+			.ok_or_else(invalid)?;
+		let direction = match fields.next() {
+			Some(">") => Direction::Client,
+			Some("<") => Direction::Bridge,
+			_ => return Err(invalid()),
+		};
+		Ok(Entry {
+			at: Duration::from_millis(at),
+			conn,
+			direction,
+			line: fields.next().unwrap_or("").to_string(),
+		})
+	}
+}
+
+impl Transcript {
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<Transcript, Error> {
+		fs::read_to_string(path)?.parse()
+	}
+
+	/// The entries of each connection, in the order the connections were
+	/// opened.
+	pub fn connections(&self) -> Vec<Vec<Entry>> {
+		let mut conns: Vec<(u64, Vec<Entry>)> = Vec::new();
+		for entry in &self.entries {
+			match conns.iter_mut().find(|(conn, _)| *conn == entry.conn) {
+				Some((_, entries)) => entries.push(entry.clone()),
+				None => conns.push((entry.conn, vec![entry.clone()])),
+			}
+		}
+		conns.into_iter().map(|(_, entries)| entries).collect()
+	}
+}
+
+/// Lines starting with `#` and blank lines are skipped.
+impl FromStr for Transcript {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Transcript, Error> {
+		let entries = s
+			.lines()
+			.filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+			.map(str::parse)
+			.collect::<Result<_, _>>()?;
+		Ok(Transcript { entries })
+	}
+}
+
+impl fmt::Display for Transcript {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		writeln!(f, "{}", HEADER)?;
+		for entry in &self.entries {
+			writeln!(f, "{}", entry)?;
+		}
+		Ok(())
+	}
+}
+
+impl Recorder {
+	pub fn new<W: Write + Send + 'static>(out: W) -> Recorder {
+		let mut out: Box<dyn Write + Send> = Box::new(out);
+		if let Err(e) = writeln!(out, "{}", HEADER) {
+			debug!("failed to record SAM transcript: {}", e);
+		}
+		Recorder {
+			inner: Arc::new(Mutex::new(RecorderState {
+				start: Instant::now(),
+				last_conn: 0,
+				out,
+			})),
+		}
+	}
+
+	/// Records to the file at `path`, replacing it if it exists.
+	pub fn to_file<P: AsRef<Path>>(path: P) -> Result<Recorder, Error> {
+		Ok(Recorder::new(BufWriter::new(File::create(path)?)))
+	}
+
+	fn lock(&self) -> MutexGuard<'_, RecorderState> {
+		self.inner.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	/// Numbers a new connection to record.
+	pub(crate) fn recording(&self) -> Recording {
+		let mut state = self.lock();
+		state.last_conn += 1;
+		Recording {
+			recorder: self.clone(),
+			conn: state.last_conn,
+		}
+	}
+}
+
+/// Leaves out where the transcript goes.
+impl fmt::Debug for Recorder {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Recorder").finish_non_exhaustive()
+	}
+}
+
+impl Recording {
+	/// Adds `line` to the transcript. Failing to write it only gets logged,
+	/// since recording must not break the connection.
+	pub(crate) fn record(&self, direction: Direction, line: &str) {
+		let mut state = self.recorder.lock();
+		let entry = Entry {
+			at: state.start.elapsed(),
+			conn: self.conn,
+			direction,
+			line: redact(line.trim_end()),
+		};
+		if let Err(e) = writeln!(state.out, "{}", entry).and_then(|_| state.out.flush()) {
+			debug!("failed to record SAM transcript: {}", e);
+		}
+	}
+}
+
+/// Replaces the values of secret `KEY=VALUE` pairs in `line` with `***`.
+fn redact(line: &str) -> String {
+	let session = line.starts_with("SESSION ");
+	let mut redacted = String::with_capacity(line.len());
+	let mut rest = line;
+	while !rest.is_empty() {
+		let spaces = rest.len() - rest.trim_start_matches(' ').len();
+		redacted.push_str(&rest[..spaces]);
+		rest = &rest[spaces..];
+		let (word, after) = rest.split_at(word_end(rest));
+		match word.split_once('=') {
+			Some((key, value)) if is_secret(key, value, session) => {
+				redacted.push_str(key);
+				redacted.push_str(if value.starts_with('"') {
+					"=\"***\""
+				} else {
+					"=***"
+				});
+			}
+			_ => redacted.push_str(word),
+		}
+		rest = after;
+	}
+	redacted
+}
+
+/// End of the word `line` starts with, the first space outside of quotes.
+fn word_end(line: &str) -> usize {
+	let (mut quoted, mut escaped) = (false, false);
+	for (i, c) in line.char_indices() {
+		match c {
+			_ if escaped => escaped = false,
+			'\\' if quoted => escaped = true,
+			'"' => quoted = !quoted,
+			' ' if !quoted => return i,
+			_ => {}
+		}
+	}
+	line.len()
+}
+
+/// Whether the value of `key` must not end up in a transcript: passwords,
+/// the private keys in `DEST REPLY` and of sessions, and the secret keys
+/// among the I2CP options.
+fn is_secret(key: &str, value: &str, session: bool) -> bool {
+	let key = key.to_ascii_lowercase();
+	match key.as_str() {
+		"password" | "priv" => true,
+		"destination" => session && value != "TRANSIENT",
+		_ => {
+			let option = key.rsplit('.').next().unwrap_or("");
+			key.starts_with("i2cp.leasesetkey")
+				|| key.contains(".psk")
+				|| option.contains("password")
+				|| option.contains("privkey")
+				|| option.contains("privatekey")
+				|| option.contains("secret")
+		}
+	}
+}
+
+impl ReplayServer {
+	/// Plays `transcript` back on a free port of localhost, answering each
+	/// command right away.
+	pub fn start(transcript: &Transcript) -> Result<ReplayServer, Error> {
+		Self::spawn(transcript, false)
+	}
+
+	/// Same as `start`, but takes as long to answer as the bridge did when
+	/// the transcript was recorded, e.g. to reproduce timeouts.
+	pub fn start_timed(transcript: &Transcript) -> Result<ReplayServer, Error> {
+		Self::spawn(transcript, true)
+	}
+
+	fn spawn(transcript: &Transcript, timed: bool) -> Result<ReplayServer, Error> {
+		let listener = TcpListener::bind("127.0.0.1:0")?;
+		let server = ReplayServer {
+			addr: listener.local_addr()?,
+			mismatches: Default::default(),
+			stopped: Default::default(),
+		};
+		let mut scripts = transcript.connections().into_iter();
+		let mismatches = server.mismatches.clone();
+		let stopped = server.stopped.clone();
+		thread::spawn(move || {
+			for (n, conn) in listener.incoming().enumerate() {
+				if stopped.load(Ordering::SeqCst) {
+					return;
+				}
+				let conn = match conn {
+					Ok(conn) => conn,
+					Err(_) => continue,
+				};
+				let mismatches = mismatches.clone();
+				match scripts.next() {
+					Some(script) => {
+						thread::spawn(move || play(conn, n + 1, &script, timed, &mismatches));
+					}
+					None => lock(&mismatches).push(format!("unexpected connection {}", n + 1)),
+				}
+			}
+		});
+		Ok(server)
+	}
+
+	/// The address to connect to in place of the bridge.
+	pub fn addr(&self) -> SocketAddr {
+		self.addr
+	}
+
+	/// Where the client strayed from the transcript so far.
+	pub fn mismatches(&self) -> Vec<String> {
+		lock(&self.mismatches).clone()
+	}
+}
+
+impl Drop for ReplayServer {
+	fn drop(&mut self) {
+		self.stopped.store(true, Ordering::SeqCst);
+		// wake up the accept loop so that it sees the flag
+		let _ = TcpStream::connect(self.addr);
+	}
+}
+
+fn lock(mismatches: &Mutex<Vec<String>>) -> MutexGuard<'_, Vec<String>> {
+	mismatches.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn play(
+	mut conn: TcpStream,
+	n: usize,
+	script: &[Entry],
+	timed: bool,
+	mismatches: &Mutex<Vec<String>>,
+) {
+	let mut last = Duration::from_secs(0);
+	let mut buffer = Vec::new();
+	for entry in script {
+		match entry.direction {
+			Direction::Client => {
+				let line = match read_line_exact(&conn, &mut buffer) {
+					Ok(line) => line,
+					Err(_) => {
+						lock(mismatches).push(format!(
+							"connection {}: closed instead of sending `{}`",
+							n, entry.line
+						));
+						return;
+					}
+				};
+				if command(&line) != command(&entry.line) {
+					lock(mismatches).push(format!(
+						"connection {}: expected `{}`, got `{}`",
+						n,
+						entry.line,
+						line.trim_end()
+					));
+					return;
+				}
+			}
+			Direction::Bridge => {
+				if timed {
+					thread::sleep(entry.at.checked_sub(last).unwrap_or_default());
+				}
+				let sent = conn
+					.write_all(entry.line.as_bytes())
+					.and_then(|_| conn.write_all(b"\n"));
+				if sent.is_err() {
+					return;
+				}
+			}
+		}
+		last = entry.at;
+	}
+	// stream data, or nothing until the client closes the session
+	let _ = io::copy(&mut conn, &mut io::sink());
+}
+
+/// The part of a command that has to match the transcript, e.g.
+/// `SESSION CREATE`.
+fn command(line: &str) -> Vec<&str> {
+	line.split_whitespace().take(2).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::{redact, Direction, Entry, ReplayServer, Transcript};
+	use crate::sam::{Credentials, Recorder, SessionBuilder, StreamConnect};
+	use crate::test::{default_reply, mock_sam};
+
+	#[test]
+	fn parses_transcripts() {
+		let transcript: Transcript = "# comment\n\n      12 1 > HELLO VERSION MIN=3.0 MAX=3.2\n"
+			.parse()
+			.unwrap();
+		assert_eq!(
+			transcript.entries,
+			vec![Entry {
+				at: Duration::from_millis(12),
+				conn: 1,
+				direction: Direction::Client,
+				line: "HELLO VERSION MIN=3.0 MAX=3.2".to_string(),
+			}]
+		);
+		assert_eq!(
+			transcript.to_string().parse::<Transcript>().unwrap(),
+			transcript
+		);
+		assert!("12 1 HELLO".parse::<Transcript>().is_err());
+	}
+
+	#[test]
+	fn secrets_are_redacted() {
+		let cases = [
+			(
+				"HELLO VERSION USER=\"a\" PASSWORD=\"secret\"",
+				"HELLO VERSION USER=\"a\" PASSWORD=\"***\"",
+			),
+			(
+				"HELLO VERSION USER=a PASSWORD=secret MAX=3.2",
+				"HELLO VERSION USER=a PASSWORD=*** MAX=3.2",
+			),
+			(
+				"AUTH ADD USER=\"a\" PASSWORD=\"s \\\" e\"",
+				"AUTH ADD USER=\"a\" PASSWORD=\"***\"",
+			),
+			(
+				"SESSION CREATE STYLE=STREAM ID=a DESTINATION=privkey i2cp.password=p \
+				 i2cp.leaseSetPrivateKey=0:k i2cp.leaseSetKey=k i2cp.leaseSetSecret=s \
+				 i2cp.leaseSetClient.psk.0=k inbound.length=2",
+				"SESSION CREATE STYLE=STREAM ID=a DESTINATION=*** i2cp.password=*** \
+				 i2cp.leaseSetPrivateKey=*** i2cp.leaseSetKey=*** i2cp.leaseSetSecret=*** \
+				 i2cp.leaseSetClient.psk.0=*** inbound.length=2",
+			),
+			(
+				"SESSION CREATE STYLE=STREAM ID=a DESTINATION=TRANSIENT",
+				"SESSION CREATE STYLE=STREAM ID=a DESTINATION=TRANSIENT",
+			),
+			(
+				"SESSION STATUS RESULT=OK DESTINATION=privkey",
+				"SESSION STATUS RESULT=OK DESTINATION=***",
+			),
+			(
+				"DEST REPLY PUB=pub PRIV=priv",
+				"DEST REPLY PUB=pub PRIV=***",
+			),
+			(
+				"STREAM CONNECT ID=a DESTINATION=peer SILENT=false",
+				"STREAM CONNECT ID=a DESTINATION=peer SILENT=false",
+			),
+		];
+		for (line, redacted) in cases.iter() {
+			assert_eq!(redact(line), *redacted);
+		}
+	}
+
+	#[test]
+	fn replays_recorded_sessions() {
+		let path = std::env::temp_dir().join(format!("i2p-rs-{}.sam", std::process::id()));
+		let session = SessionBuilder::default()
+			.with_addr(mock_sam(default_reply))
+			.with_credentials(Credentials::new("alice", "secret"))
+			.with_recorder(Recorder::to_file(&path).unwrap())
+			.build()
+			.unwrap();
+		StreamConnect::with_session(&session, "example.i2p", 0).unwrap();
+		drop(session);
+
+		let transcript = Transcript::load(&path).unwrap();
+		let _ = std::fs::remove_file(&path);
+		let conns = transcript.connections();
+		assert_eq!(conns.len(), 2);
+		assert!(conns[0][0].line.ends_with("PASSWORD=\"***\""));
+		assert!(conns[1].last().unwrap().line.starts_with("STREAM STATUS"));
+
+		let replay = ReplayServer::start(&transcript).unwrap();
+		let session = SessionBuilder::default()
+			.with_addr(replay.addr())
+			.with_credentials(Credentials::new("alice", "secret"))
+			.build()
+			.unwrap();
+		assert_eq!(session.local_dest, "dest");
+		StreamConnect::with_session(&session, "example.i2p", 0).unwrap();
+		assert!(replay.mismatches().is_empty());
+
+		// the transcript has no third connection
+		assert!(StreamConnect::with_session(&session, "example.i2p", 0).is_err());
+		assert_eq!(replay.mismatches(), vec!["unexpected connection 3"]);
+	}
+}
@@ -0,0 +1,176 @@
+//! what SAM connections run over
+//!
+//! A [SamConnection](super::SamConnection) talks to the bridge over any
+//! [SamTransport], TCP by default. Sessions keep the [Connector] they were
+//! created with and use it for every further connection they need, such as
+//! the data connections of their streams.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::{check_line_length, read_line_exact};
+
+/// A connection to a SAM bridge.
+///
+/// Only `try_clone` and `shutdown` are required. Transports without
+/// timeouts can't be used with the `_timeout` functions, and ones without
+/// non-blocking mode can't be used with `StreamForward::start_accept`.
+pub trait SamTransport: Read + Write + Send + Sized + 'static {
+	/// Another handle to the same connection, e.g. to shut it down from
+	/// another thread.
+	fn try_clone(&self) -> io::Result<Self>;
+
+	fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+	fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+		Err(unsupported("timeouts"))
+	}
+
+	fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+		Err(unsupported("timeouts"))
+	}
+
+	fn read_timeout(&self) -> io::Result<Option<Duration>> {
+		Ok(None)
+	}
+
+	fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+		Err(unsupported("non-blocking mode"))
+	}
+
+	/// Reads up to and including the next newline, without consuming
+	/// anything past it since whatever follows belongs to the stream. A
+	/// partial line is kept in `buffer` across calls, so this can be retried
+	/// after `WouldBlock`.
+	///
+	/// The default reads a byte at a time.
+	fn read_line(&mut self, buffer: &mut Vec<u8>) -> io::Result<String> {
+		let mut byte = [0_u8];
+		loop {
+			if self.read(&mut byte)? == 0 {
+				return Err(io::Error::new(
+					io::ErrorKind::UnexpectedEof,
+					"SAM bridge closed the connection",
+				));
+			}
+			buffer.push(byte[0]);
+			check_line_length(buffer)?;
+			if byte[0] == b'\n' {
+				let line = String::from_utf8_lossy(buffer).into_owned();
+				buffer.clear();
+				return Ok(line);
+			}
+		}
+	}
+
+	/// The TCP socket underneath, if any. TLS and `STREAM FORWARD` need one.
+	fn as_tcp(&self) -> Option<&TcpStream> {
+		None
+	}
+}
+
+fn unsupported(what: &str) -> io::Error {
+	io::Error::other(format!("{} not supported by this transport", what))
+}
+
+impl SamTransport for TcpStream {
+	fn try_clone(&self) -> io::Result<Self> {
+		TcpStream::try_clone(self)
+	}
+	fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+		TcpStream::shutdown(self, how)
+	}
+	fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		TcpStream::set_read_timeout(self, timeout)
+	}
+	fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		TcpStream::set_write_timeout(self, timeout)
+	}
+	fn read_timeout(&self) -> io::Result<Option<Duration>> {
+		TcpStream::read_timeout(self)
+	}
+	fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+		TcpStream::set_nonblocking(self, nonblocking)
+	}
+	fn read_line(&mut self, buffer: &mut Vec<u8>) -> io::Result<String> {
+		read_line_exact(self, buffer)
+	}
+	fn as_tcp(&self) -> Option<&TcpStream> {
+		Some(self)
+	}
+}
+
+#[cfg(unix)]
+impl SamTransport for UnixStream {
+	fn try_clone(&self) -> io::Result<Self> {
+		UnixStream::try_clone(self)
+	}
+	fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+		UnixStream::shutdown(self, how)
+	}
+	fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		UnixStream::set_read_timeout(self, timeout)
+	}
+	fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		UnixStream::set_write_timeout(self, timeout)
+	}
+	fn read_timeout(&self) -> io::Result<Option<Duration>> {
+		UnixStream::read_timeout(self)
+	}
+	fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+		UnixStream::set_nonblocking(self, nonblocking)
+	}
+}
+
+/// Opens connections to a SAM bridge.
+pub trait Connector: Send + Sync + fmt::Debug + 'static {
+	type Transport: SamTransport;
+
+	/// Connects to the bridge, giving up after `timeout` if there is one.
+	fn connect(&self, timeout: Option<Duration>) -> io::Result<Self::Transport>;
+}
+
+impl Connector for SocketAddr {
+	type Transport = TcpStream;
+
+	fn connect(&self, timeout: Option<Duration>) -> io::Result<TcpStream> {
+		match timeout {
+			Some(timeout) => TcpStream::connect_timeout(self, timeout),
+			None => TcpStream::connect(self),
+		}
+	}
+}
+
+/// Connects to a SAM bridge listening on a Unix domain socket, e.g. one
+/// forwarded from another host with `ssh -L /path/to/sam.sock:localhost:7656`.
+#[cfg(unix)]
+#[derive(Clone, Debug)]
+pub struct UnixConnector {
+	path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixConnector {
+	pub fn new<P: AsRef<Path>>(path: P) -> UnixConnector {
+		UnixConnector {
+			path: path.as_ref().to_path_buf(),
+		}
+	}
+}
+
+#[cfg(unix)]
+impl Connector for UnixConnector {
+	type Transport = UnixStream;
+
+	/// Connecting to a local socket doesn't wait on the network, so the
+	/// timeout is not needed.
+	fn connect(&self, _timeout: Option<Duration>) -> io::Result<UnixStream> {
+		UnixStream::connect(&self.path)
+	}
+}
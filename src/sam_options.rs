@@ -2,9 +2,20 @@
 //! I2CP client and router options taken from https://geti2p.net/en/docs/protocol/i2cp
 //! SAMv3 options taken from https://geti2p.net/en/docs/api/samv3#options
 
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
 
 use serde_derive::{Deserialize, Serialize};
 
+use crate::error::{Error, ErrorKind};
+
+mod registry;
+
+use self::registry::OPTIONS;
+pub use self::registry::{supported_options, OptionInfo};
+
 /// options used when interacting with the SAM bridge
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SAMOptions {
@@ -12,12 +23,16 @@ pub struct SAMOptions {
 	pub to_port: Option<u16>,
 	pub i2cp_options: Option<I2CPOptions>,
 	pub signature_type: SignatureType,
+	/// options without a typed field, passed to the router as they are
+	#[serde(default)]
+	pub extra: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct I2CPOptions {
 	pub router_options: Option<I2CPRouterOptions>,
 	pub client_options: Option<I2CPClientOptions>,
+	pub streaming_options: Option<I2PStreamingOptions>,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -33,7 +48,7 @@ pub struct I2CPRouterOptions {
 	pub lease_set_auth_type: Option<LeaseSetAuthType>,
 	/// The encryption type to be used, as of 0.9.38. Interpreted client-side, but also passed to the router in the SessionConfig, to declare intent and check support. As of 0.9.39, may be comma-separated values for multiple types. See PublicKey in common strutures spec for values. See proposals 123, 144, and 145.
 	pub lease_set_enc_type: Option<LeaseSetEncType>,
-	/// The expiration of the offline signature, 4 bytes, seconds since the epoch. See proposal 123.
+	/// The expiration of the offline signature, in seconds since the epoch. See proposal 123.
 	pub lease_set_offline_expiration: Option<LeaseSetOfflineExpiration>,
 	/// The base 64 of the offline signature. See proposal 123.
 	pub lease_set_offline_signature: Option<LeaseSetOfflineSignature>,
@@ -102,7 +117,65 @@ pub struct I2CPClientOptions {
 	/// Router hostname. If the client is running in the same JVM as a router, this option is ignored, and the client connects to that router internally
 	pub tcp_host: Option<String>,
 	/// Router I2CP port. If the client is running in the same JVM as a router, this option is ignored, and the client connects to that router internally.
-	pub tcp_port: Option<u8>,
+	pub tcp_port: Option<u16>,
+}
+
+/// options of the streaming library, used by STREAM sessions, taken from
+/// https://geti2p.net/en/docs/api/streaming#options
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct I2PStreamingOptions {
+	/// Base 64 hashes of the peers in the access list or the blacklist
+	pub access_list: Option<Vec<String>>,
+	/// Whether to respond to incoming pings (default true)
+	pub answer_pings: Option<bool>,
+	/// (ms) How long to wait after instantiating a new connection before actually attempting to connect. If this is <= 0, connect immediately with no initial data. If greater than 0, wait until the output stream is flushed, the buffer fills, or that many milliseconds pass, and include any initial data with the SYN. (default -1)
+	pub connect_delay: Option<i32>,
+	/// (ms) How long to wait for the other side to accept the connection (default 5 minutes)
+	pub connect_timeout: Option<u64>,
+	/// Only allow the peers of `access_list` to connect
+	pub enable_access_list: Option<bool>,
+	/// Refuse the peers of `access_list`
+	pub enable_blacklist: Option<bool>,
+	/// Whether to listen only for the streaming protocol. Setting to true will prohibit communication with destinations earlier than 0.7.1 (default true)
+	pub enforce_protocol: Option<bool>,
+	/// What to do on an inactivity timeout (default SendPing)
+	pub inactivity_action: Option<InactivityAction>,
+	/// (ms) Idle time before sending a keepalive (default 90 seconds)
+	pub inactivity_timeout: Option<u64>,
+	/// (ms) Delay before sending an ack (default 750)
+	pub initial_ack_delay: Option<u32>,
+	/// (ms) Initial timeout before resending (default 9000)
+	pub initial_rto: Option<u32>,
+	/// (messages) Initial window size (default 6)
+	pub initial_window_size: Option<u32>,
+	/// What to do when a connection limit is exceeded (default Reset)
+	pub limit_action: Option<LimitAction>,
+	/// Maximum number of active connections, -1 for unlimited (default -1)
+	pub max_concurrent_streams: Option<i32>,
+	/// Incoming connection limit per peer per minute, 0 to disable (default 0)
+	pub max_conns_per_minute: Option<u32>,
+	/// Incoming connection limit per peer per hour, 0 to disable (default 0)
+	pub max_conns_per_hour: Option<u32>,
+	/// Incoming connection limit per peer per day, 0 to disable (default 0)
+	pub max_conns_per_day: Option<u32>,
+	/// (bytes) The maximum size of the payload, the MTU in bytes (default 1730)
+	pub max_message_size: Option<u32>,
+	/// Maximum number of retransmissions before failure (default 8)
+	pub max_resends: Option<u8>,
+	/// Total incoming connection limit per minute, 0 to disable (default 0)
+	pub max_total_conns_per_minute: Option<u32>,
+	/// Total incoming connection limit per hour, 0 to disable (default 0)
+	pub max_total_conns_per_hour: Option<u32>,
+	/// Total incoming connection limit per day, 0 to disable (default 0)
+	pub max_total_conns_per_day: Option<u32>,
+	/// (messages) Maximum window size (default 128)
+	pub max_window_size: Option<u32>,
+	/// Bulk or interactive, interactive is not supported by the router (default Bulk)
+	pub profile: Option<StreamingProfile>,
+	/// (ms) How long to block on read, -1 for forever (default -1)
+	pub read_timeout: Option<i64>,
+	/// (ms) How long to block on write or flush, -1 for forever (default -1)
+	pub write_timeout: Option<i64>,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -184,8 +257,8 @@ pub struct LeaseSetTransientPublicKey(String);
 /// Base 64 private key for signatures. Optionally preceded by the key type and ':'. DSA_SHA1 is the default. Key type must match the signature type in the destination. I2CP will generate the public key from the private key. Use for persistent leaseset keys across restarts.
 pub struct LeaseSetSigningPrivateKey(String);
 
-/// The expiration of the offline signature, 4 bytes, seconds since the epoch. See proposal 123.
-pub type LeaseSetOfflineExpiration = [u8; 4];
+/// The expiration of the offline signature, in seconds since the epoch. See proposal 123.
+pub type LeaseSetOfflineExpiration = u32;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 /// The type of leaseset to be sent in the CreateLeaseSet2 Message. Interpreted client-side, but also passed to the router in the SessionConfig, to declare intent and check support. See proposal 123.
 pub struct LeaseSetType(u8);
@@ -194,9 +267,10 @@ pub struct LeaseSetType(u8);
 pub struct LeaseSetBlindedType(u16);
 
 /// The type of authentication for encrypted LS2. 0 for no per-client authentication (the default); 1 for DH per-client authentication; 2 for PSK per-client authentication. See proposal 123.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[repr(u64)]
 pub enum LeaseSetAuthType {
+	#[default]
 	NoPerClient = 0_u64,
 	DHPerClient = 1_u64,
 	PSKPerClient = 2_u64,
@@ -211,23 +285,37 @@ pub enum SignatureType {
 	EdDsaSha512Ed25519,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 /// Guaranteed is disabled; None implemented in 0.8.1; the streaming lib default is None as of 0.8.1, the client side default is None as of 0.9.4
 pub enum MessageReliability {
 	BestEffort,
+	#[default]
 	None,
 }
 
-impl SignatureType {
-	fn string(&self) -> &str {
-		match self {
-			Self::DsaSha1 => "DSA_SHA1",
-			Self::EcdsaSha256P256 => "ECDSA_SHA256_P256",
-			Self::EcdsaSha384P384 => "ECDSA_SHA384_P384",
-			Self::EcdsaSha512P21 => "ECDSA_SHA512_P521",
-			Self::EdDsaSha512Ed25519 => "EdDSA_SHA512_Ed25519",
-		}
-	}
+/// What the streaming library does on an inactivity timeout
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum InactivityAction {
+	DoNothing,
+	Disconnect,
+	SendPing,
+}
+
+/// What the streaming library does with a connection exceeding a limit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LimitAction {
+	/// reset the connection
+	Reset,
+	/// drop the connection silently
+	Drop,
+	/// answer with an HTTP 429 response
+	Http,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StreamingProfile {
+	Bulk,
+	Interactive,
 }
 
 /// returns the default settings for a connection to the
@@ -251,12 +339,127 @@ impl Default for SAMOptions {
 					lease_set_enc_type: Some(LeaseSetEncType::default()),
 					..Default::default()
 				}),
+				streaming_options: None,
 			}),
 			signature_type: SignatureType::EdDsaSha512Ed25519,
+			extra: BTreeMap::new(),
 		}
 	}
 }
 
+/// Presets for common trade-offs between latency, throughput and anonymity,
+/// and setters overriding individual tunnel settings. Presets start from
+/// `SAMOptions::default()`.
+///
+/// # Examples
+///
+/// ```
+/// use i2p::sam_options::SAMOptions;
+///
+/// let options = SAMOptions::high_anonymity()
+///     .with_quantity(4)
+///     .with_dont_publish_lease_set(true);
+/// assert!(options.options().contains("inbound.length=3 "));
+/// ```
+impl SAMOptions {
+	/// 1 hop tunnels, few of them: fast to build and to use, but a peer can
+	/// tell which router hosts the destination
+	pub fn low_latency() -> SAMOptions {
+		SAMOptions::default()
+			.with_length(1)
+			.with_length_variance(0)
+			.with_quantity(2)
+	}
+
+	/// 2 hop tunnels, with a backup each way
+	pub fn balanced() -> SAMOptions {
+		SAMOptions::default()
+			.with_length(2)
+			.with_length_variance(0)
+			.with_quantity(3)
+			.with_backup_quantity(1)
+	}
+
+	/// 3 hop tunnels, randomly lengthened by up to a hop so that their
+	/// length does not identify the destination
+	pub fn high_anonymity() -> SAMOptions {
+		SAMOptions::default()
+			.with_length(3)
+			.with_length_variance(1)
+			.with_quantity(3)
+			.with_backup_quantity(1)
+	}
+
+	/// 2 hop tunnels, many of them, for throughput
+	pub fn bulk_transfer() -> SAMOptions {
+		let mut options = SAMOptions::default()
+			.with_length(2)
+			.with_length_variance(0)
+			.with_quantity(6)
+			.with_backup_quantity(2);
+		registry::streaming::get_mut(&mut options).profile = Some(StreamingProfile::Bulk);
+		options
+	}
+
+	/// default tunnels for a destination that only connects out, so its
+	/// leaseset is not published
+	pub fn client_only() -> SAMOptions {
+		SAMOptions::default().with_dont_publish_lease_set(true)
+	}
+
+	/// sets the length of inbound and outbound tunnels
+	pub fn with_length(self, length: u8) -> Self {
+		self.with_inbound_length(length)
+			.with_outbound_length(length)
+	}
+
+	pub fn with_inbound_length(mut self, length: u8) -> Self {
+		registry::inbound::get_mut(&mut self).length = Some(length);
+		self
+	}
+
+	pub fn with_outbound_length(mut self, length: u8) -> Self {
+		registry::outbound::get_mut(&mut self).length = Some(length);
+		self
+	}
+
+	/// sets the random amount added to the length of inbound and outbound
+	/// tunnels, see `I2CPTunnelInboundOptions::length_variance`
+	pub fn with_length_variance(mut self, variance: i8) -> Self {
+		registry::inbound::get_mut(&mut self).length_variance = Some(variance);
+		registry::outbound::get_mut(&mut self).length_variance = Some(variance);
+		self
+	}
+
+	/// sets the number of inbound and outbound tunnels
+	pub fn with_quantity(self, quantity: u8) -> Self {
+		self.with_inbound_quantity(quantity)
+			.with_outbound_quantity(quantity)
+	}
+
+	pub fn with_inbound_quantity(mut self, quantity: u8) -> Self {
+		registry::inbound::get_mut(&mut self).quantity = Some(quantity);
+		self
+	}
+
+	pub fn with_outbound_quantity(mut self, quantity: u8) -> Self {
+		registry::outbound::get_mut(&mut self).quantity = Some(quantity);
+		self
+	}
+
+	/// sets the number of inbound and outbound backup tunnels
+	pub fn with_backup_quantity(mut self, quantity: u8) -> Self {
+		registry::inbound::get_mut(&mut self).backup_quantity = Some(quantity);
+		registry::outbound::get_mut(&mut self).backup_quantity = Some(quantity);
+		self
+	}
+
+	pub fn with_dont_publish_lease_set(mut self, dont_publish: bool) -> Self {
+		registry::router::get_mut(&mut self).dont_publish_lease_set = Some(dont_publish);
+		self
+	}
+}
+
 impl SAMOptions {
 	/// parses the SAMOptions object returning
 	/// any client-side and router-side options as defined in the
@@ -264,348 +467,373 @@ impl SAMOptions {
 	pub fn options(&self) -> String {
 		self.to_string()
 	}
-}
 
-impl I2CPOptions {
-	pub fn string(&self) -> String {
-		let mut options = String::default();
-		if let Some(router_options) = &self.router_options {
-			let router_options_str = router_options.string();
-			if router_options_str != "" {
-				options.push_str(router_options_str.as_str());
-			}
+	/// sets the option named `key`, as it appears in an option string, to
+	/// `value`. Returns false if the key is not known.
+	pub(crate) fn set_option(&mut self, key: &str, value: &str) -> Result<bool, Error> {
+		if key == "SIGNATURE_TYPE" {
+			self.signature_type = parse(key, value)?;
+			return Ok(true);
+		}
+		match OPTIONS.iter().find(|option| option.key == key) {
+			Some(option) => (option.set)(self, value).ok_or_else(|| invalid(key, value))?,
+			None => return Ok(false),
+		}
+		Ok(true)
+	}
+
+	/// passes the option `key=value` to the router as it is, for options
+	/// without a typed field such as `outbound.nickname`, or ones newer than
+	/// this crate. Fails if `key` has a typed field, if either contains
+	/// whitespace, or if `key` contains `=`, which would corrupt the SAM
+	/// command. The value may contain `=`: the router splits on the first one.
+	pub fn set_extra(&mut self, key: &str, value: &str) -> Result<(), Error> {
+		check_extra(key, value)?;
+		self.extra.insert(key.to_string(), value.to_string());
+		Ok(())
+	}
+
+	/// same as `set_extra`, for chaining
+	pub fn with_extra(mut self, key: &str, value: &str) -> Result<Self, Error> {
+		self.set_extra(key, value)?;
+		Ok(self)
+	}
+
+	/// checks the values the router would reject, or that would corrupt the
+	/// SAM command, returning every problem found. Entries of `extra` are
+	/// checked as `set_extra` does, since the map can also be filled in
+	/// directly.
+	pub fn validate(&self) -> Result<(), Vec<OptionError>> {
+		let errors: Vec<_> = OPTIONS
+			.iter()
+			.filter_map(|option| (option.check)(self).map(|reason| (option.key, reason)))
+			.chain(
+				self.extra
+					.iter()
+					.filter_map(|(key, value)| Some((key.as_str(), extra_problem(key, value)?))),
+			)
+			.map(|(key, reason)| OptionError {
+				key: key.to_string(),
+				reason: reason.to_string(),
+			})
+			.collect();
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(errors)
 		}
-		if let Some(client_options) = &self.client_options {
-			let client_options_str = client_options.string();
-			if client_options_str != "" {
-				options.push_str(client_options_str.as_str());
+	}
+
+	/// `validate`, reporting the problems as a single `ErrorKind::InvalidOption`
+	pub(crate) fn check(&self) -> Result<(), Error> {
+		self.validate().map_err(|errors| {
+			let errors: Vec<_> = errors.iter().map(OptionError::to_string).collect();
+			ErrorKind::InvalidOption(errors.join(", ")).into()
+		})
+	}
+
+	/// every option set, typed ones first, as `key => value` pairs
+	fn pairs(&self) -> impl Iterator<Item = (&str, String)> {
+		let typed = OPTIONS
+			.iter()
+			.filter_map(move |option| Some((option.key, (option.get)(self)?)));
+		let extra = self
+			.extra
+			.iter()
+			.map(|(key, value)| (key.as_str(), value.clone()));
+		typed.chain(extra)
+	}
+
+	/// sets every option set in `other`, including its signature type
+	pub fn overlay(&mut self, other: &SAMOptions) {
+		for (key, value) in other.pairs() {
+			// values of `other` are valid, and typed keys stay typed
+			if let Ok(false) = self.set_option(key, &value) {
+				self.extra.insert(key.to_string(), value);
 			}
 		}
-		options
+		self.signature_type = other.signature_type.clone();
 	}
-}
 
-impl I2CPRouterOptions {
-	pub fn string(&self) -> String {
-		let mut options = String::default();
-		if let Some(client_message_timeout) = &self.client_message_timeout {
-			options.push_str(&format!("clientMessageTimeout={} ", client_message_timeout));
+	/// options with nothing set, unlike `SAMOptions::default()`
+	fn empty() -> SAMOptions {
+		SAMOptions {
+			from_port: None,
+			to_port: None,
+			i2cp_options: None,
+			signature_type: SignatureType::EdDsaSha512Ed25519,
+			extra: BTreeMap::new(),
 		}
-		if let Some(crypto_options) = &self.crypto_options {
-			let crypto_options_str = crypto_options.string();
-			if crypto_options_str != "" {
-				options.push_str(&crypto_options_str.as_str());
+	}
+
+	fn i2cp(&mut self) -> &mut I2CPOptions {
+		self.i2cp_options.get_or_insert_with(Default::default)
+	}
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, Error> {
+	value.parse().map_err(|_| invalid(key, value))
+}
+
+/// (De)serializes `SAMOptions` as a flat map of SAM keys, for config files
+/// written by operators. Use it with `#[serde(with = "i2p::sam_options::dotted")]`.
+///
+/// Values may be strings, numbers, booleans or lists of strings, which are
+/// joined with commas. Keys without a typed field are kept in `extra`, and
+/// options that are not given keep their `SAMOptions::default()` value.
+///
+/// ```toml
+/// SIGNATURE_TYPE = "EdDSA_SHA512_Ed25519"
+/// "inbound.length" = 2
+/// "i2cp.dontPublishLeaseSet" = true
+/// "i2p.streaming.accessList" = ["hash1", "hash2"]
+/// "outbound.nickname" = "service"
+/// ```
+pub mod dotted {
+	use std::collections::BTreeMap;
+	use std::fmt;
+
+	use serde::de::{self, Deserialize, Deserializer};
+	use serde::ser::{Serialize, SerializeMap, Serializer};
+	use serde_derive::Deserialize;
+
+	use super::SAMOptions;
+
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Value {
+		Bool(bool),
+		Int(i64),
+		Text(String),
+		List(Vec<String>),
+	}
+
+	impl fmt::Display for Value {
+		fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+			match self {
+				Value::Bool(b) => write!(f, "{}", b),
+				Value::Int(i) => write!(f, "{}", i),
+				Value::Text(s) => f.write_str(s),
+				Value::List(l) => f.write_str(&l.join(",")),
 			}
 		}
-		if let Some(dont_publish_lease_set) = &self.dont_publish_lease_set {
-			options.push_str(&format!(
-				"i2cp.dontPublishLeaseSet={} ",
-				dont_publish_lease_set
-			));
-		}
-		if let Some(fast_receive) = &self.fast_receive {
-			options.push_str(&format!("i2cp.fastReceive={} ", fast_receive));
-		}
-		if let Some(lease_set_auth_type) = &self.lease_set_auth_type {
-			options.push_str(&format!(
-				"i2cp.leaseSetAuthType={} ",
-				lease_set_auth_type.to_string()
-			));
-		}
-		if let Some(lease_set_enc_type) = &self.lease_set_enc_type {
-			options.push_str(&format!(
-				"i2cp.leaseSetEncType={} ",
-				lease_set_enc_type.to_string()
-			));
-		}
-		if let Some(lease_set_offline_expiration) = &self.lease_set_offline_expiration {
-			options.push_str(&format!(
-				"i2cp.leaseSetOfflineExpiration={} ",
-				String::from_utf8(lease_set_offline_expiration[..].to_vec()).unwrap()
-			))
-		}
-		if let Some(lease_set_priv_key) = &self.lease_set_priv_key {
-			options.push_str(&format!(
-				"i2cp.leaseSetPrivKey={} ",
-				lease_set_priv_key.to_string()
-			))
-		}
-		if let Some(lease_set_secret) = &self.lease_set_secret {
-			options.push_str(&format!(
-				"i2cp.leaseSetSecret={} ",
-				lease_set_secret.to_string()
-			));
-		}
-		if let Some(lease_set_transient_public_key) = &self.lease_set_transient_public_key {
-			options.push_str(&format!(
-				"i2cp.leaseSetTransientPublicKey={} ",
-				lease_set_transient_public_key.to_string()
-			));
-		}
-		if let Some(lease_set_type) = &self.lease_set_type {
-			options.push_str(&format!(
-				"i2cp.leaseSetType={} ",
-				lease_set_type.to_string()
-			));
-		}
-		if let Some(message_reliability) = &self.message_reliability {
-			options.push_str(&format!(
-				"i2cp.messageReliability={} ",
-				message_reliability.to_string()
-			));
-		}
-		if let Some(password) = &self.password {
-			options.push_str(&format!("i2cp.password={} ", password));
-		}
-		if let Some(username) = &self.username {
-			options.push_str(&format!("i2cp.username={} ", username));
-		}
-		if let Some(inbound) = &self.inbound {
-			let inbound_str = inbound.string();
-			if inbound_str != "" {
-				options.push_str(inbound_str.as_str());
+	}
+
+	/// writes numbers and booleans as such, so the output reads like the input
+	struct Written<'a>(&'a str);
+
+	impl Serialize for Written<'_> {
+		fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+			if let Ok(b) = self.0.parse::<bool>() {
+				serializer.serialize_bool(b)
+			} else if let Ok(i) = self.0.parse::<i64>() {
+				serializer.serialize_i64(i)
+			} else {
+				serializer.serialize_str(self.0)
 			}
 		}
-		if let Some(outbound) = &self.outbound {
-			let outbound_str = outbound.string();
-			if outbound_str != "" {
-				options.push_str(outbound_str.as_str());
+	}
+
+	pub fn serialize<S: Serializer>(
+		options: &SAMOptions,
+		serializer: S,
+	) -> Result<S::Ok, S::Error> {
+		let mut map = serializer.serialize_map(None)?;
+		map.serialize_entry("SIGNATURE_TYPE", &options.signature_type.to_string())?;
+		for (key, value) in options.pairs() {
+			map.serialize_entry(key, &Written(&value))?;
+		}
+		map.end()
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SAMOptions, D::Error> {
+		let map = BTreeMap::<String, Value>::deserialize(deserializer)?;
+		let mut options = SAMOptions::default();
+		for (key, value) in map {
+			let value = value.to_string();
+			let typed = options
+				.set_option(&key, &value)
+				.map_err(|e| de::Error::custom(e.kind()))?;
+			if !typed {
+				options
+					.set_extra(&key, &value)
+					.map_err(|e| de::Error::custom(e.kind()))?;
 			}
 		}
-		if let Some(should_bundle_reply_info) = &self.should_bundle_reply_info {
-			options.push_str(&format!(
-				"shouldBundleReplyInfo={} ",
-				should_bundle_reply_info
-			));
-		}
-		options
+		Ok(options)
 	}
 }
 
-impl I2CPClientOptions {
-	pub fn string(&self) -> String {
-		let mut options = String::default();
-		if let Some(close_idle_time) = &self.close_idle_time {
-			options.push_str(&format!("i2cp.closeIdleTime={} ", close_idle_time));
-		}
-		if let Some(close_on_idle) = &self.close_on_idle {
-			options.push_str(&format!("i2cp.closeOnIdle={} ", close_on_idle));
-		}
-		if let Some(encrypt_lease_set) = &self.encrypt_lease_set {
-			options.push_str(&format!("i2cp.encryptLeaseSet={} ", encrypt_lease_set));
-		}
-		if let Some(fast_receive) = &self.fast_receive {
-			options.push_str(&format!("i2cp.fastReceive={} ", fast_receive));
-		}
-		if let Some(gzip) = &self.gzip {
-			options.push_str(&format!("i2cp.gzip={} ", gzip));
-		}
-		if let Some(lease_set_auth_type) = &self.lease_set_auth_type {
-			options.push_str(&format!(
-				"i2cp.leaseSetAuthType={} ",
-				lease_set_auth_type.to_string()
-			));
-		}
-		if let Some(lease_set_blinded_type) = &self.lease_set_blinded_type {
-			options.push_str(&format!(
-				"i2cp.leaseSetBlindedType={} ",
-				lease_set_blinded_type.to_string()
-			));
-		}
-		if let Some(lease_set_enc_type) = &self.lease_set_enc_type {
-			options.push_str(&format!(
-				"i2cp.leaseSetEncType={} ",
-				lease_set_enc_type.to_string()
-			));
-		}
-		if let Some(lease_set_key) = &self.lease_set_key {
-			options.push_str(&format!("i2cp.leaseSetKey={} ", lease_set_key.to_string()));
-		}
-		if let Some(lease_set_private_key) = &self.lease_set_private_key {
-			options.push_str(&format!(
-				"i2cp.leaseSetPrivateKey={} ",
-				lease_set_private_key.to_string()
-			));
-		}
-		if let Some(lease_set_secret) = &self.lease_set_secret {
-			options.push_str(&format!(
-				"i2cp.leaseSetSecret={} ",
-				lease_set_secret.to_string()
-			));
-		}
-		if let Some(lease_set_signing_private_key) = &self.lease_set_signing_private_key {
-			options.push_str(&format!(
-				"i2cp.leaseSetSigningPrivateKey={} ",
-				lease_set_signing_private_key.to_string()
-			));
-		}
-		if let Some(message_reliability) = &self.message_reliability {
-			options.push_str(&format!(
-				"i2cp.messageReliability={} ",
-				message_reliability.to_string()
-			));
-		}
-		if let Some(reduce_idle_time) = &self.reduce_idle_time {
-			options.push_str(&format!("i2cp.reduceIdleTime={} ", reduce_idle_time));
-		}
-		if let Some(reduce_on_idle) = &self.reduce_on_idle {
-			options.push_str(&format!("i2cp.reduceOnIdle={} ", reduce_on_idle));
-		}
-		if let Some(ssl) = &self.ssl {
-			options.push_str(&format!("i2cp.ssl={} ", ssl));
-		}
-		if let Some(tcp_host) = &self.tcp_host {
-			options.push_str(&format!("i2cp.tcp.host={} ", tcp_host));
-		}
-		if let Some(tcp_port) = &self.tcp_port {
-			options.push_str(&format!("i2cp.tcp.port={} ", tcp_port));
-		}
-		options
+/// a problem with one option, as found by `SAMOptions::validate`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OptionError {
+	/// the option as named in the SAM command, e.g. `inbound.length`
+	pub key: String,
+	pub reason: String,
+}
+
+impl fmt::Display for OptionError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} {}", self.key, self.reason)
 	}
 }
 
-impl I2CPRouterCryptoOptions {
-	pub fn string(&self) -> String {
-		let mut options = String::default();
-		if let Some(low_tag_threshold) = &self.low_tag_threshold {
-			options.push_str(&format!("crypto.lowTagThreshold={} ", low_tag_threshold));
-		}
-		if let Some(inbound_tags) = &self.ratchet_inbound_tags {
-			options.push_str(&format!("crypto.ratchet.inboundTags={} ", inbound_tags));
-		}
-		if let Some(outbound_tags) = &self.ratchet_outbound_tags {
-			options.push_str(&format!("crypto.ratchet.outboundTags={} ", outbound_tags));
-		}
-		if let Some(tags_to_send) = &self.tags_to_send {
-			options.push_str(&format!("crypto.tagsToSend={} ", tags_to_send));
-		}
-		options
+const TOKEN: &str = "must not contain whitespace";
+
+/// whether the text value, if any, can be sent in a SAM command
+fn is_token(value: Option<&str>) -> bool {
+	value.is_none_or(|value| !value.contains(char::is_whitespace))
+}
+
+/// keys of the SESSION CREATE command itself
+const RESERVED_KEYS: &[&str] = &["STYLE", "ID", "DESTINATION", "SILENT"];
+
+fn extra_problem(key: &str, value: &str) -> Option<&'static str> {
+	if key.is_empty() || key.contains(|c: char| c.is_whitespace() || c == '=') {
+		return Some("is not a valid option name");
+	}
+	if !is_token(Some(value)) {
+		return Some(TOKEN);
+	}
+	// known keys are recognized by setting them on scratch options: unknown
+	// ones are the only ones reported as not set, whatever the value
+	let typed = !matches!(SAMOptions::empty().set_option(key, value), Ok(false));
+	if typed || RESERVED_KEYS.contains(&key) {
+		return Some("is not an extra option, use its typed field");
 	}
+	None
 }
 
-impl I2CPTunnelInboundOptions {
-	pub fn string(&self) -> String {
-		let mut options = String::default();
-		if let Some(allow_zero_hop) = &self.allow_zero_hop {
-			options.push_str(&format!("inbound.allowZeroHop={} ", allow_zero_hop));
-		}
-		if let Some(backup_quantity) = &self.backup_quantity {
-			options.push_str(&format!("inbound.backupQuantity={} ", backup_quantity));
-		}
-		if let Some(ip_restriction) = &self.ip_restriction {
-			options.push_str(&format!("inbound.IPRestriction={} ", ip_restriction));
-		}
-		if let Some(length) = &self.length {
-			options.push_str(&format!("inbound.length={} ", length));
-		}
-		if let Some(length_variance) = &self.length_variance {
-			options.push_str(&format!("inbound.lengthVariance{} ", length_variance));
-		}
-		if let Some(quantity) = &self.quantity {
-			options.push_str(&format!("inbound.quantity={} ", quantity));
-		}
-		if let Some(random_key) = &self.random_key {
-			options.push_str(&format!("inbound.randomKey={} ", random_key));
-		}
-		options
+fn check_extra(key: &str, value: &str) -> Result<(), Error> {
+	match extra_problem(key, value) {
+		Some(reason) => Err(ErrorKind::InvalidOption(format!("{} {}", key, reason)).into()),
+		None => Ok(()),
 	}
 }
 
-impl I2CPTunnelOutboundOptions {
-	pub fn string(&self) -> String {
-		let mut options = String::default();
-		if let Some(allow_zero_hop) = &self.allow_zero_hop {
-			options.push_str(&format!("outbound.allowZeroHop={} ", allow_zero_hop));
-		}
-		if let Some(backup_quantity) = &self.backup_quantity {
-			options.push_str(&format!("outbound.backupQuantity={} ", backup_quantity));
-		}
-		if let Some(ip_restriction) = &self.ip_restriction {
-			options.push_str(&format!("outbound.IPRestriction={} ", ip_restriction));
-		}
-		if let Some(length) = &self.length {
-			options.push_str(&format!("outbound.length={} ", length));
-		}
-		if let Some(length_variance) = &self.length_variance {
-			options.push_str(&format!("outbound.lengthVariance{} ", length_variance));
-		}
-		if let Some(priority) = &self.priority {
-			options.push_str(&format!("outbound.priority={} ", priority));
-		}
-		if let Some(quantity) = &self.quantity {
-			options.push_str(&format!("outbound.quantity={} ", quantity));
+fn invalid(key: &str, value: &str) -> Error {
+	ErrorKind::InvalidOption(format!("{}={}", key, value)).into()
+}
+
+/// parses a SAM option string such as `inbound.length=2 i2cp.leaseSetEncType=4,0`,
+/// as returned by `SAMOptions::options`. Options that are not known are kept
+/// in `extra`.
+impl FromStr for SAMOptions {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<SAMOptions, Error> {
+		let mut options = SAMOptions::empty();
+		for option in s.split_whitespace() {
+			let (key, value) = option
+				.split_once('=')
+				.ok_or_else(|| ErrorKind::InvalidOption(option.to_string()))?;
+			if !options.set_option(key, value)? {
+				options.set_extra(key, value)?;
+			}
 		}
-		if let Some(random_key) = &self.random_key {
-			options.push_str(&format!("outbound.randomKey={} ", random_key));
+		Ok(options)
+	}
+}
+
+/// builds options from `key => value` pairs, e.g. read from a properties
+/// file. Options that are not known are kept in `extra`.
+impl TryFrom<HashMap<String, String>> for SAMOptions {
+	type Error = Error;
+
+	fn try_from(map: HashMap<String, String>) -> Result<SAMOptions, Error> {
+		let mut options = SAMOptions::empty();
+		for (key, value) in map {
+			if !options.set_option(&key, &value)? {
+				options.set_extra(&key, &value)?;
+			}
 		}
-		options
+		Ok(options)
+	}
+}
+
+impl fmt::Display for LeaseSetType {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.0)
 	}
 }
 
-impl ToString for LeaseSetType {
-	fn to_string(&self) -> String {
-		format!("{}", self.0)
+impl fmt::Display for LeaseSetBlindedType {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.0)
 	}
 }
 
-impl ToString for LeaseSetBlindedType {
-	fn to_string(&self) -> String {
-		format!("{}", self.0)
+impl fmt::Display for LeaseSetAuthType {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::NoPerClient => "0",
+			Self::DHPerClient => "1",
+			Self::PSKPerClient => "2",
+		})
 	}
 }
 
-impl ToString for LeaseSetAuthType {
-	fn to_string(&self) -> String {
-		match self {
-			Self::NoPerClient => String::from("0"),
-			Self::DHPerClient => String::from("1"),
-			Self::PSKPerClient => String::from("2"),
+impl FromStr for LeaseSetAuthType {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<LeaseSetAuthType, Error> {
+		match s {
+			"0" => Ok(Self::NoPerClient),
+			"1" => Ok(Self::DHPerClient),
+			"2" => Ok(Self::PSKPerClient),
+			_ => Err(invalid("i2cp.leaseSetAuthType", s)),
 		}
 	}
 }
 
-impl ToString for LeaseSetOfflineSignature {
-	fn to_string(&self) -> String {
-		self.0.clone()
+impl fmt::Display for LeaseSetOfflineSignature {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.0)
 	}
 }
 
-impl ToString for LeaseSetEncType {
-	fn to_string(&self) -> String {
-		self.0.clone()
+impl fmt::Display for LeaseSetEncType {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.0)
 	}
 }
 
-impl ToString for LeaseSetPrivKey {
-	fn to_string(&self) -> String {
-		self.0.clone()
+impl fmt::Display for LeaseSetPrivKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.0)
 	}
 }
 
-impl ToString for LeaseSetPrivateKey {
-	fn to_string(&self) -> String {
-		self.0.clone()
+impl fmt::Display for LeaseSetPrivateKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.0)
 	}
 }
-impl ToString for LeaseSetKey {
-	fn to_string(&self) -> String {
-		self.0.clone()
+impl fmt::Display for LeaseSetKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.0)
 	}
 }
 
-impl ToString for LeaseSetSecret {
-	fn to_string(&self) -> String {
-		self.0.clone()
+impl fmt::Display for LeaseSetSecret {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.0)
+	}
+}
+impl fmt::Display for LeaseSetTransientPublicKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.0)
 	}
 }
-impl ToString for LeaseSetTransientPublicKey {
-	fn to_string(&self) -> String {
-		self.0.clone()
+impl fmt::Display for LeaseSetSigningPrivateKey {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.0)
 	}
 }
-impl ToString for LeaseSetSigningPrivateKey {
-	fn to_string(&self) -> String {
-		self.0.clone()
+
+impl LeaseSetEncType {
+	fn is_valid(&self) -> bool {
+		self.0.split(',').all(|t| t.parse::<u16>().is_ok())
 	}
 }
 
@@ -627,69 +855,472 @@ impl From<&str> for LeaseSetEncType {
 	}
 }
 
-impl Default for LeaseSetAuthType {
+impl Default for LeaseSetType {
 	fn default() -> Self {
-		Self::NoPerClient
+		LeaseSetType(1)
 	}
 }
 
-impl Default for LeaseSetType {
-	fn default() -> Self {
-		LeaseSetType(1)
+impl FromStr for MessageReliability {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<MessageReliability, Error> {
+		match s {
+			"BestEffort" => Ok(Self::BestEffort),
+			"None" => Ok(Self::None),
+			_ => Err(invalid("i2cp.messageReliability", s)),
+		}
 	}
 }
 
-impl Default for MessageReliability {
-	fn default() -> Self {
-		Self::None
+impl fmt::Display for MessageReliability {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::BestEffort => "BestEffort",
+			Self::None => "None",
+		})
 	}
 }
 
-impl ToString for MessageReliability {
-	fn to_string(&self) -> String {
-		match self {
-			Self::BestEffort => String::from("BestEffort"),
-			Self::None => String::from("None"),
+impl FromStr for InactivityAction {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<InactivityAction, Error> {
+		match s {
+			"0" => Ok(Self::DoNothing),
+			"1" => Ok(Self::Disconnect),
+			"2" => Ok(Self::SendPing),
+			_ => Err(invalid("i2p.streaming.inactivityAction", s)),
 		}
 	}
 }
 
-impl ToString for SAMOptions {
-	fn to_string(&self) -> String {
-		let mut options = String::default();
-		if let Some(from_port) = &self.from_port {
-			options.push_str(&format!("FROM_PORT={} ", from_port));
+impl fmt::Display for InactivityAction {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::DoNothing => "0",
+			Self::Disconnect => "1",
+			Self::SendPing => "2",
+		})
+	}
+}
+
+impl FromStr for LimitAction {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<LimitAction, Error> {
+		match s {
+			"reset" => Ok(Self::Reset),
+			"drop" => Ok(Self::Drop),
+			"http" => Ok(Self::Http),
+			_ => Err(invalid("i2p.streaming.limitAction", s)),
 		}
-		if let Some(to_port) = &self.to_port {
-			options.push_str(&format!("TO_PORT={} ", to_port));
+	}
+}
+
+impl fmt::Display for LimitAction {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Reset => "reset",
+			Self::Drop => "drop",
+			Self::Http => "http",
+		})
+	}
+}
+
+impl FromStr for StreamingProfile {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<StreamingProfile, Error> {
+		match s {
+			"1" => Ok(Self::Bulk),
+			"2" => Ok(Self::Interactive),
+			_ => Err(invalid("i2p.streaming.profile", s)),
 		}
-		if let Some(i2cp_options) = &self.i2cp_options {
-			let i2cp_options_str = i2cp_options.string();
-			if i2cp_options_str != "" {
-				options.push_str(i2cp_options_str.as_str());
-			}
+	}
+}
+
+impl fmt::Display for StreamingProfile {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Bulk => "1",
+			Self::Interactive => "2",
+		})
+	}
+}
+
+impl fmt::Display for SAMOptions {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (key, value) in self.pairs() {
+			write!(f, "{}={} ", key, value)?;
 		}
-		// make sure to remove duplicate options
-		// todo: there is likely a better way of handling this
-		let mut options_parts: Vec<_> = options.split(" ").collect();
-		options_parts.sort_unstable();
-		options_parts.dedup();
-		let mut options_parsed = String::default();
-		for option_part in options_parts.clone() {
-			options_parsed.push_str(&format!("{} ", option_part));
+		Ok(())
+	}
+}
+
+impl FromStr for SignatureType {
+	type Err = Error;
+
+	/// accepts the names and the numeric codes of the SAM specification
+	fn from_str(s: &str) -> Result<SignatureType, Error> {
+		match s {
+			"DSA_SHA1" | "0" => Ok(Self::DsaSha1),
+			"ECDSA_SHA256_P256" | "1" => Ok(Self::EcdsaSha256P256),
+			"ECDSA_SHA384_P384" | "2" => Ok(Self::EcdsaSha384P384),
+			"ECDSA_SHA512_P521" | "3" => Ok(Self::EcdsaSha512P21),
+			"EdDSA_SHA512_Ed25519" | "7" => Ok(Self::EdDsaSha512Ed25519),
+			_ => Err(invalid("SIGNATURE_TYPE", s)),
 		}
-		options_parsed
 	}
 }
 
-impl ToString for SignatureType {
-	fn to_string(&self) -> String {
-		match self {
-			Self::DsaSha1 => "DSA_SHA1".to_string(),
-			Self::EcdsaSha256P256 => "ECDSA_SHA256_P256".to_string(),
-			Self::EcdsaSha384P384 => "ECDSA_SHA384_P384".to_string(),
-			Self::EcdsaSha512P21 => "ECDSA_SHA512_P521".to_string(),
-			Self::EdDsaSha512Ed25519 => "EdDSA_SHA512_Ed25519".to_string(),
+impl fmt::Display for SignatureType {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::DsaSha1 => "DSA_SHA1",
+			Self::EcdsaSha256P256 => "ECDSA_SHA256_P256",
+			Self::EcdsaSha384P384 => "ECDSA_SHA384_P384",
+			Self::EcdsaSha512P21 => "ECDSA_SHA512_P521",
+			Self::EdDsaSha512Ed25519 => "EdDSA_SHA512_Ed25519",
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use std::convert::TryFrom;
+
+	use super::{supported_options, I2PStreamingOptions, LimitAction, SAMOptions, SignatureType};
+	use crate::error::ErrorKind;
+	use crate::sam::Session;
+
+	fn sorted(options: &str) -> Vec<&str> {
+		let mut parts: Vec<_> = options.split_whitespace().collect();
+		parts.sort_unstable();
+		parts
+	}
+
+	#[test]
+	fn parses_option_string() {
+		let options: SAMOptions =
+			"inbound.length=2 outbound.lengthVariance=-1 i2cp.leaseSetEncType=4,0 i2cp.gzip=false"
+				.parse()
+				.unwrap();
+		let router = options
+			.i2cp_options
+			.as_ref()
+			.and_then(|o| o.router_options.as_ref())
+			.unwrap();
+		assert_eq!(router.inbound.as_ref().unwrap().length, Some(2));
+		assert_eq!(router.outbound.as_ref().unwrap().length_variance, Some(-1));
+		assert_eq!(
+			router.lease_set_enc_type.as_ref().unwrap().to_string(),
+			"4,0"
+		);
+		assert!(options.extra.is_empty());
+	}
+
+	#[test]
+	fn round_trips_every_key() {
+		let all = "FROM_PORT=1 TO_PORT=2 clientMessageTimeout=3 crypto.lowTagThreshold=4 \
+		           crypto.ratchet.inboundTags=5 crypto.ratchet.outboundTags=6 crypto.tagsToSend=7 \
+		           i2cp.closeIdleTime=8 i2cp.closeOnIdle=true i2cp.dontPublishLeaseSet=true \
+		           i2cp.encryptLeaseSet=false i2cp.fastReceive=true i2cp.gzip=false \
+		           i2cp.leaseSetAuthType=1 i2cp.leaseSetBlindedType=11 i2cp.leaseSetEncType=4 \
+		           i2cp.leaseSetKey=key i2cp.leaseSetOfflineExpiration=1700000000 \
+		           i2cp.leaseSetOfflineSignature=sig i2cp.leaseSetPrivKey=priv \
+		           i2cp.leaseSetPrivateKey=private i2cp.leaseSetSecret=secret \
+		           i2cp.leaseSetSigningPrivateKey=signing i2cp.leaseSetTransientPublicKey=transient \
+		           i2cp.leaseSetType=3 i2cp.messageReliability=BestEffort i2cp.password=pass \
+		           i2cp.reduceIdleTime=9 i2cp.reduceOnIdle=true i2cp.reduceQuantity=1 i2cp.ssl=false \
+		           i2cp.tcp.host=localhost i2cp.tcp.port=76 i2cp.username=user \
+		           inbound.IPRestriction=2 inbound.allowZeroHop=false inbound.backupQuantity=1 \
+		           inbound.length=3 inbound.lengthVariance=1 inbound.quantity=4 inbound.randomKey=a \
+		           outbound.IPRestriction=2 outbound.allowZeroHop=false outbound.backupQuantity=1 \
+		           outbound.length=3 outbound.lengthVariance=-1 outbound.priority=5 \
+		           outbound.quantity=4 outbound.randomKey=b shouldBundleReplyInfo=false \
+		           i2p.streaming.accessList=h1,h2 i2p.streaming.answerPings=false \
+		           i2p.streaming.connectDelay=-1 i2p.streaming.connectTimeout=1000 \
+		           i2p.streaming.enableAccessList=true i2p.streaming.enableBlacklist=false \
+		           i2p.streaming.enforceProtocol=true i2p.streaming.inactivityAction=1 \
+		           i2p.streaming.inactivityTimeout=2000 i2p.streaming.initialAckDelay=750 \
+		           i2p.streaming.initialRTO=9000 i2p.streaming.initialWindowSize=6 \
+		           i2p.streaming.limitAction=http i2p.streaming.maxConcurrentStreams=-1 \
+		           i2p.streaming.maxConnsPerMinute=1 i2p.streaming.maxConnsPerHour=2 \
+		           i2p.streaming.maxConnsPerDay=3 i2p.streaming.maxMessageSize=1730 \
+		           i2p.streaming.maxResends=8 i2p.streaming.maxTotalConnsPerMinute=4 \
+		           i2p.streaming.maxTotalConnsPerHour=5 i2p.streaming.maxTotalConnsPerDay=6 \
+		           i2p.streaming.maxWindowSize=128 i2p.streaming.profile=1 \
+		           i2p.streaming.readTimeout=-1 i2p.streaming.writeTimeout=30000";
+		let options: SAMOptions = all.parse().unwrap();
+		assert!(options.extra.is_empty(), "unknown: {:?}", options.extra);
+		assert_eq!(sorted(&options.options()), sorted(all));
+	}
+
+	#[test]
+	fn serializes_streaming_options() {
+		let mut options = SAMOptions::default();
+		options.i2cp_options.as_mut().unwrap().streaming_options = Some(I2PStreamingOptions {
+			max_conns_per_minute: Some(10),
+			limit_action: Some(LimitAction::Drop),
+			access_list: Some(vec!["hash1".to_string(), "hash2".to_string()]),
+			..Default::default()
+		});
+		let string = options.options();
+		assert!(string.contains("i2p.streaming.maxConnsPerMinute=10 "));
+		assert!(string.contains("i2p.streaming.limitAction=drop "));
+		assert!(string.contains("i2p.streaming.accessList=hash1,hash2 "));
+		assert!("i2p.streaming.limitAction=block"
+			.parse::<SAMOptions>()
+			.is_err());
+	}
+
+	#[test]
+	fn keeps_unknown_keys() {
+		let options: SAMOptions = "inbound.length=1 outbound.nickname=tunnel".parse().unwrap();
+		assert_eq!(options.extra["outbound.nickname"], "tunnel");
+		assert!(options.options().contains("outbound.nickname=tunnel "));
+	}
+
+	#[test]
+	fn parses_maps() {
+		let mut map = HashMap::new();
+		map.insert(
+			"SIGNATURE_TYPE".to_string(),
+			"ECDSA_SHA256_P256".to_string(),
+		);
+		map.insert("outbound.quantity".to_string(), "6".to_string());
+		map.insert("sam.udp.host".to_string(), "127.0.0.1".to_string());
+		let options = SAMOptions::try_from(map).unwrap();
+		match options.signature_type {
+			SignatureType::EcdsaSha256P256 => {}
+			ref other => panic!("unexpected signature type {:?}", other),
+		}
+		assert_eq!(options.extra["sam.udp.host"], "127.0.0.1");
+		assert!(options.options().contains("outbound.quantity=6 "));
+	}
+
+	#[test]
+	fn extra_options() {
+		let options = SAMOptions::default()
+			.with_extra("outbound.nickname", "hello")
+			.unwrap()
+			.with_extra("i2cp.leaseSetKey.future", "a=b")
+			.unwrap();
+		let string = options.options();
+		assert!(string.contains("outbound.nickname=hello "));
+		assert!(string.contains("i2cp.leaseSetKey.future=a=b "));
+
+		let mut options = SAMOptions::default();
+		for (key, value) in &[
+			("inbound.length", "2"),
+			("i2cp.leaseSetKey", "key"),
+			("DESTINATION", "TRANSIENT"),
+			("outbound.nickname", "two words"),
+			("outbound.nickname", "line\nSESSION REMOVE"),
+			("bad key", "1"),
+			("bad=key", "1"),
+			("", "1"),
+		] {
+			assert!(options.set_extra(key, value).is_err(), "{}={}", key, value);
+		}
+		assert!(options.extra.is_empty());
+	}
+
+	#[test]
+	fn session_refuses_invalid_options() {
+		let mut options: SAMOptions = "outbound.length=9".parse().unwrap();
+		options
+			.extra
+			.insert("outbound.nickname".to_string(), "a\nb".to_string());
+		// fails before connecting to the (missing) bridge
+		let err = Session::transient("127.0.0.1:1", options).unwrap_err();
+		match err.kind() {
+			ErrorKind::InvalidOption(_) => {}
+			other => panic!("unexpected error {:?}", other),
+		}
+	}
+
+	/// every typed option with a valid value, as sent to the router
+	const GOLDEN: &[(&str, &str)] = &[
+		("FROM_PORT", "1"),
+		("TO_PORT", "2"),
+		("clientMessageTimeout", "3"),
+		("crypto.lowTagThreshold", "4"),
+		("crypto.ratchet.inboundTags", "5"),
+		("crypto.ratchet.outboundTags", "6"),
+		("crypto.tagsToSend", "7"),
+		("i2cp.closeIdleTime", "1800000"),
+		("i2cp.closeOnIdle", "true"),
+		("i2cp.dontPublishLeaseSet", "true"),
+		("i2cp.encryptLeaseSet", "false"),
+		("i2cp.fastReceive", "true"),
+		("i2cp.gzip", "false"),
+		("i2cp.leaseSetAuthType", "1"),
+		("i2cp.leaseSetBlindedType", "11"),
+		("i2cp.leaseSetEncType", "4,0"),
+		("i2cp.leaseSetKey", "key"),
+		("i2cp.leaseSetOfflineExpiration", "1700000000"),
+		("i2cp.leaseSetOfflineSignature", "sig"),
+		("i2cp.leaseSetPrivKey", "priv"),
+		("i2cp.leaseSetPrivateKey", "private"),
+		("i2cp.leaseSetSecret", "secret"),
+		("i2cp.leaseSetSigningPrivateKey", "signing"),
+		("i2cp.leaseSetTransientPublicKey", "transient"),
+		("i2cp.leaseSetType", "3"),
+		("i2cp.messageReliability", "BestEffort"),
+		("i2cp.password", "pass"),
+		("i2cp.reduceIdleTime", "1200000"),
+		("i2cp.reduceOnIdle", "true"),
+		("i2cp.reduceQuantity", "1"),
+		("i2cp.ssl", "false"),
+		("i2cp.tcp.host", "localhost"),
+		("i2cp.tcp.port", "7654"),
+		("i2cp.username", "user"),
+		("inbound.IPRestriction", "2"),
+		("inbound.allowZeroHop", "false"),
+		("inbound.backupQuantity", "1"),
+		("inbound.length", "3"),
+		("inbound.lengthVariance", "1"),
+		("inbound.quantity", "4"),
+		("inbound.randomKey", "a"),
+		("outbound.IPRestriction", "2"),
+		("outbound.allowZeroHop", "false"),
+		("outbound.backupQuantity", "1"),
+		("outbound.length", "3"),
+		("outbound.lengthVariance", "-1"),
+		("outbound.priority", "5"),
+		("outbound.quantity", "4"),
+		("outbound.randomKey", "b"),
+		("shouldBundleReplyInfo", "false"),
+		("i2p.streaming.accessList", "h1,h2"),
+		("i2p.streaming.answerPings", "false"),
+		("i2p.streaming.connectDelay", "-1"),
+		("i2p.streaming.connectTimeout", "300000"),
+		("i2p.streaming.enableAccessList", "true"),
+		("i2p.streaming.enableBlacklist", "false"),
+		("i2p.streaming.enforceProtocol", "true"),
+		("i2p.streaming.inactivityAction", "1"),
+		("i2p.streaming.inactivityTimeout", "90000"),
+		("i2p.streaming.initialAckDelay", "750"),
+		("i2p.streaming.initialRTO", "9000"),
+		("i2p.streaming.initialWindowSize", "6"),
+		("i2p.streaming.limitAction", "http"),
+		("i2p.streaming.maxConcurrentStreams", "-1"),
+		("i2p.streaming.maxConnsPerMinute", "1"),
+		("i2p.streaming.maxConnsPerHour", "2"),
+		("i2p.streaming.maxConnsPerDay", "3"),
+		("i2p.streaming.maxMessageSize", "1730"),
+		("i2p.streaming.maxResends", "8"),
+		("i2p.streaming.maxTotalConnsPerMinute", "4"),
+		("i2p.streaming.maxTotalConnsPerHour", "5"),
+		("i2p.streaming.maxTotalConnsPerDay", "6"),
+		("i2p.streaming.maxWindowSize", "128"),
+		("i2p.streaming.profile", "1"),
+		("i2p.streaming.readTimeout", "-1"),
+		("i2p.streaming.writeTimeout", "30000"),
+	];
+
+	#[test]
+	fn golden_options() {
+		let keys: Vec<_> = supported_options().iter().map(|o| o.key).collect();
+		let golden: Vec<_> = GOLDEN.iter().map(|(key, _)| *key).collect();
+		assert_eq!(keys, golden);
+		for (key, value) in GOLDEN {
+			let option = format!("{}={}", key, value);
+			let options: SAMOptions = option.parse().unwrap();
+			assert!(options.extra.is_empty(), "{} is not typed", key);
+			assert_eq!(options.options(), format!("{} ", option));
+			assert!(options.validate().is_ok(), "{} is invalid", option);
+		}
+		let all: Vec<_> = GOLDEN
+			.iter()
+			.map(|(k, v)| format!("{}={} ", k, v))
+			.collect();
+		let options: SAMOptions = all.concat().parse().unwrap();
+		assert_eq!(options.options(), all.concat());
+	}
+
+	#[test]
+	fn presets() {
+		for options in &[
+			SAMOptions::low_latency(),
+			SAMOptions::balanced(),
+			SAMOptions::high_anonymity(),
+			SAMOptions::bulk_transfer(),
+			SAMOptions::client_only(),
+		] {
+			assert!(options.validate().is_ok());
+			assert!(options.options().contains("i2cp.leaseSetEncType=4,0 "));
+		}
+		let options = SAMOptions::low_latency().options();
+		assert!(options.contains("inbound.length=1 "));
+		assert!(options.contains("outbound.quantity=2 "));
+		assert!(SAMOptions::client_only()
+			.options()
+			.contains("i2cp.dontPublishLeaseSet=true "));
+	}
+
+	#[test]
+	fn builder_overrides_presets() {
+		let options = SAMOptions::high_anonymity()
+			.with_outbound_length(2)
+			.with_inbound_quantity(5)
+			.options();
+		assert!(options.contains("inbound.length=3 "));
+		assert!(options.contains("outbound.length=2 "));
+		assert!(options.contains("inbound.quantity=5 "));
+		assert!(options.contains("outbound.quantity=3 "));
+		assert!(options.contains("outbound.lengthVariance=1 "));
+	}
+
+	#[test]
+	fn validates_fields() {
+		assert!(SAMOptions::default().validate().is_ok());
+		let mut options: SAMOptions = "inbound.length=8 inbound.lengthVariance=-9 \
+		                               outbound.length=7 outbound.quantity=0 \
+		                               i2cp.leaseSetEncType=4,x i2cp.tcp.port=0"
+			.parse()
+			.unwrap();
+		options.extra.insert("ID".to_string(), "other".to_string());
+		let mut keys: Vec<_> = options
+			.validate()
+			.unwrap_err()
+			.into_iter()
+			.map(|e| e.key)
+			.collect();
+		keys.sort_unstable();
+		assert_eq!(
+			keys,
+			[
+				"ID",
+				"i2cp.leaseSetEncType",
+				"i2cp.tcp.port",
+				"inbound.length",
+				"inbound.lengthVariance",
+				"outbound.quantity",
+			]
+		);
+	}
+
+	#[test]
+	fn rejects_invalid_values() {
+		for options in &[
+			"inbound.length=two",
+			"inbound.length",
+			"i2cp.leaseSetAuthType=3",
+			"i2cp.messageReliability=Guaranteed",
+			"i2cp.leaseSetOfflineExpiration=toolong",
+			"SIGNATURE_TYPE=RSA",
+		] {
+			match options.parse::<SAMOptions>() {
+				Err(e) => assert_eq!(
+					std::mem::discriminant(&e.kind()),
+					std::mem::discriminant(&ErrorKind::InvalidOption(String::new()))
+				),
+				Ok(_) => panic!("{} was accepted", options),
+			}
 		}
 	}
 }
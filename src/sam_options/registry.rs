@@ -0,0 +1,345 @@
+//! the typed options of `SAMOptions`, declared once with their SAM key, type
+//! and field. Parsing, serialization and validation are all derived from
+//! the table at the bottom.
+
+use super::*;
+
+/// An option with a typed field in `SAMOptions`, as listed by
+/// `supported_options`.
+pub struct OptionInfo {
+	/// the key in the SAM command, e.g. `inbound.length`
+	pub key: &'static str,
+	/// the values accepted, e.g. `u8`
+	pub kind: &'static str,
+	/// what the option does
+	pub doc: &'static str,
+	pub(super) get: fn(&SAMOptions) -> Option<String>,
+	pub(super) set: fn(&mut SAMOptions, &str) -> Option<()>,
+	pub(super) check: fn(&SAMOptions) -> Option<&'static str>,
+}
+
+/// Returns every option with a typed field, in the order they are sent.
+/// `SIGNATURE_TYPE` is not one of them: it is set with
+/// `SAMOptions::signature_type`.
+pub fn supported_options() -> &'static [OptionInfo] {
+	OPTIONS
+}
+
+/// the type of an option value, and how it is written in a SAM command
+pub(super) trait OptionValue: Sized + Clone {
+	const KIND: &'static str;
+
+	fn parse(s: &str) -> Option<Self>;
+
+	fn format(&self) -> String;
+}
+
+macro_rules! parsed_value {
+	($($ty:ty => $kind:expr),* $(,)?) => {$(
+		impl OptionValue for $ty {
+			const KIND: &'static str = $kind;
+
+			fn parse(s: &str) -> Option<Self> {
+				s.parse().ok()
+			}
+
+			fn format(&self) -> String {
+				self.to_string()
+			}
+		}
+	)*};
+}
+
+macro_rules! newtype_value {
+	($($ty:ident($inner:ty) => $kind:expr),* $(,)?) => {$(
+		impl OptionValue for $ty {
+			const KIND: &'static str = $kind;
+
+			fn parse(s: &str) -> Option<Self> {
+				Some($ty(s.parse().ok()?))
+			}
+
+			fn format(&self) -> String {
+				self.0.to_string()
+			}
+		}
+	)*};
+}
+
+parsed_value! {
+	bool => "true or false",
+	u8 => "u8",
+	u16 => "u16",
+	u32 => "u32",
+	u64 => "u64",
+	i8 => "i8",
+	i32 => "i32",
+	i64 => "i64",
+	String => "string",
+	LeaseSetAuthType => "0, 1 or 2",
+	MessageReliability => "BestEffort or None",
+	InactivityAction => "0, 1 or 2",
+	LimitAction => "reset, drop or http",
+	StreamingProfile => "1 or 2",
+}
+
+newtype_value! {
+	LeaseSetType(u8) => "1, 3, 5 or 7",
+	LeaseSetBlindedType(u16) => "u16",
+	LeaseSetEncType(String) => "comma separated encryption types",
+	LeaseSetOfflineSignature(String) => "base 64",
+	LeaseSetPrivKey(String) => "base 64",
+	LeaseSetPrivateKey(String) => "base 64",
+	LeaseSetKey(String) => "base 64",
+	LeaseSetSecret(String) => "base 64",
+	LeaseSetTransientPublicKey(String) => "base 64",
+	LeaseSetSigningPrivateKey(String) => "base 64",
+}
+
+impl OptionValue for Vec<String> {
+	const KIND: &'static str = "comma separated list";
+
+	fn parse(s: &str) -> Option<Self> {
+		Some(s.split(',').map(String::from).collect())
+	}
+
+	fn format(&self) -> String {
+		self.join(",")
+	}
+}
+
+/// the problem with a value that can't be sent in a SAM command
+fn token<T: OptionValue>(value: &T) -> Option<&'static str> {
+	if is_token(Some(&value.format())) {
+		None
+	} else {
+		Some(TOKEN)
+	}
+}
+
+/// The structs holding options, each with `get` returning it if set and
+/// `get_mut` creating it if needed.
+macro_rules! groups {
+	($($name:ident: $ty:ty = |$o:ident| $get:expr, $get_mut:expr;)*) => {$(
+		pub(super) mod $name {
+			use super::*;
+
+			pub(in crate::sam_options) fn get($o: &SAMOptions) -> Option<&$ty> {
+				$get
+			}
+
+			pub(in crate::sam_options) fn get_mut($o: &mut SAMOptions) -> &mut $ty {
+				$get_mut
+			}
+		}
+	)*};
+}
+
+groups! {
+	sam: SAMOptions = |o| Some(o), o;
+	router: I2CPRouterOptions = |o| o.i2cp_options.as_ref()?.router_options.as_ref(),
+		o.i2cp().router_options.get_or_insert_with(Default::default);
+	client: I2CPClientOptions = |o| o.i2cp_options.as_ref()?.client_options.as_ref(),
+		o.i2cp().client_options.get_or_insert_with(Default::default);
+	streaming: I2PStreamingOptions = |o| o.i2cp_options.as_ref()?.streaming_options.as_ref(),
+		o.i2cp().streaming_options.get_or_insert_with(Default::default);
+	crypto: I2CPRouterCryptoOptions = |o| router::get(o)?.crypto_options.as_ref(),
+		router::get_mut(o).crypto_options.get_or_insert_with(Default::default);
+	inbound: I2CPTunnelInboundOptions = |o| router::get(o)?.inbound.as_ref(),
+		router::get_mut(o).inbound.get_or_insert_with(Default::default);
+	outbound: I2CPTunnelOutboundOptions = |o| router::get(o)?.outbound.as_ref(),
+		router::get_mut(o).outbound.get_or_insert_with(Default::default);
+}
+
+/// Declares the options: `key: type => group.field, doc`, optionally
+/// followed by `if check => reason` to validate the value. An option stored
+/// in several groups is set in all of them, and read from the first set.
+macro_rules! registry {
+	($(
+		$key:literal: $ty:ty => $($group:ident)|+ . $field:ident, $doc:literal
+		$(if $check:expr => $reason:literal)?;
+	)*) => {
+		pub(super) static OPTIONS: &[OptionInfo] = &[$(
+			OptionInfo {
+				key: $key,
+				kind: <$ty as OptionValue>::KIND,
+				doc: $doc,
+				get: |o| {
+					$(if let Some(value) = $group::get(o).and_then(|g| g.$field.as_ref()) {
+						return Some(value.format());
+					})+
+					None
+				},
+				set: |o, s| {
+					let value = <$ty as OptionValue>::parse(s)?;
+					$($group::get_mut(o).$field = Some(value.clone());)+
+					Some(())
+				},
+				check: |o| {
+					let check = registry!(@check $ty $(, $check => $reason)?);
+					$(if let Some(value) = $group::get(o).and_then(|g| g.$field.as_ref()) {
+						if let Some(reason) = check(value).or_else(|| token(value)) {
+							return Some(reason);
+						}
+					})+
+					None
+				},
+			},
+		)*];
+	};
+	(@check $ty:ty) => {
+		|_: &$ty| None
+	};
+	(@check $ty:ty, $check:expr => $reason:literal) => {
+		|value: &$ty| if ($check)(value) { None } else { Some($reason) }
+	};
+}
+
+registry! {
+	"FROM_PORT": u16 => sam.from_port, "the port streams and datagrams are sent from";
+	"TO_PORT": u16 => sam.to_port, "the port streams and datagrams are sent to";
+	"clientMessageTimeout": u32 => router.client_message_timeout,
+		"(ms) timeout for all sent messages, unused";
+	"crypto.lowTagThreshold": u8 => crypto.low_tag_threshold,
+		"minimum number of ElGamal/AES session tags before sending more";
+	"crypto.ratchet.inboundTags": u64 => crypto.ratchet_inbound_tags,
+		"inbound tag window for ECIES-X25519-AEAD-Ratchet";
+	"crypto.ratchet.outboundTags": u64 => crypto.ratchet_outbound_tags,
+		"outbound tag window for ECIES-X25519-AEAD-Ratchet";
+	"crypto.tagsToSend": u8 => crypto.tags_to_send,
+		"number of ElGamal/AES session tags to send at a time";
+	"i2cp.closeIdleTime": u64 => client.close_idle_time, "(ms) idle time before closing";
+	"i2cp.closeOnIdle": bool => client.close_on_idle, "close the session when idle";
+	"i2cp.dontPublishLeaseSet": bool => router.dont_publish_lease_set,
+		"don't publish the leaseset, true for clients";
+	"i2cp.encryptLeaseSet": bool => client.encrypt_lease_set, "encrypt the leaseset";
+	"i2cp.fastReceive": bool => router | client.fast_receive,
+		"receive message payloads without acknowledging them";
+	"i2cp.gzip": bool => client.gzip, "gzip outbound data";
+	"i2cp.leaseSetAuthType": LeaseSetAuthType => router | client.lease_set_auth_type,
+		"per-client authentication of an encrypted LS2";
+	"i2cp.leaseSetBlindedType": LeaseSetBlindedType => client.lease_set_blinded_type,
+		"signature type of the blinded key of an encrypted LS2";
+	"i2cp.leaseSetEncType": LeaseSetEncType => router | client.lease_set_enc_type,
+		"encryption types of the leaseset"
+		if LeaseSetEncType::is_valid => "must be comma separated encryption type numbers";
+	"i2cp.leaseSetKey": LeaseSetKey => client.lease_set_key,
+		"key of an encrypted leaseset";
+	"i2cp.leaseSetOfflineExpiration": LeaseSetOfflineExpiration
+		=> router.lease_set_offline_expiration,
+		"expiration of the offline signature, seconds since the epoch";
+	"i2cp.leaseSetOfflineSignature": LeaseSetOfflineSignature
+		=> router.lease_set_offline_signature,
+		"offline signature";
+	"i2cp.leaseSetPrivKey": LeaseSetPrivKey => router.lease_set_priv_key,
+		"X25519 private key decrypting an encrypted LS2 with per-client authentication";
+	"i2cp.leaseSetPrivateKey": LeaseSetPrivateKey => client.lease_set_private_key,
+		"private keys for leaseset decryption";
+	"i2cp.leaseSetSecret": LeaseSetSecret => router | client.lease_set_secret,
+		"secret blinding the leaseset address";
+	"i2cp.leaseSetSigningPrivateKey": LeaseSetSigningPrivateKey
+		=> client.lease_set_signing_private_key,
+		"private key signing the leaseset";
+	"i2cp.leaseSetTransientPublicKey": LeaseSetTransientPublicKey
+		=> router.lease_set_transient_public_key,
+		"transient private key of an offline signature";
+	"i2cp.leaseSetType": LeaseSetType => router.lease_set_type, "type of leaseset to send"
+		if |t: &LeaseSetType| matches!(t.0, 1 | 3 | 5 | 7) => "must be 1, 3, 5 or 7";
+	"i2cp.messageReliability": MessageReliability => router | client.message_reliability,
+		"delivery guarantee of messages";
+	"i2cp.password": String => router.password, "password for I2CP authentication";
+	"i2cp.reduceIdleTime": u64 => client.reduce_idle_time,
+		"(ms) idle time before reducing tunnel quantity";
+	"i2cp.reduceOnIdle": bool => client.reduce_on_idle, "reduce tunnel quantity when idle";
+	"i2cp.reduceQuantity": u8 => client.reduce_quantity, "tunnel quantity when reduced"
+		if |q: &u8| (1..=16).contains(q) => "must be between 1 and 16";
+	"i2cp.ssl": bool => client.ssl, "connect to the router using SSL";
+	"i2cp.tcp.host": String => client.tcp_host, "router hostname";
+	"i2cp.tcp.port": u16 => client.tcp_port, "router I2CP port"
+		if |p: &u16| *p != 0 => "must not be 0";
+	"i2cp.username": String => router.username, "username for I2CP authentication";
+	"inbound.IPRestriction": u8 => inbound.ip_restriction,
+		"number of IP bytes to match to exclude peers from the same tunnel"
+		if |r: &u8| *r <= 4 => "must be between 0 and 4";
+	"inbound.allowZeroHop": bool => inbound.allow_zero_hop, "allow zero hop tunnels";
+	"inbound.backupQuantity": u8 => inbound.backup_quantity, "number of backup tunnels";
+	"inbound.length": u8 => inbound.length, "length of tunnels"
+		if |l: &u8| *l <= 7 => "must be between 0 and 7";
+	"inbound.lengthVariance": i8 => inbound.length_variance,
+		"random amount added to the length of tunnels"
+		if |v: &i8| (-7..=7).contains(v) => "must be between -7 and 7";
+	"inbound.quantity": u8 => inbound.quantity, "number of tunnels"
+		if |q: &u8| (1..=16).contains(q) => "must be between 1 and 16";
+	"inbound.randomKey": String => inbound.random_key, "key ordering peers in tunnels";
+	"outbound.IPRestriction": u8 => outbound.ip_restriction,
+		"number of IP bytes to match to exclude peers from the same tunnel"
+		if |r: &u8| *r <= 4 => "must be between 0 and 4";
+	"outbound.allowZeroHop": bool => outbound.allow_zero_hop, "allow zero hop tunnels";
+	"outbound.backupQuantity": u8 => outbound.backup_quantity, "number of backup tunnels";
+	"outbound.length": u8 => outbound.length, "length of tunnels"
+		if |l: &u8| *l <= 7 => "must be between 0 and 7";
+	"outbound.lengthVariance": i8 => outbound.length_variance,
+		"random amount added to the length of tunnels"
+		if |v: &i8| (-7..=7).contains(v) => "must be between -7 and 7";
+	"outbound.priority": i8 => outbound.priority, "priority of outbound messages"
+		if |p: &i8| (-25..=25).contains(p) => "must be between -25 and 25";
+	"outbound.quantity": u8 => outbound.quantity, "number of tunnels"
+		if |q: &u8| (1..=16).contains(q) => "must be between 1 and 16";
+	"outbound.randomKey": String => outbound.random_key, "key ordering peers in tunnels";
+	"shouldBundleReplyInfo": bool => router.should_bundle_reply_info,
+		"bundle a reply leaseset with messages";
+	"i2p.streaming.accessList": Vec<String> => streaming.access_list,
+		"base 64 hashes of the peers in the access list or blacklist"
+		if |l: &Vec<String>| l.iter().all(|h| !h.is_empty() && !h.contains(','))
+			=> "must hold base 64 hashes";
+	"i2p.streaming.answerPings": bool => streaming.answer_pings, "respond to pings";
+	"i2p.streaming.connectDelay": i32 => streaming.connect_delay,
+		"(ms) delay before connecting, to send initial data with the SYN";
+	"i2p.streaming.connectTimeout": u64 => streaming.connect_timeout,
+		"(ms) how long to wait for a connection to be accepted";
+	"i2p.streaming.enableAccessList": bool => streaming.enable_access_list,
+		"only allow the peers of the access list";
+	"i2p.streaming.enableBlacklist": bool => streaming.enable_blacklist,
+		"refuse the peers of the access list";
+	"i2p.streaming.enforceProtocol": bool => streaming.enforce_protocol,
+		"listen for the streaming protocol only";
+	"i2p.streaming.inactivityAction": InactivityAction => streaming.inactivity_action,
+		"what to do on an inactivity timeout";
+	"i2p.streaming.inactivityTimeout": u64 => streaming.inactivity_timeout,
+		"(ms) idle time before sending a keepalive";
+	"i2p.streaming.initialAckDelay": u32 => streaming.initial_ack_delay,
+		"(ms) delay before sending an ack";
+	"i2p.streaming.initialRTO": u32 => streaming.initial_rto,
+		"(ms) initial timeout before resending";
+	"i2p.streaming.initialWindowSize": u32 => streaming.initial_window_size,
+		"(messages) initial window size";
+	"i2p.streaming.limitAction": LimitAction => streaming.limit_action,
+		"what to do when a connection limit is exceeded";
+	"i2p.streaming.maxConcurrentStreams": i32 => streaming.max_concurrent_streams,
+		"maximum number of active connections, -1 for unlimited";
+	"i2p.streaming.maxConnsPerMinute": u32 => streaming.max_conns_per_minute,
+		"incoming connection limit per peer per minute";
+	"i2p.streaming.maxConnsPerHour": u32 => streaming.max_conns_per_hour,
+		"incoming connection limit per peer per hour";
+	"i2p.streaming.maxConnsPerDay": u32 => streaming.max_conns_per_day,
+		"incoming connection limit per peer per day";
+	"i2p.streaming.maxMessageSize": u32 => streaming.max_message_size,
+		"(bytes) maximum size of the payload"
+		if |s: &u32| *s != 0 => "must not be 0";
+	"i2p.streaming.maxResends": u8 => streaming.max_resends,
+		"maximum number of retransmissions";
+	"i2p.streaming.maxTotalConnsPerMinute": u32 => streaming.max_total_conns_per_minute,
+		"total incoming connection limit per minute";
+	"i2p.streaming.maxTotalConnsPerHour": u32 => streaming.max_total_conns_per_hour,
+		"total incoming connection limit per hour";
+	"i2p.streaming.maxTotalConnsPerDay": u32 => streaming.max_total_conns_per_day,
+		"total incoming connection limit per day";
+	"i2p.streaming.maxWindowSize": u32 => streaming.max_window_size,
+		"(messages) maximum window size"
+		if |s: &u32| *s != 0 => "must not be 0";
+	"i2p.streaming.profile": StreamingProfile => streaming.profile, "bulk or interactive";
+	"i2p.streaming.readTimeout": i64 => streaming.read_timeout,
+		"(ms) how long to block on read, -1 for forever";
+	"i2p.streaming.writeTimeout": i64 => streaming.write_timeout,
+		"(ms) how long to block on write or flush, -1 for forever";
+}
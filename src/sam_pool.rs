@@ -0,0 +1,588 @@
+//! a pool of pre-handshaked SAM control connections
+//!
+//! Every outbound stream needs its own control connection to the SAM bridge,
+//! which costs a TCP connect and a HELLO round-trip before `STREAM CONNECT`
+//! can even be sent. [SamConnectionPool] keeps a number of connections in the
+//! handshaked state, so that clients opening many short-lived streams only pay
+//! for the stream setup itself.
+//!
+//! A connection handed out for `STREAM CONNECT` or `STREAM ACCEPT` becomes
+//! the data socket of that stream and never returns to the pool; it keeps
+//! counting against `max_open` until the stream is dropped.
+
+use std::collections::VecDeque;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::error::{Error, ErrorKind};
+use crate::net::I2pSocketAddr;
+use crate::sam::{Credentials, SamConnection, Session, StreamConnect};
+
+/// limits applied by a [SamConnectionPool]
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+	/// Number of handshaked connections the pool tries to keep ready
+	pub min_idle: usize,
+	/// Maximum number of handshaked connections kept ready; extra connections
+	/// handed back to the pool are closed
+	pub max_idle: usize,
+	/// Maximum number of connections open at once, counting idle ones and
+	/// the ones backing live streams. `None` means unlimited
+	pub max_open: Option<usize>,
+	/// Idle connections older than this are closed by the reaper
+	pub idle_timeout: Duration,
+	/// How long `get` waits for a connection when `max_open` is reached.
+	/// `None` waits forever
+	pub checkout_timeout: Option<Duration>,
+	/// How often the background thread reaps expired connections and tops
+	/// the pool back up to `min_idle`. `None` disables the thread, in which
+	/// case reaping only happens on checkout
+	pub maintenance_interval: Option<Duration>,
+}
+
+impl Default for PoolOptions {
+	fn default() -> Self {
+		PoolOptions {
+			min_idle: 2,
+			max_idle: 16,
+			max_open: None,
+			idle_timeout: Duration::from_secs(300),
+			checkout_timeout: None,
+			maintenance_interval: Some(Duration::from_secs(10)),
+		}
+	}
+}
+
+/// point-in-time counters of a [SamConnectionPool]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PoolMetrics {
+	/// Connections currently waiting in the pool
+	pub idle: usize,
+	/// Connections currently handed out, either checked out or backing a stream
+	pub in_use: usize,
+	/// Connections opened and handshaked since the pool was created
+	pub created: usize,
+	/// Checkouts served from an idle connection
+	pub hits: usize,
+	/// Checkouts that had to open a new connection
+	pub misses: usize,
+	/// Checkouts that had to wait for `max_open`
+	pub waits: usize,
+	/// Checkouts that gave up after `checkout_timeout`
+	pub timeouts: usize,
+	/// Idle connections closed because they expired or exceeded `max_idle`
+	pub reaped: usize,
+	/// Connection attempts that failed in connect or HELLO
+	pub failed: usize,
+}
+
+/// A pool of handshaked SAM control connections to a single SAM bridge.
+///
+/// The pool is cheap to clone; all clones share the same connections.
+///
+/// # Examples
+///
+/// ```no_run
+/// use i2p::sam_options::SAMOptions;
+/// use i2p::sam_pool::{PoolOptions, SamConnectionPool};
+/// use i2p::Session;
+///
+/// let session = Session::transient("127.0.0.1:7656", SAMOptions::default()).unwrap();
+/// let pool = SamConnectionPool::for_session(&session, PoolOptions::default()).unwrap();
+/// let stream = pool.connect(&session, "example.i2p", 80).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct SamConnectionPool {
+	inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+	sam_addr: SocketAddr,
+	credentials: Option<Credentials>,
+	options: PoolOptions,
+	state: Mutex<PoolState>,
+	released: Condvar,
+	counters: Counters,
+}
+
+#[derive(Default)]
+struct PoolState {
+	idle: VecDeque<IdleConnection>,
+	/// handed out connections plus connections being opened
+	in_use: usize,
+}
+
+struct IdleConnection {
+	conn: SamConnection,
+	since: Instant,
+}
+
+#[derive(Default)]
+struct Counters {
+	created: AtomicUsize,
+	hits: AtomicUsize,
+	misses: AtomicUsize,
+	waits: AtomicUsize,
+	timeouts: AtomicUsize,
+	reaped: AtomicUsize,
+	failed: AtomicUsize,
+}
+
+/// A control connection checked out of a [SamConnectionPool].
+///
+/// Dereferences to [SamConnection]. Dropping it hands the connection back
+/// to the pool, so it must only be used for commands that leave the
+/// connection in its handshaked state, such as `NAMING LOOKUP`. Use
+/// [PooledConnection::detach] to keep the connection instead.
+pub struct PooledConnection {
+	conn: Option<SamConnection>,
+	lease: Option<PoolLease>,
+}
+
+/// Keeps a connection counted as in use until dropped.
+pub(crate) struct PoolLease {
+	pool: Weak<PoolInner>,
+}
+
+impl SamConnectionPool {
+	/// Create a pool of connections to the SAM bridge at `sam_addr`, and
+	/// handshake `options.min_idle` connections up front.
+	pub fn new<A: ToSocketAddrs>(sam_addr: A, options: PoolOptions) -> Result<Self, Error> {
+		Self::create(sam_addr, None, options)
+	}
+
+	/// Same as `new`, but every connection authenticates to the bridge with
+	/// `credentials`.
+	pub fn with_credentials<A: ToSocketAddrs>(
+		sam_addr: A,
+		credentials: Credentials,
+		options: PoolOptions,
+	) -> Result<Self, Error> {
+		Self::create(sam_addr, Some(credentials), options)
+	}
+
+	fn create<A: ToSocketAddrs>(
+		sam_addr: A,
+		credentials: Option<Credentials>,
+		options: PoolOptions,
+	) -> Result<Self, Error> {
+		let sam_addr = sam_addr
+			.to_socket_addrs()?
+			.next()
+			.ok_or_else(|| Error::from(ErrorKind::UnresolvableAddress))?;
+		let pool = SamConnectionPool {
+			inner: Arc::new(PoolInner {
+				sam_addr,
+				credentials,
+				options,
+				state: Mutex::new(PoolState::default()),
+				released: Condvar::new(),
+				counters: Counters::default(),
+			}),
+		};
+		pool.fill()?;
+		if let Some(interval) = pool.inner.options.maintenance_interval {
+			let weak = Arc::downgrade(&pool.inner);
+			thread::spawn(move || maintain(weak, interval));
+		}
+		Ok(pool)
+	}
+
+	/// Create a pool of connections to the SAM bridge `session` lives on,
+	/// authenticating with the credentials of the session.
+	pub fn for_session(session: &Session, options: PoolOptions) -> Result<Self, Error> {
+		Self::create(session.sam_api()?, session.credentials().cloned(), options)
+	}
+
+	/// The SAM bridge this pool connects to.
+	pub fn sam_api(&self) -> SocketAddr {
+		self.inner.sam_addr
+	}
+
+	/// Check out a handshaked connection, opening a new one if none is idle.
+	///
+	/// Blocks while `max_open` connections are in use, up to
+	/// `checkout_timeout`, after which `ErrorKind::PoolExhausted` is returned.
+	pub fn get(&self) -> Result<PooledConnection, Error> {
+		let inner = &self.inner;
+		let mut state = inner.lock();
+		inner.reap_expired(&mut state);
+		let deadline = inner.options.checkout_timeout.map(|t| Instant::now() + t);
+		let mut waited = false;
+		loop {
+			if let Some(idle) = state.idle.pop_front() {
+				state.in_use += 1;
+				inner.counters.hits.fetch_add(1, Ordering::Relaxed);
+				return Ok(PooledConnection {
+					conn: Some(idle.conn),
+					lease: Some(self.lease()),
+				});
+			}
+			if inner.has_room(&state) {
+				// reserve the slot, then connect without holding the lock
+				state.in_use += 1;
+				drop(state);
+				inner.counters.misses.fetch_add(1, Ordering::Relaxed);
+				let conn = inner.open()?;
+				return Ok(PooledConnection {
+					conn: Some(conn),
+					lease: Some(self.lease()),
+				});
+			}
+			if !waited {
+				waited = true;
+				inner.counters.waits.fetch_add(1, Ordering::Relaxed);
+			}
+			state = match deadline {
+				None => inner
+					.released
+					.wait(state)
+					.unwrap_or_else(|e| e.into_inner()),
+				Some(deadline) => {
+					let now = Instant::now();
+					if now >= deadline {
+						inner.counters.timeouts.fetch_add(1, Ordering::Relaxed);
+						return Err(ErrorKind::PoolExhausted(format!(
+							"no SAM connection available after {:?}",
+							inner.options.checkout_timeout.unwrap_or_default()
+						))
+						.into());
+					}
+					inner
+						.released
+						.wait_timeout(state, deadline - now)
+						.unwrap_or_else(|e| e.into_inner())
+						.0
+				}
+			};
+		}
+	}
+
+	/// Open a stream to `dest` on `session` using a pooled connection.
+	///
+	/// `dest` may be a hostname, a b32 address or a full base64 destination;
+	/// only the former two cost a `NAMING LOOKUP`.
+	pub fn connect(
+		&self,
+		session: &Session,
+		dest: &str,
+		port: u16,
+	) -> Result<StreamConnect, Error> {
+		let (mut conn, lease) = self.get()?.detach_with_lease();
+		conn.lease = lease;
+		StreamConnect::connect_on(conn, session, dest, port, None)
+	}
+
+	/// Accept an incoming stream on `session` using a pooled connection.
+	pub fn accept(&self, session: &Session) -> Result<(StreamConnect, I2pSocketAddr), Error> {
+		let (mut conn, lease) = self.get()?.detach_with_lease();
+		conn.lease = lease;
+		StreamConnect::accept_on(conn, session, None)
+	}
+
+	/// Close idle connections that outlived `idle_timeout`, returning how
+	/// many were closed.
+	pub fn reap_idle(&self) -> usize {
+		let mut state = self.inner.lock();
+		self.inner.reap_expired(&mut state)
+	}
+
+	/// Open connections until `min_idle` are ready or `max_open` is reached.
+	pub fn fill(&self) -> Result<(), Error> {
+		self.inner.fill()
+	}
+
+	/// Snapshot of the pool counters.
+	pub fn metrics(&self) -> PoolMetrics {
+		let (idle, in_use) = {
+			let state = self.inner.lock();
+			(state.idle.len(), state.in_use)
+		};
+		let c = &self.inner.counters;
+		PoolMetrics {
+			idle,
+			in_use,
+			created: c.created.load(Ordering::Relaxed),
+			hits: c.hits.load(Ordering::Relaxed),
+			misses: c.misses.load(Ordering::Relaxed),
+			waits: c.waits.load(Ordering::Relaxed),
+			timeouts: c.timeouts.load(Ordering::Relaxed),
+			reaped: c.reaped.load(Ordering::Relaxed),
+			failed: c.failed.load(Ordering::Relaxed),
+		}
+	}
+
+	fn lease(&self) -> PoolLease {
+		PoolLease {
+			pool: Arc::downgrade(&self.inner),
+		}
+	}
+}
+
+impl PoolInner {
+	fn lock(&self) -> MutexGuard<'_, PoolState> {
+		self.state.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	fn has_room(&self, state: &PoolState) -> bool {
+		match self.options.max_open {
+			Some(max) => state.idle.len() + state.in_use < max,
+			None => true,
+		}
+	}
+
+	/// Connect and handshake. The caller must already have reserved a slot
+	/// in `in_use`, which is released again if the connection fails.
+	fn open(&self) -> Result<SamConnection, Error> {
+		let conn = match self.credentials {
+			Some(ref credentials) => {
+				SamConnection::connect_with_credentials(self.sam_addr, credentials.clone())
+			}
+			None => SamConnection::connect(self.sam_addr),
+		};
+		match conn {
+			Ok(conn) => {
+				self.counters.created.fetch_add(1, Ordering::Relaxed);
+				Ok(conn)
+			}
+			Err(e) => {
+				warn!("failed to open pooled SAM connection: {}", e.kind());
+				self.counters.failed.fetch_add(1, Ordering::Relaxed);
+				self.release();
+				Err(e)
+			}
+		}
+	}
+
+	fn fill(&self) -> Result<(), Error> {
+		loop {
+			{
+				let mut state = self.lock();
+				if state.idle.len() >= self.options.min_idle || !self.has_room(&state) {
+					return Ok(());
+				}
+				state.in_use += 1;
+			}
+			let conn = self.open()?;
+			let mut state = self.lock();
+			state.in_use -= 1;
+			state.idle.push_back(IdleConnection {
+				conn,
+				since: Instant::now(),
+			});
+			self.released.notify_one();
+		}
+	}
+
+	fn reap_expired(&self, state: &mut PoolState) -> usize {
+		let timeout = self.options.idle_timeout;
+		let before = state.idle.len();
+		state.idle.retain(|c| c.since.elapsed() < timeout);
+		let reaped = before - state.idle.len();
+		if reaped > 0 {
+			debug!("reaped {} idle SAM connections", reaped);
+			self.counters.reaped.fetch_add(reaped, Ordering::Relaxed);
+			self.released.notify_all();
+		}
+		reaped
+	}
+
+	/// Hand a connection back, closing it if the pool is already full.
+	fn put(&self, conn: SamConnection) {
+		let mut state = self.lock();
+		state.in_use -= 1;
+		if state.idle.len() < self.options.max_idle {
+			state.idle.push_back(IdleConnection {
+				conn,
+				since: Instant::now(),
+			});
+		} else {
+			self.counters.reaped.fetch_add(1, Ordering::Relaxed);
+		}
+		self.released.notify_one();
+	}
+
+	fn release(&self) {
+		let mut state = self.lock();
+		state.in_use -= 1;
+		self.released.notify_one();
+	}
+}
+
+fn maintain(pool: Weak<PoolInner>, interval: Duration) {
+	loop {
+		thread::sleep(interval);
+		let inner = match pool.upgrade() {
+			Some(inner) => inner,
+			None => return,
+		};
+		{
+			let mut state = inner.lock();
+			inner.reap_expired(&mut state);
+		}
+		if let Err(e) = inner.fill() {
+			debug!("failed to top up SAM connection pool: {}", e.kind());
+		}
+	}
+}
+
+impl PooledConnection {
+	/// Take the connection out of the pool for good, e.g. to turn it into a
+	/// stream. It no longer counts against `max_open`.
+	pub fn detach(self) -> SamConnection {
+		self.detach_with_lease().0
+	}
+
+	pub(crate) fn detach_with_lease(mut self) -> (SamConnection, Option<PoolLease>) {
+		let conn = self
+			.conn
+			.take()
+			.expect("connection is only taken on drop or detach");
+		(conn, self.lease.take())
+	}
+}
+
+impl Deref for PooledConnection {
+	type Target = SamConnection;
+	fn deref(&self) -> &SamConnection {
+		self.conn
+			.as_ref()
+			.expect("connection is only taken on drop or detach")
+	}
+}
+
+impl DerefMut for PooledConnection {
+	fn deref_mut(&mut self) -> &mut SamConnection {
+		self.conn
+			.as_mut()
+			.expect("connection is only taken on drop or detach")
+	}
+}
+
+impl Drop for PooledConnection {
+	fn drop(&mut self) {
+		if let (Some(conn), Some(mut lease)) = (self.conn.take(), self.lease.take()) {
+			// `put` releases the slot, so disarm the lease first
+			let pool = std::mem::replace(&mut lease.pool, Weak::new());
+			if let Some(pool) = pool.upgrade() {
+				pool.put(conn);
+			}
+		}
+	}
+}
+
+impl Drop for PoolLease {
+	fn drop(&mut self) {
+		if let Some(pool) = self.pool.upgrade() {
+			pool.release();
+		}
+	}
+}
+
+impl std::fmt::Debug for PoolLease {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("PoolLease").finish()
+	}
+}
+
+impl std::fmt::Debug for SamConnectionPool {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("SamConnectionPool")
+			.field("sam_addr", &self.inner.sam_addr)
+			.field("metrics", &self.metrics())
+			.finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use super::{PoolOptions, SamConnectionPool};
+	use crate::error::ErrorKind;
+	use crate::test::{default_reply, mock_sam};
+
+	fn options() -> PoolOptions {
+		PoolOptions {
+			min_idle: 0,
+			maintenance_interval: None,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn reuses_returned_connections() {
+		let pool = SamConnectionPool::new(mock_sam(default_reply), options()).unwrap();
+		{
+			let mut conn = pool.get().unwrap();
+			assert_eq!(conn.naming_lookup("ME").unwrap(), "dest");
+		}
+		let _conn = pool.get().unwrap();
+		let metrics = pool.metrics();
+		assert_eq!(metrics.created, 1);
+		assert_eq!((metrics.hits, metrics.misses), (1, 1));
+		assert_eq!((metrics.idle, metrics.in_use), (0, 1));
+	}
+
+	#[test]
+	fn prefills_min_idle() {
+		let opts = PoolOptions {
+			min_idle: 3,
+			..options()
+		};
+		let pool = SamConnectionPool::new(mock_sam(default_reply), opts).unwrap();
+		assert_eq!(pool.metrics().idle, 3);
+	}
+
+	#[test]
+	fn max_open_times_out() {
+		let opts = PoolOptions {
+			max_open: Some(1),
+			checkout_timeout: Some(Duration::from_millis(50)),
+			..options()
+		};
+		let pool = SamConnectionPool::new(mock_sam(default_reply), opts).unwrap();
+		let held = pool.get().unwrap();
+		match pool.get() {
+			Err(e) => match e.kind() {
+				ErrorKind::PoolExhausted(_) => {}
+				kind => panic!("unexpected error {:?}", kind),
+			},
+			Ok(_) => panic!("checkout beyond max_open succeeded"),
+		}
+		assert_eq!(pool.metrics().timeouts, 1);
+		drop(held);
+	}
+
+	#[test]
+	fn reaps_expired_connections() {
+		let opts = PoolOptions {
+			min_idle: 2,
+			idle_timeout: Duration::from_millis(0),
+			..options()
+		};
+		let pool = SamConnectionPool::new(mock_sam(default_reply), opts).unwrap();
+		assert_eq!(pool.reap_idle(), 2);
+		assert_eq!(pool.metrics().idle, 0);
+	}
+
+	#[test]
+	fn failed_handshake_releases_slot() {
+		let sam = mock_sam(|_: &str| Some("HELLO REPLY RESULT=NOVERSION\n".to_string()));
+		let opts = PoolOptions {
+			max_open: Some(1),
+			..options()
+		};
+		let pool = SamConnectionPool::new(sam, opts).unwrap();
+		assert!(pool.get().is_err());
+		assert!(pool.get().is_err());
+		let metrics = pool.metrics();
+		assert_eq!((metrics.failed, metrics.in_use), (2, 0));
+	}
+}
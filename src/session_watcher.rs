@@ -54,6 +54,8 @@ impl SamSessionWatcher {
     pub fn accept(self: &mut Box<Self>) -> Result<(StreamConnect, I2pSocketAddr), Error> {
         match self.listener.forward.accept() {
             Ok(res) => Ok(res),
+            // the listener was shut down on purpose, don't bring it back
+            Err(err) if err.kind() == ErrorKind::ListenerClosed => Err(err),
             Err(err) => {
                 error!("accept encountered error, recreating stream: {:#?}", err);
                 {
@@ -0,0 +1,151 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Certificate of a test CA, which issued `TLS_CERT` for `sam.test` and
+/// 127.0.0.1.
+#[cfg(feature = "tls")]
+pub const TLS_CA: &[u8] = include_bytes!("../tests/data/ca.der");
+#[cfg(feature = "tls")]
+pub const TLS_CERT: &[u8] = include_bytes!("../tests/data/sam.der");
+#[cfg(feature = "tls")]
+const TLS_KEY: &[u8] = include_bytes!("../tests/data/sam.key.der");
+
+/// A minimal in-process SAM bridge. Every accepted connection is served on
+/// its own thread by `reply`, which maps each command line to the reply to
+/// send back. Returning `None` closes the connection.
+pub fn mock_sam<F>(reply: F) -> SocketAddr
+where
+	F: Fn(&str) -> Option<String> + Send + Sync + Clone + 'static,
+{
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	thread::spawn(move || {
+		for conn in listener.incoming() {
+			let conn = match conn {
+				Ok(conn) => conn,
+				Err(_) => return,
+			};
+			let reply = reply.clone();
+			thread::spawn(move || serve(conn, reply));
+		}
+	});
+	addr
+}
+
+/// Same as `mock_sam`, but the bridge listens on a Unix domain socket.
+#[cfg(unix)]
+pub fn mock_sam_unix<F>(reply: F) -> PathBuf
+where
+	F: Fn(&str) -> Option<String> + Send + Sync + Clone + 'static,
+{
+	static NEXT: AtomicUsize = AtomicUsize::new(0);
+	let path = std::env::temp_dir().join(format!(
+		"i2p-rs-sam-{}-{}.sock",
+		std::process::id(),
+		NEXT.fetch_add(1, Ordering::Relaxed)
+	));
+	let _ = std::fs::remove_file(&path);
+	let listener = UnixListener::bind(&path).unwrap();
+	thread::spawn(move || {
+		for conn in listener.incoming() {
+			let conn = match conn {
+				Ok(conn) => conn,
+				Err(_) => return,
+			};
+			let reply = reply.clone();
+			thread::spawn(move || serve(conn, reply));
+		}
+	});
+	path
+}
+
+/// Same as `mock_sam`, but the bridge speaks TLS with `TLS_CERT`.
+#[cfg(feature = "tls")]
+pub fn mock_sam_tls<F>(reply: F) -> SocketAddr
+where
+	F: Fn(&str) -> Option<String> + Send + Sync + Clone + 'static,
+{
+	use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+	use rustls::{ServerConfig, ServerConnection, StreamOwned};
+	use std::sync::Arc;
+
+	let config =
+		ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+			.with_safe_default_protocol_versions()
+			.unwrap()
+			.with_no_client_auth()
+			.with_single_cert(
+				vec![CertificateDer::from(TLS_CERT.to_vec())],
+				PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(TLS_KEY.to_vec())),
+			)
+			.unwrap();
+	let config = Arc::new(config);
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	thread::spawn(move || {
+		for conn in listener.incoming() {
+			let conn = match conn {
+				Ok(conn) => conn,
+				Err(_) => return,
+			};
+			let tls = ServerConnection::new(config.clone()).unwrap();
+			let reply = reply.clone();
+			thread::spawn(move || serve(StreamOwned::new(tls, conn), reply));
+		}
+	});
+	addr
+}
+
+fn serve<S, F>(mut conn: S, reply: F)
+where
+	S: Read + Write,
+	F: Fn(&str) -> Option<String>,
+{
+	loop {
+		// a byte at a time, so nothing the client sends after a command is
+		// swallowed by a buffer
+		let mut line = Vec::new();
+		let mut byte = [0_u8];
+		while byte[0] != b'\n' {
+			match conn.read(&mut byte) {
+				Ok(0) | Err(_) => return,
+				Ok(_) => line.push(byte[0]),
+			}
+		}
+		let line = String::from_utf8_lossy(&line);
+		match reply(&line) {
+			Some(answer) => {
+				if conn
+					.write_all(answer.as_bytes())
+					.and_then(|_| conn.flush())
+					.is_err()
+				{
+					return;
+				}
+			}
+			None => return,
+		}
+	}
+}
+
+/// Answers every command with success, the way a well-behaved router does.
+pub fn default_reply(line: &str) -> Option<String> {
+	if line.starts_with("HELLO") {
+		Some("HELLO REPLY RESULT=OK VERSION=3.1\n".to_string())
+	} else if line.starts_with("NAMING LOOKUP") {
+		Some("NAMING REPLY RESULT=OK NAME=ME VALUE=dest\n".to_string())
+	} else if line.starts_with("SESSION CREATE") {
+		Some("SESSION STATUS RESULT=OK DESTINATION=privkey\n".to_string())
+	} else if line.starts_with("STREAM") {
+		Some("STREAM STATUS RESULT=OK\n".to_string())
+	} else {
+		None
+	}
+}
@@ -0,0 +1,511 @@
+//! SAM bridge connections over TLS
+//!
+//! A SAM bridge on another host, e.g. Java I2P with `sam.useSSL=true` or
+//! i2pd behind stunnel, can be reached over TLS so that credentials and
+//! private keys don't cross the network in the clear. Every connection made
+//! for a session, including the data connections of its streams, then goes
+//! through TLS.
+//!
+//! Two things don't:
+//!
+//! - connections the router opens back to us with `STREAM FORWARD`, so
+//!   forwarding on a TLS session fails unless it asks for SSL forwarding
+//! - the raw sockets from `AsRawFd`, `AsFd` and `IntoRawFd`, which carry
+//!   ciphertext; they can be polled, but data has to go through `Read` and
+//!   `Write`
+//!
+//! Routers usually have a self-signed certificate, so the simplest setup is
+//! to pin its fingerprint:
+//!
+//! ```no_run
+//! use i2p::sam::SessionBuilder;
+//! use i2p::tls::TlsConfig;
+//!
+//! let tls = TlsConfig::new("router.lan")
+//!     .with_pin("18:23:1D:DA:04:D3:F8:47:55:46:DE:FC:13:F1:F8:FA:D6:72:F6:03:1C:06:B6:5D:B9:56:F8:DF:5E:7A:D7:85")
+//!     .unwrap();
+//! let session = SessionBuilder::default()
+//!     .with_addrs("router.lan:7656")
+//!     .unwrap()
+//!     .with_tls(tls)
+//!     .build()
+//!     .unwrap();
+//! ```
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+	CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+	SignatureScheme, StreamOwned,
+};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, ErrorKind};
+use crate::sam::check_line_length;
+
+/// How to authenticate the SAM bridge when connecting over TLS.
+///
+/// The bridge is accepted if its certificate chains up to one of the root
+/// certificates and is valid for the server name, and, if any pins are
+/// set, its SHA-256 fingerprint is one of them. With pins only, the chain
+/// and name aren't checked.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+	server_name: String,
+	roots: Vec<CertificateDer<'static>>,
+	pins: Vec<[u8; 32]>,
+}
+
+impl TlsConfig {
+	/// `server_name` is the DNS name or IP address the certificate of the
+	/// bridge is issued for; it is also sent as SNI.
+	pub fn new(server_name: &str) -> TlsConfig {
+		TlsConfig {
+			server_name: server_name.to_string(),
+			roots: vec![],
+			pins: vec![],
+		}
+	}
+
+	/// Trust certificates issued by the DER-encoded certificate `der`.
+	pub fn with_root_certificate(mut self, der: &[u8]) -> Self {
+		self.roots.push(CertificateDer::from(der.to_vec()));
+		self
+	}
+
+	/// Only accept a bridge whose certificate has the SHA-256 fingerprint
+	/// `sha256`. Can be called several times, e.g. while rotating keys.
+	pub fn with_pinned_certificate(mut self, sha256: [u8; 32]) -> Self {
+		self.pins.push(sha256);
+		self
+	}
+
+	/// Same as `with_pinned_certificate`, with the fingerprint in hex as
+	/// printed by `openssl x509 -fingerprint -sha256`, colons optional.
+	pub fn with_pin(self, fingerprint: &str) -> Result<Self, Error> {
+		let hex: String = fingerprint.chars().filter(|&c| c != ':').collect();
+		let invalid =
+			|| ErrorKind::InvalidOption(format!("{} is not a SHA-256 fingerprint", fingerprint));
+		if hex.len() != 64 || !hex.is_ascii() {
+			return Err(invalid().into());
+		}
+		let mut sha256 = [0; 32];
+		for (i, byte) in sha256.iter_mut().enumerate() {
+			*byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+		}
+		Ok(self.with_pinned_certificate(sha256))
+	}
+
+	/// The SHA-256 fingerprint of the DER-encoded certificate `der`, as
+	/// pinned by `with_pinned_certificate`.
+	pub fn fingerprint(der: &[u8]) -> [u8; 32] {
+		let mut sha256 = [0; 32];
+		sha256.copy_from_slice(&Sha256::digest(der));
+		sha256
+	}
+
+	fn client_config(&self) -> Result<ClientConfig, Error> {
+		let provider = Arc::new(ring::default_provider());
+		let chain = if self.roots.is_empty() {
+			if self.pins.is_empty() {
+				return Err(ErrorKind::Tls(
+					"a root or pinned certificate is needed to trust the SAM bridge".to_string(),
+				)
+				.into());
+			}
+			None
+		} else {
+			let mut roots = RootCertStore::empty();
+			for root in &self.roots {
+				roots.add(root.clone()).map_err(tls_error)?;
+			}
+			let verifier =
+				WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+					.build()
+					.map_err(|e| ErrorKind::Tls(e.to_string()))?;
+			Some(verifier)
+		};
+		let verifier = Verifier {
+			chain,
+			pins: self.pins.clone(),
+			provider: provider.clone(),
+		};
+		Ok(ClientConfig::builder_with_provider(provider)
+			.with_safe_default_protocol_versions()
+			.map_err(tls_error)?
+			.dangerous()
+			.with_custom_certificate_verifier(Arc::new(verifier))
+			.with_no_client_auth())
+	}
+
+	/// Runs the TLS handshake on `sock`, honoring its timeouts.
+	pub(crate) fn connect(&self, sock: TcpStream) -> Result<TlsStream, Error> {
+		let name = ServerName::try_from(self.server_name.clone())
+			.map_err(|e| ErrorKind::Tls(format!("{}: {}", self.server_name, e)))?;
+		let conn =
+			ClientConnection::new(Arc::new(self.client_config()?), name).map_err(tls_error)?;
+		let mut stream = StreamOwned::new(conn, sock);
+		while stream.conn.is_handshaking() {
+			stream.conn.complete_io(&mut stream.sock).map_err(|e| {
+				match e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+					Some(e) => tls_error(e.clone()),
+					None => Error::from(e),
+				}
+			})?;
+		}
+		Ok(TlsStream {
+			inner: Arc::new(TlsInner {
+				conn: Mutex::new(stream.conn),
+				sock: stream.sock,
+				reading: Mutex::new(()),
+				writing: Mutex::new(Vec::new()),
+			}),
+		})
+	}
+}
+
+fn tls_error(e: rustls::Error) -> Error {
+	ErrorKind::Tls(e.to_string()).into()
+}
+
+#[derive(Debug)]
+struct Verifier {
+	chain: Option<Arc<WebPkiServerVerifier>>,
+	pins: Vec<[u8; 32]>,
+	provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for Verifier {
+	fn verify_server_cert(
+		&self,
+		end_entity: &CertificateDer<'_>,
+		intermediates: &[CertificateDer<'_>],
+		server_name: &ServerName<'_>,
+		ocsp_response: &[u8],
+		now: UnixTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		if let Some(ref chain) = self.chain {
+			chain.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+		}
+		if !self.pins.is_empty() && !self.pins.contains(&TlsConfig::fingerprint(end_entity)) {
+			return Err(rustls::Error::InvalidCertificate(
+				CertificateError::ApplicationVerificationFailure,
+			));
+		}
+		Ok(ServerCertVerified::assertion())
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls12_signature(
+			message,
+			cert,
+			dss,
+			&self.provider.signature_verification_algorithms,
+		)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls13_signature(
+			message,
+			cert,
+			dss,
+			&self.provider.signature_verification_algorithms,
+		)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.provider
+			.signature_verification_algorithms
+			.supported_schemes()
+	}
+}
+
+/// The TLS session of a SAM connection, shared by its duplicates.
+///
+/// The rustls state is only locked to encrypt or decrypt, never while
+/// waiting on the socket, so a duplicate can write while another one is
+/// blocked reading, as `tunnel::pump` does.
+#[derive(Clone)]
+pub(crate) struct TlsStream {
+	inner: Arc<TlsInner>,
+}
+
+struct TlsInner {
+	conn: Mutex<ClientConnection>,
+	sock: TcpStream,
+	/// held while reading the socket, so records reach `conn` in order
+	reading: Mutex<()>,
+	/// held while writing the socket, so records leave in the order they
+	/// were encrypted; holds what the socket didn't take yet
+	writing: Mutex<Vec<u8>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl TlsStream {
+	/// Reads up to and including the next newline. Like `read_line_exact`,
+	/// a partial line is kept in `buffer` across `WouldBlock`; nothing past
+	/// the newline is consumed.
+	pub(crate) fn read_line(&self, buffer: &mut Vec<u8>) -> io::Result<String> {
+		let mut byte = [0_u8];
+		loop {
+			// decrypted data is buffered by rustls, so this is cheap
+			if self.read(&mut byte)? == 0 {
+				return Err(io::Error::new(
+					io::ErrorKind::UnexpectedEof,
+					"SAM bridge closed the connection",
+				));
+			}
+			buffer.push(byte[0]);
+			check_line_length(buffer)?;
+			if byte[0] == b'\n' {
+				let line = String::from_utf8_lossy(buffer).into_owned();
+				buffer.clear();
+				return Ok(line);
+			}
+		}
+	}
+
+	pub(crate) fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+		let _reading = lock(&self.inner.reading);
+		loop {
+			match lock(&self.inner.conn).reader().read(buf) {
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+				// zero once the bridge sent close_notify
+				result => return result,
+			}
+			let mut ciphertext = [0_u8; 4096];
+			let n = (&self.inner.sock).read(&mut ciphertext)?;
+			let mut conn = lock(&self.inner.conn);
+			// an empty read tells rustls the socket was closed
+			let mut received = &ciphertext[..n];
+			loop {
+				conn.read_tls(&mut received)?;
+				conn.process_new_packets()
+					.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+				if received.is_empty() {
+					break;
+				}
+			}
+		}
+	}
+
+	/// Encrypts `buf` and sends it. If the socket would block, the rest is
+	/// kept and sent before anything else.
+	pub(crate) fn write(&self, buf: &[u8]) -> io::Result<usize> {
+		let mut unsent = lock(&self.inner.writing);
+		self.send(&mut unsent)?;
+		let n = {
+			let mut conn = lock(&self.inner.conn);
+			let n = conn.writer().write(buf)?;
+			// also picks up anything rustls queued while reading, e.g. the
+			// answer to a key update
+			while conn.wants_write() {
+				conn.write_tls(&mut *unsent)?;
+			}
+			n
+		};
+		match self.send(&mut unsent) {
+			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(n),
+			result => result.map(|_| n),
+		}
+	}
+
+	pub(crate) fn flush(&self) -> io::Result<()> {
+		let mut unsent = lock(&self.inner.writing);
+		{
+			let mut conn = lock(&self.inner.conn);
+			while conn.wants_write() {
+				conn.write_tls(&mut *unsent)?;
+			}
+		}
+		self.send(&mut unsent)?;
+		(&self.inner.sock).flush()
+	}
+
+	fn send(&self, unsent: &mut Vec<u8>) -> io::Result<()> {
+		while !unsent.is_empty() {
+			match (&self.inner.sock).write(unsent) {
+				Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+				Ok(n) => {
+					unsent.drain(..n);
+				}
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(())
+	}
+}
+
+impl std::fmt::Debug for TlsStream {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("TlsStream").finish_non_exhaustive()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::TlsConfig;
+	use crate::error::ErrorKind;
+	use crate::sam::{SamConnection, SessionBuilder, StreamConnect, StreamForward};
+	use crate::test::{default_reply, mock_sam, mock_sam_tls, TLS_CA, TLS_CERT};
+	use std::io::{Read, Write};
+	use std::sync::mpsc;
+	use std::thread;
+	use std::time::Duration;
+
+	#[test]
+	fn parses_pins() {
+		let fingerprint = TlsConfig::fingerprint(TLS_CERT);
+		let hex: Vec<String> = fingerprint.iter().map(|b| format!("{:02X}", b)).collect();
+		let config = TlsConfig::new("sam.test").with_pin(&hex.join(":")).unwrap();
+		assert_eq!(config.pins, vec![fingerprint]);
+		let config = TlsConfig::new("sam.test")
+			.with_pin(&hex.concat().to_lowercase())
+			.unwrap();
+		assert_eq!(config.pins, vec![fingerprint]);
+		for pin in &["", "18:23", &"zz".repeat(32)] {
+			assert!(TlsConfig::new("sam.test").with_pin(pin).is_err(), "{}", pin);
+		}
+	}
+
+	#[test]
+	fn pinned_and_chained_bridges_are_trusted() {
+		let sam = mock_sam_tls(default_reply);
+		for config in [
+			TlsConfig::new("sam.test").with_pinned_certificate(TlsConfig::fingerprint(TLS_CERT)),
+			TlsConfig::new("sam.test").with_root_certificate(TLS_CA),
+			TlsConfig::new("127.0.0.1")
+				.with_root_certificate(TLS_CA)
+				.with_pinned_certificate(TlsConfig::fingerprint(TLS_CERT)),
+		] {
+			let mut conn = SamConnection::connect_tls(sam, config, None).unwrap();
+			assert_eq!(conn.naming_lookup("ME").unwrap(), "dest");
+		}
+	}
+
+	#[test]
+	fn untrusted_bridges_are_refused() {
+		let sam = mock_sam_tls(default_reply);
+		for config in [
+			TlsConfig::new("sam.test"),
+			TlsConfig::new("sam.test").with_pinned_certificate([0; 32]),
+			TlsConfig::new("other.test").with_root_certificate(TLS_CA),
+			TlsConfig::new("sam.test").with_root_certificate(TLS_CERT),
+			TlsConfig::new("sam.test")
+				.with_root_certificate(TLS_CA)
+				.with_pinned_certificate([0; 32]),
+		] {
+			match SamConnection::connect_tls(sam, config.clone(), None)
+				.unwrap_err()
+				.kind()
+			{
+				ErrorKind::Tls(_) => {}
+				kind => panic!("unexpected error {:?} for {:?}", kind, config),
+			}
+		}
+		// a plaintext bridge doesn't speak TLS
+		let plain = mock_sam(default_reply);
+		let config = TlsConfig::new("sam.test").with_root_certificate(TLS_CA);
+		assert!(SamConnection::connect_tls(plain, config, None).is_err());
+	}
+
+	#[test]
+	fn streams_of_the_session_use_tls() {
+		let sam = mock_sam_tls(|line: &str| {
+			if line.starts_with("STREAM CONNECT") {
+				Some("STREAM STATUS RESULT=OK\npong\n".to_string())
+			} else {
+				default_reply(line)
+			}
+		});
+		let session = SessionBuilder::default()
+			.with_addr(sam)
+			.with_tls(TlsConfig::new("sam.test").with_root_certificate(TLS_CA))
+			.build()
+			.unwrap();
+		let mut stream = StreamConnect::with_session(&session, "example.i2p", 0).unwrap();
+		let mut pong = [0; 5];
+		stream.read_exact(&mut pong).unwrap();
+		assert_eq!(&pong, b"pong\n");
+		stream.write_all(b"ping").unwrap();
+	}
+
+	#[test]
+	fn plain_forwarding_is_refused() {
+		let sam = mock_sam_tls(default_reply);
+		let session = SessionBuilder::default()
+			.with_addr(sam)
+			.with_tls(TlsConfig::new("sam.test").with_root_certificate(TLS_CA))
+			.build()
+			.unwrap();
+		let forward = StreamForward::with_session(&session).unwrap();
+		match forward.forward_to("127.0.0.1", 7656, false, false) {
+			Err(e) => assert!(matches!(e.kind(), ErrorKind::Tls(_)), "{}", e),
+			Ok(_) => panic!("forwarded in the clear"),
+		}
+	}
+
+	#[test]
+	fn duplicates_read_and_write_at_once() {
+		let sam = mock_sam_tls(|line: &str| {
+			if line == "ping\n" {
+				Some("pong\n".to_string())
+			} else {
+				default_reply(line)
+			}
+		});
+		let session = SessionBuilder::default()
+			.with_addr(sam)
+			.with_tls(TlsConfig::new("sam.test").with_root_certificate(TLS_CA))
+			.build()
+			.unwrap();
+		let mut writer = StreamConnect::with_session(&session, "example.i2p", 0).unwrap();
+		let mut reader = writer.duplicate().unwrap();
+		let (done, result) = mpsc::channel();
+		let read_done = done.clone();
+		thread::spawn(move || {
+			let mut pong = [0; 5];
+			let read = reader.read_exact(&mut pong).map(|_| pong.to_vec());
+			read_done.send(read).unwrap();
+		});
+		// let the reader block on the socket first
+		thread::sleep(Duration::from_millis(100));
+		thread::spawn(move || {
+			let written = writer.write_all(b"ping\n").and_then(|_| writer.flush());
+			done.send(written.map(|_| vec![])).unwrap();
+			// keep the stream open until the reader is done
+			thread::sleep(Duration::from_secs(5));
+		});
+		let mut results = vec![];
+		for _ in 0..2 {
+			let res = result.recv_timeout(Duration::from_secs(5));
+			results.push(
+				res.expect("reads and writes of duplicates block each other")
+					.unwrap(),
+			);
+		}
+		results.sort();
+		assert_eq!(results, vec![vec![], b"pong\n".to_vec()]);
+	}
+}
@@ -0,0 +1,280 @@
+//! a client tunnel forwarding a local TCP port to an I2P destination
+
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use crate::error::{Error, ErrorKind};
+use crate::net::{I2pSocketAddr, I2pStream, ToI2pSocketAddrs};
+use crate::sam::Session;
+use crate::sam_options::SAMOptions;
+
+use super::Sessions;
+
+/// Forwards every connection accepted on a local TCP port to a fixed I2P
+/// destination, so that unmodified clients (IRC, git, ...) can reach it.
+///
+/// By default all connections share one session, created when the first
+/// client connects. It can be closed once the tunnel has been idle for a
+/// while with `with_idle_timeout`, and is created again on demand.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use i2p::sam::DEFAULT_API;
+/// use i2p::sam_options::SAMOptions;
+/// use i2p::tunnel::ClientTunnel;
+///
+/// let tunnel = ClientTunnel::bind(
+///     "127.0.0.1:6668",
+///     DEFAULT_API,
+///     "irc.postman.i2p:6667",
+///     SAMOptions::default(),
+/// )
+/// .unwrap()
+/// .with_idle_timeout(Duration::from_secs(600));
+/// tunnel.run().unwrap();
+/// ```
+pub struct ClientTunnel {
+	listener: TcpListener,
+	inner: Arc<Inner>,
+}
+
+struct Inner {
+	sessions: Sessions,
+	dest: I2pSocketAddr,
+	per_connection: bool,
+	idle_timeout: Option<Duration>,
+	activity: Mutex<Activity>,
+}
+
+struct Activity {
+	/// clients currently forwarded
+	active: usize,
+	/// when the last client was done
+	last: Instant,
+}
+
+impl ClientTunnel {
+	/// Listen on `local_addr` and forward connections to `dest`, e.g.
+	/// `"example.i2p:80"`, through the SAM bridge at `sam_addr`. Sessions
+	/// are created with `options`.
+	pub fn bind<A: ToSocketAddrs, B: ToSocketAddrs, C: ToI2pSocketAddrs>(
+		local_addr: A,
+		sam_addr: B,
+		dest: C,
+		options: SAMOptions,
+	) -> Result<ClientTunnel, Error> {
+		let sam_addr = sam_addr
+			.to_socket_addrs()?
+			.next()
+			.ok_or_else(|| Error::from(ErrorKind::UnresolvableAddress))?;
+		let dest = dest
+			.to_socket_addrs()?
+			.next()
+			.ok_or_else(|| Error::from(ErrorKind::UnresolvableAddress))?;
+		Ok(ClientTunnel {
+			listener: TcpListener::bind(local_addr)?,
+			inner: Arc::new(Inner {
+				sessions: Sessions::new(sam_addr, options),
+				dest,
+				per_connection: false,
+				idle_timeout: None,
+				activity: Mutex::new(Activity {
+					active: 0,
+					last: Instant::now(),
+				}),
+			}),
+		})
+	}
+
+	/// Open all streams on `session` instead of creating one. The session
+	/// is never closed by the tunnel.
+	pub fn with_session(mut self, session: Session) -> Self {
+		self.inner_mut().sessions.fixed = Some(Arc::new(session));
+		self
+	}
+
+	/// Create a new session, and so a new I2P destination, for every
+	/// connection instead of sharing one.
+	pub fn with_session_per_connection(mut self, per_connection: bool) -> Self {
+		self.inner_mut().per_connection = per_connection;
+		self
+	}
+
+	/// Close the shared session after no client has been connected for
+	/// `timeout`.
+	pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+		self.inner_mut().idle_timeout = Some(timeout);
+		self
+	}
+
+	/// Forward to `port` on the destination instead of the port it was
+	/// given with.
+	pub fn with_port(mut self, port: u16) -> Self {
+		self.inner_mut().dest.set_port(port);
+		self
+	}
+
+	fn inner_mut(&mut self) -> &mut Inner {
+		Arc::get_mut(&mut self.inner).expect("tunnel is configured before it runs")
+	}
+
+	/// Returns the local address clients connect to.
+	pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+		self.listener.local_addr().map_err(|e| e.into())
+	}
+
+	/// Forward clients forever, each on its own thread.
+	pub fn run(&self) -> Result<(), Error> {
+		info!(
+			"client tunnel on {} forwarding to {}",
+			self.local_addr()?,
+			self.inner.dest
+		);
+		if let Some(timeout) = self.inner.idle_timeout {
+			let inner = Arc::downgrade(&self.inner);
+			thread::spawn(move || close_when_idle(inner, timeout));
+		}
+		for client in self.listener.incoming() {
+			let client = match client {
+				Ok(client) => client,
+				Err(e) => {
+					warn!("failed to accept client: {}", e);
+					continue;
+				}
+			};
+			let inner = self.inner.clone();
+			inner.activity().active += 1;
+			thread::spawn(move || {
+				if let Err(e) = inner.forward(client) {
+					debug!("client tunnel connection failed: {}", e.kind());
+				}
+				let mut activity = inner.activity();
+				activity.active -= 1;
+				activity.last = Instant::now();
+			});
+		}
+		Ok(())
+	}
+}
+
+impl Inner {
+	fn activity(&self) -> MutexGuard<'_, Activity> {
+		self.activity.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	fn forward(&self, client: TcpStream) -> Result<(), Error> {
+		let stream = if self.per_connection {
+			let session = self.sessions.create()?;
+			I2pStream::connect_with_session(&session, &self.dest)?
+		} else {
+			let session = self.sessions.get("")?;
+			I2pStream::connect_with_session(&session, &self.dest).inspect_err(|e| {
+				if let ErrorKind::Io(_) = e.kind() {
+					self.sessions.evict("", &session);
+				}
+			})?
+		};
+		super::pump(client, stream)?;
+		Ok(())
+	}
+}
+
+/// Closes the shared session of the tunnel whenever it has had no client
+/// for `timeout`, until the tunnel is dropped.
+fn close_when_idle(inner: Weak<Inner>, timeout: Duration) {
+	let interval = (timeout / 4).clamp(Duration::from_millis(10), Duration::from_secs(10));
+	loop {
+		thread::sleep(interval);
+		let inner = match inner.upgrade() {
+			Some(inner) => inner,
+			None => return,
+		};
+		let activity = inner.activity();
+		if activity.active == 0
+			&& activity.last.elapsed() >= timeout
+			&& inner.sessions.close_all() > 0
+		{
+			info!("closed idle session of tunnel to {}", inner.dest);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::{Read, Write};
+	use std::net::{SocketAddr, TcpStream};
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::sync::Arc;
+	use std::thread;
+	use std::time::Duration;
+
+	use super::ClientTunnel;
+	use crate::sam_options::SAMOptions;
+	use crate::test::{default_reply, mock_sam};
+
+	/// Starts a tunnel to a mock bridge that closes every stream on the
+	/// first byte, returning the local address and the number of sessions
+	/// created so far.
+	fn tunnel<F>(configure: F) -> (SocketAddr, Arc<AtomicUsize>)
+	where
+		F: FnOnce(ClientTunnel) -> ClientTunnel,
+	{
+		let sessions = Arc::new(AtomicUsize::new(0));
+		let created = sessions.clone();
+		let sam = mock_sam(move |line| {
+			if line.starts_with("SESSION CREATE") {
+				created.fetch_add(1, Ordering::SeqCst);
+			}
+			default_reply(line)
+		});
+		let tunnel =
+			ClientTunnel::bind("127.0.0.1:0", sam, "example.i2p:80", SAMOptions::default())
+				.unwrap();
+		let tunnel = configure(tunnel);
+		let addr = tunnel.local_addr().unwrap();
+		thread::spawn(move || tunnel.run());
+		(addr, sessions)
+	}
+
+	fn round_trip(addr: SocketAddr) {
+		let mut client = TcpStream::connect(addr).unwrap();
+		client.write_all(b"ping\n").unwrap();
+		let mut rest = Vec::new();
+		let _ = client.read_to_end(&mut rest);
+	}
+
+	#[test]
+	fn shared_session_is_lazy() {
+		let (addr, sessions) = tunnel(|t| t);
+		thread::sleep(Duration::from_millis(50));
+		assert_eq!(sessions.load(Ordering::SeqCst), 0);
+
+		round_trip(addr);
+		round_trip(addr);
+		assert_eq!(sessions.load(Ordering::SeqCst), 1);
+	}
+
+	#[test]
+	fn session_per_connection() {
+		let (addr, sessions) = tunnel(|t| t.with_session_per_connection(true));
+		round_trip(addr);
+		round_trip(addr);
+		assert_eq!(sessions.load(Ordering::SeqCst), 2);
+	}
+
+	#[test]
+	fn idle_session_is_recreated() {
+		let (addr, sessions) = tunnel(|t| t.with_idle_timeout(Duration::from_millis(50)));
+		round_trip(addr);
+		thread::sleep(Duration::from_millis(200));
+		round_trip(addr);
+		assert_eq!(sessions.load(Ordering::SeqCst), 2);
+	}
+}
@@ -0,0 +1,455 @@
+//! tunnels described by an i2pd `tunnels.conf` file
+//!
+//! The file is INI: one section per tunnel, named after it, with a `type`
+//! and type-specific keys. The `client`, `server`, `http` (an HTTP server
+//! tunnel), `socks` and `httpproxy` types are supported, e.g.
+//!
+//! ```ini
+//! [irc]
+//! type = client
+//! address = 127.0.0.1
+//! port = 6668
+//! destination = irc.postman.i2p
+//! destinationport = 6667
+//! keys = irc-keys.dat
+//!
+//! [eepsite]
+//! type = http
+//! host = 127.0.0.1
+//! port = 8080
+//! keys = eepsite-keys.dat
+//! inbound.length = 3
+//! outbound.length = 3
+//! i2cp.leaseSetEncType = 4,0
+//! ```
+//!
+//! Key files use i2pd's binary format, so they can be moved between i2pd
+//! and tunnels started here in either direction. A key file that does not
+//! exist yet is created with a new destination, and `keys = transient`
+//! (or no `keys`) uses a transient one.
+
+use std::env;
+use std::fs;
+use std::io::ErrorKind as IoErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+
+use log::{info, warn};
+
+use crate::error::{Error, ErrorKind};
+use crate::net::{b64_decode, b64_encode};
+use crate::sam::{SamConnection, Session};
+use crate::sam_options::{SAMOptions, SignatureType};
+
+use super::{ClientTunnel, HttpProxy, ServerTunnel, SocksProxy};
+
+/// The kinds of tunnel that can be configured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelType {
+	/// a local port forwarded to an I2P destination, see `ClientTunnel`
+	Client,
+	/// an I2P destination forwarded to a local service, see `ServerTunnel`
+	Server,
+	/// a server tunnel adding the client destination to HTTP requests
+	Http,
+	/// a SOCKS proxy, see `SocksProxy`
+	Socks,
+	/// an HTTP proxy, see `HttpProxy`
+	HttpProxy,
+}
+
+/// A single tunnel, i.e. a section of `tunnels.conf`
+#[derive(Debug, Clone)]
+pub struct TunnelConfig {
+	/// the section name
+	pub name: String,
+	pub tunnel_type: TunnelType,
+	/// local address to listen on (client and proxies) or forward to
+	/// (servers), from the `address` and `host` keys respectively
+	pub host: String,
+	/// local port to listen on or forward to
+	pub port: u16,
+	/// I2P destination of client tunnels
+	pub destination: Option<String>,
+	/// port on the I2P destination of client tunnels
+	pub destination_port: u16,
+	/// key file, `None` for a transient destination
+	pub keys: Option<PathBuf>,
+	pub options: SAMOptions,
+}
+
+/// All tunnels of a `tunnels.conf` file
+#[derive(Debug, Clone, Default)]
+pub struct TunnelsConfig {
+	pub tunnels: Vec<TunnelConfig>,
+}
+
+impl TunnelsConfig {
+	/// Reads a `tunnels.conf` file. As with i2pd, relative key file paths
+	/// are taken to be relative to its default data directory, see
+	/// `default_data_dir`, rather than to the directory of the file.
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<TunnelsConfig, Error> {
+		let path = path.as_ref();
+		let data_dir = default_data_dir()
+			.or_else(|| path.parent().map(Path::to_path_buf))
+			.unwrap_or_default();
+		TunnelsConfig::load_with_data_dir(path, data_dir)
+	}
+
+	/// Same as `load`, with relative key file paths taken to be relative to
+	/// `data_dir`, like i2pd's `--datadir`.
+	pub fn load_with_data_dir<P: AsRef<Path>, D: AsRef<Path>>(
+		path: P,
+		data_dir: D,
+	) -> Result<TunnelsConfig, Error> {
+		let mut config: TunnelsConfig = fs::read_to_string(path)?.parse()?;
+		for tunnel in &mut config.tunnels {
+			if let Some(keys) = &mut tunnel.keys {
+				if keys.is_relative() {
+					*keys = data_dir.as_ref().join(&*keys);
+				}
+			}
+		}
+		Ok(config)
+	}
+
+	/// Starts every tunnel through the SAM bridge at `sam_addr`, each on a
+	/// thread named after it. The threads only return if their tunnel fails.
+	pub fn start<A: ToSocketAddrs>(
+		&self,
+		sam_addr: A,
+	) -> Result<Vec<JoinHandle<Result<(), Error>>>, Error> {
+		let sam_addr = sam_addr
+			.to_socket_addrs()?
+			.next()
+			.ok_or_else(|| Error::from(ErrorKind::UnresolvableAddress))?;
+		self.tunnels
+			.iter()
+			.map(|tunnel| tunnel.start(sam_addr))
+			.collect()
+	}
+}
+
+impl FromStr for TunnelsConfig {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<TunnelsConfig, Error> {
+		let mut sections: Vec<(String, Vec<(String, String)>)> = Vec::new();
+		for (number, line) in s.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+				continue;
+			}
+			if line.starts_with('[') && line.ends_with(']') {
+				sections.push((line[1..line.len() - 1].trim().to_string(), Vec::new()));
+				continue;
+			}
+			let invalid = || invalid(format!("line {}: {}", number + 1, line));
+			let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+			let section = sections.last_mut().ok_or_else(invalid)?;
+			section
+				.1
+				.push((key.trim().to_string(), value.trim().to_string()));
+		}
+		let tunnels = sections
+			.into_iter()
+			.map(|(name, keys)| TunnelConfig::from_keys(name, &keys))
+			.collect::<Result<_, _>>()?;
+		Ok(TunnelsConfig { tunnels })
+	}
+}
+
+impl TunnelConfig {
+	fn from_keys(name: String, keys: &[(String, String)]) -> Result<TunnelConfig, Error> {
+		let get = |key: &str| {
+			keys.iter()
+				.rev()
+				.find(|(k, _)| k == key)
+				.map(|(_, v)| v.as_str())
+		};
+		let tunnel_type = match get("type") {
+			Some("client") => TunnelType::Client,
+			Some("server") => TunnelType::Server,
+			Some("http") => TunnelType::Http,
+			Some("socks") => TunnelType::Socks,
+			Some("httpproxy") => TunnelType::HttpProxy,
+			Some(other) => {
+				return Err(invalid(format!(
+					"[{}]: unsupported tunnel type {}",
+					name, other
+				)))
+			}
+			None => return Err(invalid(format!("[{}]: missing type", name))),
+		};
+		let port = match get("port") {
+			Some(port) => port
+				.parse()
+				.map_err(|_| invalid(format!("[{}]: invalid port {}", name, port)))?,
+			None => return Err(invalid(format!("[{}]: missing port", name))),
+		};
+		let destination_port = match get("destinationport") {
+			Some(port) => port
+				.parse()
+				.map_err(|_| invalid(format!("[{}]: invalid destinationport {}", name, port)))?,
+			None => 0,
+		};
+		let host = match tunnel_type {
+			TunnelType::Server | TunnelType::Http => get("host"),
+			_ => get("address"),
+		};
+		let destination = get("destination").map(str::to_string);
+		if tunnel_type == TunnelType::Client && destination.is_none() {
+			return Err(invalid(format!("[{}]: missing destination", name)));
+		}
+		let keys_file = get("keys")
+			.filter(|keys| !keys.starts_with("transient"))
+			.map(PathBuf::from);
+
+		let mut options = SAMOptions::default();
+		for (key, value) in keys {
+			match key.as_str() {
+				"type" | "port" | "destinationport" | "host" | "address" | "destination"
+				| "keys" => {}
+				"signaturetype" => options.signature_type = signature_type(&name, value)?,
+				_ => {
+					if !options.set_option(key, value)? {
+						warn!("[{}]: ignoring unsupported key {}", name, key);
+					}
+				}
+			}
+		}
+
+		Ok(TunnelConfig {
+			name,
+			tunnel_type,
+			host: host.unwrap_or("127.0.0.1").to_string(),
+			port,
+			destination,
+			destination_port,
+			keys: keys_file,
+			options,
+		})
+	}
+
+	/// Starts the tunnel through the SAM bridge at `sam_addr`, on a thread
+	/// named after it.
+	pub fn start(&self, sam_addr: SocketAddr) -> Result<JoinHandle<Result<(), Error>>, Error> {
+		let session = match &self.keys {
+			Some(keys) => Some(self.session(sam_addr, keys)?),
+			None => None,
+		};
+		let local = (self.host.as_str(), self.port);
+		let options = self.options.clone();
+		let run: Box<dyn FnOnce() -> Result<(), Error> + Send> = match self.tunnel_type {
+			TunnelType::Client => {
+				let destination = self.destination.clone().unwrap_or_default();
+				let dest = (destination.as_str(), self.destination_port);
+				let mut tunnel = ClientTunnel::bind(local, sam_addr, dest, options)?;
+				if let Some(session) = session {
+					tunnel = tunnel.with_session(session);
+				}
+				Box::new(move || tunnel.run())
+			}
+			TunnelType::Server | TunnelType::Http => {
+				let session = match session {
+					Some(session) => session,
+					None => Session::transient(sam_addr, options)?,
+				};
+				let tunnel = ServerTunnel::new(&session, local)?
+					.with_http_headers(self.tunnel_type == TunnelType::Http);
+				info!("[{}]: serving on {}", self.name, tunnel.local_addr()?);
+				Box::new(move || tunnel.run())
+			}
+			TunnelType::Socks => {
+				let mut proxy = SocksProxy::bind(local, sam_addr, options)?;
+				if let Some(session) = session {
+					proxy = proxy.with_session(session);
+				}
+				Box::new(move || proxy.run())
+			}
+			TunnelType::HttpProxy => {
+				let mut proxy = HttpProxy::bind(local, sam_addr, options)?;
+				if let Some(session) = session {
+					proxy = proxy.with_session(session);
+				}
+				Box::new(move || proxy.run())
+			}
+		};
+		thread::Builder::new()
+			.name(self.name.clone())
+			.spawn(run)
+			.map_err(|e| e.into())
+	}
+
+	/// Creates the session of the tunnel from its key file, generating the
+	/// file if it doesn't exist.
+	fn session(&self, sam_addr: SocketAddr, keys: &Path) -> Result<Session, Error> {
+		let private_key = match fs::read(keys) {
+			Ok(data) => decode_keys(&data),
+			Err(ref e) if e.kind() == IoErrorKind::NotFound => {
+				let mut sam = SamConnection::connect(sam_addr)?;
+				let (_, private_key) =
+					sam.generate_destination(self.options.signature_type.clone())?;
+				fs::write(keys, b64_decode(&private_key)?)?;
+				info!("[{}]: created key file {}", self.name, keys.display());
+				private_key
+			}
+			Err(e) => return Err(e.into()),
+		};
+		Session::from_destination(sam_addr, &private_key, self.options.clone())
+	}
+}
+
+/// i2pd's data directory when it isn't given one: `%APPDATA%\i2pd` on
+/// Windows, `~/Library/Application Support/i2pd` on macOS and `~/.i2pd`
+/// elsewhere. `None` if the environment doesn't say where that is.
+pub fn default_data_dir() -> Option<PathBuf> {
+	if cfg!(windows) {
+		return env::var_os("APPDATA").map(|dir| PathBuf::from(dir).join("i2pd"));
+	}
+	let home = PathBuf::from(env::var_os("HOME")?);
+	if cfg!(target_os = "macos") {
+		Some(home.join("Library/Application Support/i2pd"))
+	} else {
+		Some(home.join(".i2pd"))
+	}
+}
+
+/// Reads a key file, either in i2pd's binary format or as the base64 text
+/// returned by the SAM bridge.
+fn decode_keys(data: &[u8]) -> String {
+	if let Ok(text) = std::str::from_utf8(data) {
+		if b64_decode(text.trim()).is_ok() {
+			return text.trim().to_string();
+		}
+	}
+	b64_encode(data)
+}
+
+/// Maps i2pd's numeric signature types onto `SignatureType`.
+fn signature_type(name: &str, value: &str) -> Result<SignatureType, Error> {
+	match value {
+		"0" => Ok(SignatureType::DsaSha1),
+		"1" => Ok(SignatureType::EcdsaSha256P256),
+		"2" => Ok(SignatureType::EcdsaSha384P384),
+		"3" => Ok(SignatureType::EcdsaSha512P21),
+		"7" => Ok(SignatureType::EdDsaSha512Ed25519),
+		_ => Err(invalid(format!(
+			"[{}]: unsupported signaturetype {}",
+			name, value
+		))),
+	}
+}
+
+fn invalid(message: String) -> Error {
+	ErrorKind::InvalidConfig(message).into()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+
+	use super::{decode_keys, TunnelType, TunnelsConfig};
+	use crate::error::ErrorKind;
+	use crate::sam_options::SignatureType;
+
+	const TUNNELS: &str = "
+# i2pd tunnels
+[irc]
+type = client
+address = 127.0.0.1
+port = 6668
+destination = irc.postman.i2p
+destinationport = 6667
+keys = irc-keys.dat
+
+[eepsite]
+type = http
+host = 127.0.0.2
+port = 8080
+keys = transient
+signaturetype = 7
+inbound.length = 1
+outbound.quantity = 4
+i2cp.leaseSetEncType = 4
+inport = 80
+
+; a proxy
+[socks]
+type = socks
+port = 4447
+";
+
+	#[test]
+	fn parses_tunnels() {
+		let config: TunnelsConfig = TUNNELS.parse().unwrap();
+		let names: Vec<_> = config.tunnels.iter().map(|t| t.name.as_str()).collect();
+		assert_eq!(names, ["irc", "eepsite", "socks"]);
+
+		let irc = &config.tunnels[0];
+		assert_eq!(irc.tunnel_type, TunnelType::Client);
+		assert_eq!((irc.host.as_str(), irc.port), ("127.0.0.1", 6668));
+		assert_eq!(irc.destination.as_deref(), Some("irc.postman.i2p"));
+		assert_eq!(irc.destination_port, 6667);
+		assert_eq!(irc.keys, Some(PathBuf::from("irc-keys.dat")));
+
+		let eepsite = &config.tunnels[1];
+		assert_eq!(eepsite.tunnel_type, TunnelType::Http);
+		assert_eq!((eepsite.host.as_str(), eepsite.port), ("127.0.0.2", 8080));
+		assert_eq!(eepsite.keys, None);
+		let options = eepsite.options.options();
+		assert!(options.contains("inbound.length=1 "));
+		assert!(options.contains("outbound.quantity=4 "));
+		assert!(options.contains("i2cp.leaseSetEncType=4 "));
+		assert!(!options.contains("inport"));
+		match eepsite.options.signature_type {
+			SignatureType::EdDsaSha512Ed25519 => {}
+			ref other => panic!("unexpected signature type {:?}", other),
+		}
+
+		let socks = &config.tunnels[2];
+		assert_eq!(socks.tunnel_type, TunnelType::Socks);
+		assert_eq!((socks.host.as_str(), socks.port), ("127.0.0.1", 4447));
+	}
+
+	#[test]
+	fn rejects_invalid_tunnels() {
+		for conf in &[
+			"[a]\ntype = udpclient\nport = 1\n",
+			"[a]\ntype = client\nport = 1\n",
+			"[a]\ntype = server\n",
+			"[a]\ntype = server\nport = 1\ninbound.length = long\n",
+			"type = server\n",
+			"[a]\ntype server\n",
+		] {
+			let err = conf.parse::<TunnelsConfig>().unwrap_err();
+			match err.kind() {
+				ErrorKind::InvalidConfig(_) | ErrorKind::InvalidOption(_) => {}
+				other => panic!("unexpected error {:?} for {:?}", other, conf),
+			}
+		}
+	}
+
+	#[test]
+	fn key_files_are_in_the_data_dir() {
+		let path = std::env::temp_dir().join(format!("i2p-rs-{}.conf", std::process::id()));
+		std::fs::write(&path, TUNNELS).unwrap();
+		let config = TunnelsConfig::load_with_data_dir(&path, "/var/lib/i2pd");
+		std::fs::remove_file(&path).unwrap();
+		let config = config.unwrap();
+		assert_eq!(
+			config.tunnels[0].keys,
+			Some(PathBuf::from("/var/lib/i2pd/irc-keys.dat"))
+		);
+	}
+
+	#[test]
+	fn key_files() {
+		let binary = [0_u8, 1, 2, 0xfe, 0xff];
+		let b64 = decode_keys(&binary);
+		assert_eq!(decode_keys(b64.as_bytes()), b64);
+		assert_eq!(decode_keys(format!("{}\n", b64).as_bytes()), b64);
+	}
+}
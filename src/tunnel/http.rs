@@ -0,0 +1,557 @@
+//! an HTTP/1.1 forward proxy opening I2P streams
+//!
+//! Plain requests must use an absolute URI (`GET http://example.i2p/ HTTP/1.1`),
+//! the way browsers and curl talk to a proxy; `CONNECT host.i2p:port` opens
+//! a raw tunnel instead, e.g. for HTTPS. Like I2P's own HTTP proxy,
+//! identifying headers are removed from forwarded requests.
+//!
+//! Each client connection carries a single request: responses are sent
+//! with `Connection: close`, and anything the client sends after the body
+//! of its request is dropped rather than reaching the eepsite unfiltered.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+
+use log::{debug, error, info, warn};
+
+use crate::error::{Error, ErrorKind};
+use crate::net::I2pStream;
+use crate::sam::Session;
+use crate::sam_options::SAMOptions;
+
+use super::{into_io, is_i2p_host, Sessions};
+
+/// Upper bound on the size of a request line plus headers.
+const MAX_HEAD: usize = 64 * 1024;
+
+/// Request headers never forwarded: the ones identifying the client, and
+/// the hop-by-hop ones meant for the proxy.
+const STRIPPED_HEADERS: &[&str] = &[
+	"user-agent",
+	"referer",
+	"from",
+	"via",
+	"forwarded",
+	"x-forwarded-for",
+	"x-forwarded-host",
+	"x-forwarded-proto",
+	"x-forwarded-server",
+	"x-real-ip",
+	"accept-language",
+	"host",
+	"connection",
+	"keep-alive",
+	"proxy-connection",
+	"proxy-authorization",
+];
+
+/// Response headers replaced by `Connection: close`.
+const CLOSED_HEADERS: &[&str] = &["connection", "keep-alive", "proxy-connection"];
+
+/// A failed request, answered with the matching HTTP status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+	BadRequest,
+	Forbidden,
+	LengthRequired,
+	HeadersTooLarge,
+	BadGateway,
+	GatewayTimeout,
+}
+
+impl Status {
+	fn line(self) -> &'static str {
+		match self {
+			Status::BadRequest => "400 Bad Request",
+			Status::Forbidden => "403 Forbidden",
+			Status::LengthRequired => "411 Length Required",
+			Status::HeadersTooLarge => "431 Request Header Fields Too Large",
+			Status::BadGateway => "502 Bad Gateway",
+			Status::GatewayTimeout => "504 Gateway Timeout",
+		}
+	}
+}
+
+/// A request read from a proxy client
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Request {
+	method: String,
+	host: String,
+	port: u16,
+	/// origin-form target, `None` for `CONNECT`
+	path: Option<String>,
+	version: String,
+	headers: Vec<(String, String)>,
+}
+
+impl Request {
+	/// Request head to send to the destination: origin-form target, a Host
+	/// header of our own, no identifying or hop-by-hop headers, and
+	/// `Connection: close` so the stream ends with the response.
+	fn forward_head(&self) -> String {
+		let mut head = format!(
+			"{} {} {}\r\n",
+			self.method,
+			self.path.as_deref().unwrap_or("/"),
+			self.version
+		);
+		if self.port == 80 {
+			head.push_str(&format!("Host: {}\r\n", self.host));
+		} else {
+			head.push_str(&format!("Host: {}:{}\r\n", self.host, self.port));
+		}
+		for (name, value) in &self.headers {
+			if !STRIPPED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+				head.push_str(&format!("{}: {}\r\n", name, value));
+			}
+		}
+		head.push_str("Connection: close\r\n\r\n");
+		head
+	}
+
+	/// Length of the body following the head. Chunked bodies are refused,
+	/// as the end of the request couldn't be told without decoding them.
+	fn body_length(&self) -> Result<u64, Status> {
+		let mut length = 0;
+		for (name, value) in &self.headers {
+			if name.eq_ignore_ascii_case("transfer-encoding") {
+				return Err(Status::LengthRequired);
+			}
+			if name.eq_ignore_ascii_case("content-length") {
+				length = value.parse().map_err(|_| Status::BadRequest)?;
+			}
+		}
+		Ok(length)
+	}
+}
+
+/// An HTTP proxy server forwarding requests to eepsites.
+///
+/// # Examples
+///
+/// ```no_run
+/// use i2p::sam::DEFAULT_API;
+/// use i2p::sam_options::SAMOptions;
+/// use i2p::tunnel::HttpProxy;
+///
+/// let proxy = HttpProxy::bind("127.0.0.1:4444", DEFAULT_API, SAMOptions::default()).unwrap();
+/// proxy.run().unwrap();
+/// ```
+pub struct HttpProxy {
+	listener: TcpListener,
+	sessions: Arc<Sessions>,
+}
+
+impl HttpProxy {
+	/// Listen for HTTP clients on `local_addr`, opening streams through the
+	/// SAM bridge at `sam_addr`. The session is created with `options` when
+	/// the first client needs it.
+	pub fn bind<A: ToSocketAddrs, B: ToSocketAddrs>(
+		local_addr: A,
+		sam_addr: B,
+		options: SAMOptions,
+	) -> Result<HttpProxy, Error> {
+		let sam_addr = sam_addr
+			.to_socket_addrs()?
+			.next()
+			.ok_or_else(|| Error::from(ErrorKind::UnresolvableAddress))?;
+		Ok(HttpProxy {
+			listener: TcpListener::bind(local_addr)?,
+			sessions: Arc::new(Sessions::new(sam_addr, options)),
+		})
+	}
+
+	/// Open all streams on `session` instead of creating one.
+	pub fn with_session(mut self, session: Session) -> Self {
+		Arc::get_mut(&mut self.sessions)
+			.expect("proxy is configured before it runs")
+			.fixed = Some(Arc::new(session));
+		self
+	}
+
+	/// Returns the local address clients connect to.
+	pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+		self.listener.local_addr().map_err(|e| e.into())
+	}
+
+	/// Serve clients forever, each on its own thread.
+	pub fn run(&self) -> Result<(), Error> {
+		info!("HTTP proxy listening on {}", self.local_addr()?);
+		for client in self.listener.incoming() {
+			let client = match client {
+				Ok(client) => client,
+				Err(e) => {
+					warn!("failed to accept HTTP client: {}", e);
+					continue;
+				}
+			};
+			let sessions = self.sessions.clone();
+			thread::spawn(move || {
+				if let Err(e) = serve(client, &sessions) {
+					debug!("HTTP client failed: {}", e.kind());
+				}
+			});
+		}
+		Ok(())
+	}
+}
+
+fn serve(mut client: TcpStream, sessions: &Sessions) -> Result<(), Error> {
+	let request = match read_head(&mut client) {
+		Ok(head) => parse_request(&head),
+		Err(e) if e.kind() == io::ErrorKind::InvalidData => Err(Status::HeadersTooLarge),
+		Err(e) => return Err(e.into()),
+	};
+	let request = request.and_then(|request| Ok((request.body_length()?, request)));
+	let (body, request) = match request {
+		Ok(request) => request,
+		Err(status) => {
+			write_status(&mut client, status)?;
+			return Err(ErrorKind::SAMInvalidMessage(status.line().to_string()).into());
+		}
+	};
+	debug!("HTTP {} {}:{}", request.method, request.host, request.port);
+
+	let session = match sessions.get("") {
+		Ok(session) => session,
+		Err(e) => {
+			error!("failed to create I2P session: {}", e.kind());
+			write_status(&mut client, Status::BadGateway)?;
+			return Err(e);
+		}
+	};
+	let mut stream =
+		match I2pStream::connect_with_session(&session, (request.host.as_str(), request.port)) {
+			Ok(stream) => stream,
+			Err(e) => {
+				if let ErrorKind::Io(_) = e.kind() {
+					sessions.evict("", &session);
+				}
+				write_status(&mut client, status_for(&e))?;
+				return Err(e);
+			}
+		};
+
+	if request.path.is_none() {
+		client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+		super::pump(client, stream)?;
+	} else {
+		stream.write_all(request.forward_head().as_bytes())?;
+		exchange(client, stream, body)?;
+	}
+	Ok(())
+}
+
+/// Sends the `body` bytes of the request and relays the response, closing
+/// the client once the eepsite is done. Whatever else the client sends is
+/// never forwarded.
+fn exchange(client: TcpStream, stream: I2pStream, body: u64) -> io::Result<()> {
+	let mut upload = client.try_clone()?.take(body);
+	let mut upstream = stream.try_clone().map_err(into_io)?;
+	let uploader = thread::spawn(move || io::copy(&mut upload, &mut upstream));
+
+	let (mut stream, mut client) = (stream, client);
+	let relayed = read_head(&mut stream).and_then(|head| {
+		client.write_all(&close_response(&head))?;
+		io::copy(&mut stream, &mut client)
+	});
+	let _ = client.shutdown(Shutdown::Both);
+	let _ = stream.shutdown(Shutdown::Both);
+	let _ = uploader.join();
+	relayed.map(|_| ())
+}
+
+/// Response head with `Connection: close` in place of any hop-by-hop
+/// connection headers the eepsite sent.
+fn close_response(head: &[u8]) -> Vec<u8> {
+	let mut closed = Vec::with_capacity(head.len() + 19);
+	for line in head.split_inclusive(|&b| b == b'\n') {
+		if line == b"\r\n" || line == b"\n" {
+			closed.extend_from_slice(b"Connection: close\r\n");
+			closed.extend_from_slice(line);
+			break;
+		}
+		let name = line.split(|&b| b == b':').next().unwrap_or(line);
+		let name = String::from_utf8_lossy(name).trim().to_ascii_lowercase();
+		if !CLOSED_HEADERS.contains(&name.as_str()) {
+			closed.extend_from_slice(line);
+		}
+	}
+	closed
+}
+
+fn status_for(e: &Error) -> Status {
+	match e.kind() {
+		ErrorKind::SAMCantReachPeer(_)
+		| ErrorKind::SAMPeerNotFound(_)
+		| ErrorKind::SAMKeyNotFound(_)
+		| ErrorKind::SAMTimeout(_) => Status::GatewayTimeout,
+		_ => Status::BadGateway,
+	}
+}
+
+fn write_status<S: Write>(client: &mut S, status: Status) -> io::Result<()> {
+	let body = format!("{}\r\n", status.line());
+	write!(
+		client,
+		"HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		status.line(),
+		body.len(),
+		body
+	)
+}
+
+/// Reads the request line and headers, up to and including the empty
+/// line, leaving any body unread.
+pub(crate) fn read_head<S: Read>(client: &mut S) -> io::Result<Vec<u8>> {
+	let mut head = Vec::new();
+	let mut byte = [0_u8; 1];
+	while !(head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n")) {
+		if head.len() == MAX_HEAD {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"request head too large",
+			));
+		}
+		client.read_exact(&mut byte)?;
+		head.push(byte[0]);
+	}
+	Ok(head)
+}
+
+fn parse_request(head: &[u8]) -> Result<Request, Status> {
+	let head = std::str::from_utf8(head).map_err(|_| Status::BadRequest)?;
+	let mut lines = head.lines();
+	let mut parts = lines.next().unwrap_or("").split_whitespace();
+	let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+		(Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/") => {
+			(method, target, version)
+		}
+		_ => return Err(Status::BadRequest),
+	};
+
+	let mut headers = Vec::new();
+	for line in lines.take_while(|line| !line.is_empty()) {
+		let (name, value) = line.split_once(':').ok_or(Status::BadRequest)?;
+		headers.push((name.trim().to_string(), value.trim().to_string()));
+	}
+
+	let (host, port, path) = if method.eq_ignore_ascii_case("CONNECT") {
+		let (host, port) = target.rsplit_once(':').ok_or(Status::BadRequest)?;
+		let port = port.parse().map_err(|_| Status::BadRequest)?;
+		(host, port, None)
+	} else {
+		let (host, port, path) = parse_absolute_uri(target)?;
+		(host, port, Some(path))
+	};
+	if !is_i2p_host(host) {
+		return Err(Status::Forbidden);
+	}
+
+	Ok(Request {
+		method: method.to_string(),
+		host: host.to_ascii_lowercase(),
+		port,
+		path,
+		version: version.to_string(),
+		headers,
+	})
+}
+
+/// Splits `http://host[:port][/path]` into host, port and origin-form path.
+fn parse_absolute_uri(uri: &str) -> Result<(&str, u16, String), Status> {
+	let (scheme, rest) = uri.split_once("://").ok_or(Status::BadRequest)?;
+	if !scheme.eq_ignore_ascii_case("http") {
+		return Err(Status::BadRequest);
+	}
+	let (authority, path) = match rest.find(['/', '?']) {
+		Some(i) => (&rest[..i], rest[i..].to_string()),
+		None => (rest, "/".to_string()),
+	};
+	let path = if path.starts_with('?') {
+		format!("/{}", path)
+	} else {
+		path
+	};
+	let (host, port) = match authority.rsplit_once(':') {
+		Some((host, port)) => (host, port.parse().map_err(|_| Status::BadRequest)?),
+		None => (authority, 80),
+	};
+	if host.is_empty() || host.contains('@') {
+		return Err(Status::BadRequest);
+	}
+	Ok((host, port, path))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::{Cursor, Read, Write};
+	use std::net::TcpStream;
+	use std::sync::{Arc, Mutex};
+	use std::thread;
+
+	use super::{close_response, parse_request, read_head, HttpProxy, Status};
+	use crate::sam::Session;
+	use crate::sam_options::SAMOptions;
+	use crate::test::{default_reply, mock_sam};
+
+	#[test]
+	fn absolute_uri_is_rewritten() {
+		let request = parse_request(
+			b"GET http://Example.i2p:8080/a?b=c HTTP/1.1\r\n\
+			  Host: example.i2p:8080\r\n\
+			  User-Agent: curl/8.0\r\n\
+			  referer: http://other.i2p/\r\n\
+			  X-Forwarded-For: 10.0.0.1\r\n\
+			  Via: 1.1 squid\r\n\
+			  Forwarded: for=10.0.0.1\r\n\
+			  X-Real-IP: 10.0.0.1\r\n\
+			  Accept-Language: de-CH\r\n\
+			  Proxy-Connection: keep-alive\r\n\
+			  Accept: */*\r\n\r\n",
+		)
+		.unwrap();
+
+		assert_eq!(request.host, "example.i2p");
+		assert_eq!(request.port, 8080);
+		assert_eq!(
+			request.forward_head(),
+			"GET /a?b=c HTTP/1.1\r\n\
+			 Host: example.i2p:8080\r\n\
+			 Accept: */*\r\n\
+			 Connection: close\r\n\r\n"
+		);
+	}
+
+	#[test]
+	fn default_port_and_path() {
+		let request = parse_request(b"HEAD http://example.i2p HTTP/1.0\r\n\r\n").unwrap();
+		assert_eq!(request.port, 80);
+		assert_eq!(
+			request.forward_head(),
+			"HEAD / HTTP/1.0\r\nHost: example.i2p\r\nConnection: close\r\n\r\n"
+		);
+	}
+
+	#[test]
+	fn connect() {
+		let request = parse_request(b"CONNECT example.i2p:443 HTTP/1.1\r\n\r\n").unwrap();
+		assert_eq!(
+			(request.host.as_str(), request.port, request.path),
+			("example.i2p", 443, None)
+		);
+	}
+
+	#[test]
+	fn rejected_requests() {
+		assert_eq!(
+			parse_request(b"GET http://example.com/ HTTP/1.1\r\n\r\n"),
+			Err(Status::Forbidden)
+		);
+		assert_eq!(
+			parse_request(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n"),
+			Err(Status::Forbidden)
+		);
+		assert_eq!(
+			parse_request(b"GET / HTTP/1.1\r\n\r\n"),
+			Err(Status::BadRequest)
+		);
+		assert_eq!(
+			parse_request(b"GET https://example.i2p/ HTTP/1.1\r\n\r\n"),
+			Err(Status::BadRequest)
+		);
+	}
+
+	#[test]
+	fn head_leaves_body_unread() {
+		let mut client =
+			Cursor::new(b"POST http://a.i2p/ HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody".to_vec());
+		let head = read_head(&mut client).unwrap();
+		assert!(head.ends_with(b"\r\n\r\n"));
+		let mut body = String::new();
+		client.read_to_string(&mut body).unwrap();
+		assert_eq!(body, "body");
+	}
+
+	#[test]
+	fn bodies_need_a_length() {
+		let request =
+			parse_request(b"POST http://a.i2p/ HTTP/1.1\r\nContent-Length: 4\r\n\r\n").unwrap();
+		assert_eq!(request.body_length(), Ok(4));
+		let request =
+			parse_request(b"POST http://a.i2p/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n")
+				.unwrap();
+		assert_eq!(request.body_length(), Err(Status::LengthRequired));
+	}
+
+	#[test]
+	fn responses_close_the_connection() {
+		assert_eq!(
+			close_response(
+				b"HTTP/1.1 200 OK\r\nConnection: keep-alive\r\nKeep-Alive: timeout=5\r\n\
+				  Content-Length: 2\r\n\r\n"
+			),
+			b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n".to_vec()
+		);
+	}
+
+	#[test]
+	fn one_request_per_connection() {
+		// the eepsite sees every line it is sent, and answers once the
+		// request head is complete
+		let seen = Arc::new(Mutex::new(Vec::new()));
+		let eepsite = seen.clone();
+		let sam = mock_sam(move |line: &str| {
+			if line.starts_with("HELLO") {
+				// the proxy connects to port 80, which takes SAM 3.2
+				return Some("HELLO REPLY RESULT=OK VERSION=3.2\n".to_string());
+			}
+			let command = ["NAMING", "SESSION", "STREAM"];
+			if command.iter().any(|c| line.starts_with(c)) {
+				return default_reply(line);
+			}
+			eepsite.lock().unwrap().push(line.to_string());
+			if line == "\r\n" {
+				Some(
+					"HTTP/1.1 200 OK\r\nConnection: keep-alive\r\nContent-Length: 2\r\n\r\nok"
+						.to_string(),
+				)
+			} else {
+				Some(String::new())
+			}
+		});
+		let session = Session::transient(sam, SAMOptions::default()).unwrap();
+		let proxy = HttpProxy::bind("127.0.0.1:0", sam, SAMOptions::default())
+			.unwrap()
+			.with_session(session);
+		let addr = proxy.local_addr().unwrap();
+		thread::spawn(move || proxy.run());
+
+		let mut client = TcpStream::connect(addr).unwrap();
+		client
+			.write_all(
+				b"GET http://example.i2p/one HTTP/1.1\r\nUser-Agent: curl/8.0\r\n\r\n\
+				  GET http://example.i2p/two HTTP/1.1\r\nUser-Agent: curl/8.0\r\n\r\n",
+			)
+			.unwrap();
+		let mut head = read_head(&mut client).unwrap();
+		let mut body = [0; 2];
+		client.read_exact(&mut body).unwrap();
+		head.extend_from_slice(&body);
+		assert_eq!(
+			String::from_utf8(head).unwrap(),
+			"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+		);
+		assert_eq!(
+			*seen.lock().unwrap(),
+			vec![
+				"GET /one HTTP/1.1\r\n",
+				"Host: example.i2p\r\n",
+				"Connection: close\r\n",
+				"\r\n",
+			]
+		);
+	}
+}
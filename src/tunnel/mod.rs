@@ -0,0 +1,233 @@
+//! proxies and tunnels bridging plain TCP applications and I2P streams
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use log::{debug, info};
+
+use crate::error::Error;
+use crate::net::I2pStream;
+use crate::sam::Session;
+use crate::sam_options::SAMOptions;
+
+pub mod client;
+pub mod config;
+pub mod http;
+pub mod server;
+pub mod socks;
+
+pub use self::client::ClientTunnel;
+pub use self::config::TunnelsConfig;
+pub use self::http::HttpProxy;
+pub use self::server::ServerTunnel;
+pub use self::socks::SocksProxy;
+
+/// Copies bytes between `local` and `remote` in both directions until
+/// either side closes, then closes both. Blocks the calling thread, using
+/// one extra thread for the `local` to `remote` direction. Returns the
+/// number of bytes sent to and received from `remote`.
+pub fn pump(local: TcpStream, remote: I2pStream) -> io::Result<(u64, u64)> {
+	let mut local_read = local.try_clone()?;
+	let mut remote_write = remote.try_clone().map_err(into_io)?;
+	let local_closer = local.try_clone()?;
+	let remote_closer = remote.try_clone().map_err(into_io)?;
+
+	let upstream = thread::spawn(move || {
+		let sent = io::copy(&mut local_read, &mut remote_write);
+		// unblock the other direction
+		let _ = remote_closer.shutdown(Shutdown::Both);
+		let _ = local_closer.shutdown(Shutdown::Both);
+		sent
+	});
+
+	let mut remote_read = remote;
+	let mut local_write = local;
+	let received = io::copy(&mut remote_read, &mut local_write);
+	let _ = local_write.shutdown(Shutdown::Both);
+	let _ = remote_read.shutdown(Shutdown::Both);
+
+	let sent = upstream
+		.join()
+		.map_err(|_| io::Error::other("pump thread panicked"))?;
+	let (sent, received) = (sent.unwrap_or(0), received.unwrap_or(0));
+	debug!("pumped {} bytes out, {} bytes in", sent, received);
+	Ok((sent, received))
+}
+
+/// Whether `host` names an I2P destination, i.e. a `.i2p` hostname or b32
+/// address; anything else can't be reached through a SAM session.
+pub(crate) fn is_i2p_host(host: &str) -> bool {
+	host.len() > ".i2p".len() && host.to_ascii_lowercase().ends_with(".i2p")
+}
+
+fn into_io(e: crate::Error) -> io::Error {
+	io::Error::other(e.kind().to_string())
+}
+
+/// How many sessions a proxy keeps for isolated clients; the least
+/// recently used is dropped to make room for a new one.
+pub(crate) const MAX_SESSIONS: usize = 32;
+
+/// The sessions a proxy opens its streams on, created when first needed.
+pub(crate) struct Sessions {
+	sam_addr: SocketAddr,
+	options: SAMOptions,
+	/// give every client key its own session
+	pub(crate) isolate: bool,
+	/// a session handed in by the caller, used instead of creating one
+	pub(crate) fixed: Option<Arc<Session>>,
+	/// sessions created on demand, keyed by client key
+	created: Mutex<HashMap<String, Slot>>,
+	/// upper bound on the number of keys in `created`
+	pub(crate) max_sessions: usize,
+}
+
+/// The session of a client key, and when a client last asked for it.
+struct Slot {
+	/// locked while the session is created, which only holds up clients
+	/// with the same key
+	session: Arc<Mutex<Option<Arc<Session>>>>,
+	used: Instant,
+}
+
+impl Sessions {
+	pub(crate) fn new(sam_addr: SocketAddr, options: SAMOptions) -> Sessions {
+		Sessions {
+			sam_addr,
+			options,
+			isolate: false,
+			fixed: None,
+			created: Mutex::new(HashMap::new()),
+			max_sessions: MAX_SESSIONS,
+		}
+	}
+
+	/// Returns the session for `key`, e.g. a SOCKS username. Keys are
+	/// ignored unless isolation is enabled.
+	pub(crate) fn get(&self, key: &str) -> Result<Arc<Session>, Error> {
+		let key = if self.isolate { key } else { "" };
+		if key.is_empty() {
+			if let Some(session) = &self.fixed {
+				return Ok(session.clone());
+			}
+		}
+		let slot = {
+			let mut created = self.created.lock().unwrap_or_else(|e| e.into_inner());
+			if !created.contains_key(key) && created.len() >= self.max_sessions.max(1) {
+				let oldest = created
+					.iter()
+					.min_by_key(|(_, slot)| slot.used)
+					.map(|(key, _)| key.clone());
+				if let Some(oldest) = oldest {
+					debug!("dropping the session of client {:?}", oldest);
+					created.remove(&oldest);
+				}
+			}
+			let slot = created.entry(key.to_string()).or_insert_with(|| Slot {
+				session: Default::default(),
+				used: Instant::now(),
+			});
+			slot.used = Instant::now();
+			slot.session.clone()
+		};
+		let mut session = slot.lock().unwrap_or_else(|e| e.into_inner());
+		if let Some(session) = &*session {
+			return Ok(session.clone());
+		}
+		info!("creating I2P session for client {:?}", key);
+		let created = Arc::new(Session::transient(self.sam_addr, self.options.clone())?);
+		*session = Some(created.clone());
+		Ok(created)
+	}
+
+	/// Forget a session that stopped working, so the next client with the
+	/// same key gets a fresh one.
+	pub(crate) fn evict(&self, key: &str, session: &Arc<Session>) {
+		let key = if self.isolate { key } else { "" };
+		let mut created = self.created.lock().unwrap_or_else(|e| e.into_inner());
+		let current = created.get(key).is_some_and(|slot| {
+			let slot = slot.session.lock().unwrap_or_else(|e| e.into_inner());
+			slot.as_ref().is_some_and(|s| Arc::ptr_eq(s, session))
+		});
+		if current {
+			created.remove(key);
+		}
+	}
+
+	/// Creates a session of its own for a single client, which isn't kept.
+	pub(crate) fn create(&self) -> Result<Session, Error> {
+		Session::transient(self.sam_addr, self.options.clone())
+	}
+
+	/// Drops every session created so far, returning how many there were.
+	/// Sessions still used by a client stay open until it is done.
+	pub(crate) fn close_all(&self) -> usize {
+		let mut created = self.created.lock().unwrap_or_else(|e| e.into_inner());
+		let count = created.len();
+		created.clear();
+		count
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::sync::{mpsc, Arc};
+	use std::thread;
+	use std::time::Duration;
+
+	use super::Sessions;
+	use crate::sam_options::SAMOptions;
+	use crate::test::{default_reply, mock_sam};
+
+	/// Isolated sessions on a mock bridge, counting the sessions created.
+	/// The first one takes `delay` to come up.
+	fn sessions(delay: Duration) -> (Arc<Sessions>, Arc<AtomicUsize>) {
+		let count = Arc::new(AtomicUsize::new(0));
+		let created = count.clone();
+		let sam = mock_sam(move |line: &str| {
+			if line.starts_with("SESSION CREATE") && created.fetch_add(1, Ordering::SeqCst) == 0 {
+				thread::sleep(delay);
+			}
+			default_reply(line)
+		});
+		let mut sessions = Sessions::new(sam, SAMOptions::default());
+		sessions.isolate = true;
+		(Arc::new(sessions), count)
+	}
+
+	#[test]
+	fn slow_sessions_hold_up_their_key_only() {
+		let (sessions, count) = sessions(Duration::from_secs(5));
+		let slow = sessions.clone();
+		thread::spawn(move || slow.get("slow"));
+		while count.load(Ordering::SeqCst) == 0 {
+			thread::sleep(Duration::from_millis(10));
+		}
+		let (done, fast) = mpsc::channel();
+		let other = sessions.clone();
+		thread::spawn(move || done.send(other.get("fast").is_ok()));
+		assert_eq!(fast.recv_timeout(Duration::from_secs(3)), Ok(true));
+	}
+
+	#[test]
+	fn least_recently_used_sessions_make_room() {
+		let (sessions, count) = sessions(Duration::from_secs(0));
+		let mut sessions = Arc::try_unwrap(sessions).ok().unwrap();
+		sessions.max_sessions = 2;
+		let a = sessions.get("a").unwrap();
+		sessions.get("b").unwrap();
+		assert!(Arc::ptr_eq(&a, &sessions.get("a").unwrap()));
+		sessions.get("c").unwrap();
+		assert_eq!(count.load(Ordering::SeqCst), 3);
+		// b made room for c, a is still there
+		assert!(Arc::ptr_eq(&a, &sessions.get("a").unwrap()));
+		sessions.get("b").unwrap();
+		assert_eq!(count.load(Ordering::SeqCst), 4);
+		assert_eq!(sessions.close_all(), 2);
+	}
+}
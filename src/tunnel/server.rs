@@ -0,0 +1,187 @@
+//! a server tunnel exposing a local TCP service on an I2P destination
+
+use std::fs;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::thread;
+
+use log::{debug, info, warn};
+
+use crate::error::{Error, ErrorKind};
+use crate::net::{I2pAddr, I2pListener, I2pSocketAddr, I2pStream, ShutdownHandle};
+use crate::sam::Session;
+use crate::sam_options::SAMOptions;
+
+use super::http::read_head;
+
+/// Forwards every stream accepted on an I2P destination to a local TCP
+/// service, e.g. to host an eepsite from a local web server.
+///
+/// # Examples
+///
+/// ```no_run
+/// use i2p::sam::DEFAULT_API;
+/// use i2p::sam_options::SAMOptions;
+/// use i2p::tunnel::ServerTunnel;
+///
+/// let tunnel = ServerTunnel::from_key_file(
+///     "eepsite.key",
+///     DEFAULT_API,
+///     "127.0.0.1:8080",
+///     SAMOptions::default(),
+/// )
+/// .unwrap()
+/// .with_http_headers(true);
+/// println!("serving on {}", tunnel.local_addr().unwrap());
+/// tunnel.run().unwrap();
+/// ```
+pub struct ServerTunnel {
+	listener: I2pListener,
+	target: SocketAddr,
+	http_headers: bool,
+}
+
+impl ServerTunnel {
+	/// Accept streams on the destination of `session` and forward them to
+	/// `target`.
+	pub fn new<A: ToSocketAddrs>(session: &Session, target: A) -> Result<ServerTunnel, Error> {
+		Ok(ServerTunnel {
+			listener: I2pListener::bind_with_session(session)?,
+			target: resolve(target)?,
+			http_headers: false,
+		})
+	}
+
+	/// Same as `new`, creating the session from the base64 private key
+	/// stored in `key_file`, as returned by `SamConnection::generate_destination`.
+	pub fn from_key_file<P: AsRef<Path>, A: ToSocketAddrs, B: ToSocketAddrs>(
+		key_file: P,
+		sam_addr: A,
+		target: B,
+		options: SAMOptions,
+	) -> Result<ServerTunnel, Error> {
+		let key = fs::read_to_string(key_file)?;
+		let session = Session::from_destination(sam_addr, key.trim(), options)?;
+		ServerTunnel::new(&session, target)
+	}
+
+	/// Treat the target as an HTTP server: tell it who is connecting with
+	/// `X-I2P-DestB64` and `X-I2P-DestB32` request headers, replacing any
+	/// sent by the client. Forwarded connections are closed after the first
+	/// request so every request carries the headers.
+	pub fn with_http_headers(mut self, http_headers: bool) -> Self {
+		self.http_headers = http_headers;
+		self
+	}
+
+	/// Returns the I2P address clients connect to.
+	pub fn local_addr(&self) -> Result<I2pSocketAddr, Error> {
+		self.listener.local_addr()
+	}
+
+	/// Returns a handle stopping `run` from another thread.
+	pub fn shutdown_handle(&self) -> ShutdownHandle {
+		self.listener.shutdown_handle()
+	}
+
+	/// Forward incoming streams, each on its own worker thread, until the
+	/// tunnel is shut down.
+	pub fn run(&self) -> Result<(), Error> {
+		info!("server tunnel forwarding to {}", self.target);
+		for stream in self.listener.incoming() {
+			let stream = match stream {
+				Ok(stream) => stream,
+				Err(e) => {
+					warn!("failed to accept I2P stream: {}", e.kind());
+					continue;
+				}
+			};
+			let target = self.target;
+			let http_headers = self.http_headers;
+			thread::spawn(move || {
+				if let Err(e) = forward(stream, target, http_headers) {
+					debug!("server tunnel stream failed: {}", e.kind());
+				}
+			});
+		}
+		Ok(())
+	}
+}
+
+fn resolve<A: ToSocketAddrs>(addr: A) -> Result<SocketAddr, Error> {
+	addr.to_socket_addrs()?
+		.next()
+		.ok_or_else(|| ErrorKind::UnresolvableAddress.into())
+}
+
+fn forward(mut stream: I2pStream, target: SocketAddr, http_headers: bool) -> Result<(), Error> {
+	let mut local = TcpStream::connect(target)?;
+	if http_headers {
+		let peer = stream.peer_addr()?.dest().string();
+		let head = read_head(&mut stream)?;
+		local.write_all(&inject_dest_headers(&head, &peer)?)?;
+	}
+	super::pump(local, stream)?;
+	Ok(())
+}
+
+/// Rewrites an HTTP request head to carry the destination of the client,
+/// `dest` in base64, and to close the connection after the response.
+fn inject_dest_headers(head: &[u8], dest: &str) -> Result<Vec<u8>, Error> {
+	let b32 = I2pAddr::from_b64(dest)?;
+	let head = String::from_utf8_lossy(head);
+	let mut lines = head.lines();
+	let mut out = String::with_capacity(head.len() + dest.len() + 128);
+	out.push_str(lines.next().unwrap_or(""));
+	out.push_str("\r\n");
+	for line in lines.take_while(|line| !line.is_empty()) {
+		let name = line.split(':').next().unwrap_or("").trim();
+		let name = name.to_ascii_lowercase();
+		if name.starts_with("x-i2p-dest")
+			|| name == "connection"
+			|| name == "keep-alive"
+			|| name == "proxy-connection"
+		{
+			continue;
+		}
+		out.push_str(line);
+		out.push_str("\r\n");
+	}
+	out.push_str(&format!("X-I2P-DestB64: {}\r\n", dest));
+	out.push_str(&format!("X-I2P-DestB32: {}\r\n", b32));
+	out.push_str("Connection: close\r\n\r\n");
+	Ok(out.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::inject_dest_headers;
+
+	#[test]
+	fn dest_headers_replace_client_ones() {
+		let dest = "A".repeat(516);
+		let head = inject_dest_headers(
+			b"GET / HTTP/1.1\r\n\
+			  Host: example.i2p\r\n\
+			  X-I2P-DestB32: spoofed.b32.i2p\r\n\
+			  x-i2p-desthash: spoofed\r\n\
+			  Connection: keep-alive\r\n\r\n",
+			&dest,
+		)
+		.unwrap();
+		let head = String::from_utf8(head).unwrap();
+
+		assert!(head.starts_with("GET / HTTP/1.1\r\nHost: example.i2p\r\n"));
+		assert!(!head.contains("spoofed"));
+		assert!(!head.contains("keep-alive"));
+		assert!(head.contains(&format!("X-I2P-DestB64: {}\r\n", dest)));
+		assert!(head.contains(".b32.i2p\r\n"));
+		assert!(head.ends_with("Connection: close\r\n\r\n"));
+	}
+
+	#[test]
+	fn dest_headers_need_a_valid_destination() {
+		assert!(inject_dest_headers(b"GET / HTTP/1.1\r\n\r\n", "not base64!").is_err());
+	}
+}
@@ -0,0 +1,508 @@
+//! a SOCKS5 and SOCKS4a proxy opening I2P streams
+//!
+//! Only the CONNECT command is supported, and only to `.i2p` hostnames and
+//! b32 addresses: clients must let the proxy resolve names (e.g.
+//! `socks5h://` in curl), since I2P destinations have no IP address.
+//!
+//! Streams are opened on a shared session, or, with username isolation
+//! enabled, on one session per SOCKS username, so that unrelated clients of
+//! the proxy can't be linked to each other by their destination.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+
+use log::{debug, error, info, warn};
+
+use crate::error::{Error, ErrorKind};
+use crate::net::I2pStream;
+use crate::sam::Session;
+use crate::sam_options::SAMOptions;
+
+use super::{is_i2p_host, Sessions};
+
+const SOCKS4: u8 = 0x04;
+const SOCKS5: u8 = 0x05;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+/// username/password subnegotiation, RFC 1929
+const AUTH_VERSION: u8 = 0x01;
+const AUTH_SUCCESS: u8 = 0x00;
+const AUTH_FAILURE: u8 = 0xff;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// SOCKS5 reply codes, RFC 1928 section 6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reply {
+	Succeeded = 0x00,
+	GeneralFailure = 0x01,
+	NotAllowed = 0x02,
+	HostUnreachable = 0x04,
+	CommandNotSupported = 0x07,
+	AddressTypeNotSupported = 0x08,
+}
+
+/// A CONNECT request read from a SOCKS client
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Request {
+	version: u8,
+	host: String,
+	port: u16,
+	/// SOCKS5 username or SOCKS4 user id, empty if none was given
+	username: String,
+}
+
+/// A SOCKS5/SOCKS4a proxy server forwarding CONNECT requests to I2P.
+///
+/// # Examples
+///
+/// ```no_run
+/// use i2p::sam::DEFAULT_API;
+/// use i2p::sam_options::SAMOptions;
+/// use i2p::tunnel::SocksProxy;
+///
+/// let proxy = SocksProxy::bind("127.0.0.1:4447", DEFAULT_API, SAMOptions::default())
+///     .unwrap()
+///     .with_username_isolation(true);
+/// proxy.run().unwrap();
+/// ```
+pub struct SocksProxy {
+	listener: TcpListener,
+	sessions: Arc<Sessions>,
+}
+
+impl SocksProxy {
+	/// Listen for SOCKS clients on `local_addr`, opening streams through the
+	/// SAM bridge at `sam_addr`. Sessions are created with `options` when
+	/// the first client needs them.
+	pub fn bind<A: ToSocketAddrs, B: ToSocketAddrs>(
+		local_addr: A,
+		sam_addr: B,
+		options: SAMOptions,
+	) -> Result<SocksProxy, Error> {
+		let sam_addr = sam_addr
+			.to_socket_addrs()?
+			.next()
+			.ok_or_else(|| Error::from(ErrorKind::UnresolvableAddress))?;
+		Ok(SocksProxy {
+			listener: TcpListener::bind(local_addr)?,
+			sessions: Arc::new(Sessions::new(sam_addr, options)),
+		})
+	}
+
+	/// Open all streams on `session` instead of creating one. Ignored for
+	/// clients that send a username while username isolation is enabled.
+	pub fn with_session(mut self, session: Session) -> Self {
+		self.sessions_mut().fixed = Some(Arc::new(session));
+		self
+	}
+
+	/// Give every SOCKS username its own session, and so its own I2P
+	/// destination. Clients without a username share the default session.
+	pub fn with_username_isolation(mut self, isolate: bool) -> Self {
+		self.sessions_mut().isolate = isolate;
+		self
+	}
+
+	/// Keep at most `max` sessions for isolated usernames, 32 by default.
+	/// The least recently used one is dropped for a new username; clients
+	/// still using it keep it open until they are done.
+	pub fn with_max_sessions(mut self, max: usize) -> Self {
+		self.sessions_mut().max_sessions = max;
+		self
+	}
+
+	fn sessions_mut(&mut self) -> &mut Sessions {
+		Arc::get_mut(&mut self.sessions).expect("proxy is configured before it runs")
+	}
+
+	/// Returns the local address clients connect to.
+	pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+		self.listener.local_addr().map_err(|e| e.into())
+	}
+
+	/// Serve clients forever, each on its own thread.
+	pub fn run(&self) -> Result<(), Error> {
+		info!("SOCKS proxy listening on {}", self.local_addr()?);
+		for client in self.listener.incoming() {
+			let client = match client {
+				Ok(client) => client,
+				Err(e) => {
+					warn!("failed to accept SOCKS client: {}", e);
+					continue;
+				}
+			};
+			let sessions = self.sessions.clone();
+			thread::spawn(move || {
+				if let Err(e) = serve(client, &sessions) {
+					debug!("SOCKS client failed: {}", e.kind());
+				}
+			});
+		}
+		Ok(())
+	}
+}
+
+fn serve(mut client: TcpStream, sessions: &Sessions) -> Result<(), Error> {
+	let request = match read_request(&mut client) {
+		Ok(request) => request,
+		Err((version, reply, e)) => {
+			let _ = write_reply(&mut client, version, reply);
+			return Err(e);
+		}
+	};
+	debug!(
+		"SOCKS{} CONNECT {}:{}",
+		request.version, request.host, request.port
+	);
+
+	if !is_i2p_host(&request.host) {
+		write_reply(&mut client, request.version, Reply::NotAllowed)?;
+		return Err(ErrorKind::UnresolvableAddress.into());
+	}
+
+	let session = match sessions.get(&request.username) {
+		Ok(session) => session,
+		Err(e) => {
+			error!("failed to create I2P session: {}", e.kind());
+			write_reply(&mut client, request.version, Reply::GeneralFailure)?;
+			return Err(e);
+		}
+	};
+	let stream =
+		match I2pStream::connect_with_session(&session, (request.host.as_str(), request.port)) {
+			Ok(stream) => stream,
+			Err(e) => {
+				if let ErrorKind::Io(_) = e.kind() {
+					sessions.evict(&request.username, &session);
+				}
+				write_reply(&mut client, request.version, reply_for(&e))?;
+				return Err(e);
+			}
+		};
+
+	write_reply(&mut client, request.version, Reply::Succeeded)?;
+	super::pump(client, stream)?;
+	Ok(())
+}
+
+fn reply_for(e: &Error) -> Reply {
+	match e.kind() {
+		ErrorKind::SAMCantReachPeer(_)
+		| ErrorKind::SAMPeerNotFound(_)
+		| ErrorKind::SAMKeyNotFound(_)
+		| ErrorKind::SAMTimeout(_) => Reply::HostUnreachable,
+		_ => Reply::GeneralFailure,
+	}
+}
+
+type RequestError = (u8, Reply, Error);
+
+fn read_request<S: Read + Write>(client: &mut S) -> Result<Request, RequestError> {
+	let io_err = |version: u8| move |e: io::Error| (version, Reply::GeneralFailure, Error::from(e));
+	let mut version = [0_u8; 1];
+	client.read_exact(&mut version).map_err(io_err(SOCKS5))?;
+	match version[0] {
+		SOCKS5 => read_socks5_request(client),
+		SOCKS4 => read_socks4_request(client).map_err(|(reply, e)| (SOCKS4, reply, e)),
+		v => Err((
+			SOCKS5,
+			Reply::GeneralFailure,
+			ErrorKind::SAMInvalidMessage(format!("unknown SOCKS version {}", v)).into(),
+		)),
+	}
+}
+
+fn read_socks5_request<S: Read + Write>(client: &mut S) -> Result<Request, RequestError> {
+	let fail = |reply: Reply| move |e: io::Error| (SOCKS5, reply, Error::from(e));
+	let general = fail(Reply::GeneralFailure);
+
+	// method selection
+	let mut nmethods = [0_u8; 1];
+	client.read_exact(&mut nmethods).map_err(general)?;
+	let mut methods = vec![0_u8; nmethods[0] as usize];
+	client.read_exact(&mut methods).map_err(general)?;
+	let method = if methods.contains(&METHOD_USER_PASS) {
+		METHOD_USER_PASS
+	} else if methods.contains(&METHOD_NO_AUTH) {
+		METHOD_NO_AUTH
+	} else {
+		METHOD_NONE_ACCEPTABLE
+	};
+	client.write_all(&[SOCKS5, method]).map_err(general)?;
+	if method == METHOD_NONE_ACCEPTABLE {
+		return Err((
+			SOCKS5,
+			Reply::GeneralFailure,
+			ErrorKind::SAMInvalidMessage("no acceptable SOCKS auth method".to_string()).into(),
+		));
+	}
+
+	// RFC 1929 username/password; any password is accepted, the username
+	// only selects the session
+	let mut username = String::new();
+	if method == METHOD_USER_PASS {
+		let mut header = [0_u8; 2];
+		client.read_exact(&mut header).map_err(general)?;
+		if header[0] != AUTH_VERSION {
+			let _ = client.write_all(&[AUTH_VERSION, AUTH_FAILURE]);
+			return Err((
+				SOCKS5,
+				Reply::GeneralFailure,
+				ErrorKind::SAMInvalidMessage(format!("unknown SOCKS auth version {}", header[0]))
+					.into(),
+			));
+		}
+		let mut user = vec![0_u8; header[1] as usize];
+		client.read_exact(&mut user).map_err(general)?;
+		let mut plen = [0_u8; 1];
+		client.read_exact(&mut plen).map_err(general)?;
+		let mut password = vec![0_u8; plen[0] as usize];
+		client.read_exact(&mut password).map_err(general)?;
+		client
+			.write_all(&[AUTH_VERSION, AUTH_SUCCESS])
+			.map_err(general)?;
+		username = String::from_utf8_lossy(&user).into_owned();
+	}
+
+	// request
+	let mut header = [0_u8; 4];
+	client.read_exact(&mut header).map_err(general)?;
+	if header[1] != CMD_CONNECT {
+		return Err((
+			SOCKS5,
+			Reply::CommandNotSupported,
+			ErrorKind::SAMInvalidMessage(format!("unsupported SOCKS command {}", header[1])).into(),
+		));
+	}
+	let host = match header[3] {
+		ATYP_DOMAIN => {
+			let mut len = [0_u8; 1];
+			client.read_exact(&mut len).map_err(general)?;
+			let mut host = vec![0_u8; len[0] as usize];
+			client.read_exact(&mut host).map_err(general)?;
+			String::from_utf8_lossy(&host).into_owned()
+		}
+		ATYP_IPV4 | ATYP_IPV6 => {
+			return Err((
+				SOCKS5,
+				Reply::AddressTypeNotSupported,
+				ErrorKind::UnresolvableAddress.into(),
+			))
+		}
+		atyp => {
+			return Err((
+				SOCKS5,
+				Reply::AddressTypeNotSupported,
+				ErrorKind::SAMInvalidMessage(format!("unknown SOCKS address type {}", atyp)).into(),
+			))
+		}
+	};
+	let mut port = [0_u8; 2];
+	client.read_exact(&mut port).map_err(general)?;
+
+	Ok(Request {
+		version: SOCKS5,
+		host,
+		port: u16::from_be_bytes(port),
+		username,
+	})
+}
+
+fn read_socks4_request<S: Read>(client: &mut S) -> Result<Request, (Reply, Error)> {
+	let general = |e: io::Error| (Reply::GeneralFailure, Error::from(e));
+	let mut header = [0_u8; 7];
+	client.read_exact(&mut header).map_err(general)?;
+	let username = read_cstr(client).map_err(general)?;
+	if header[0] != CMD_CONNECT {
+		return Err((
+			Reply::CommandNotSupported,
+			ErrorKind::SAMInvalidMessage(format!("unsupported SOCKS command {}", header[0])).into(),
+		));
+	}
+	let port = u16::from_be_bytes([header[1], header[2]]);
+	let ip = &header[3..7];
+	// SOCKS4a marks a hostname following the user id with 0.0.0.x, x != 0
+	if ip[..3] != [0, 0, 0] || ip[3] == 0 {
+		return Err((
+			Reply::AddressTypeNotSupported,
+			ErrorKind::UnresolvableAddress.into(),
+		));
+	}
+	let host = read_cstr(client).map_err(general)?;
+	Ok(Request {
+		version: SOCKS4,
+		host,
+		port,
+		username,
+	})
+}
+
+/// Reads a NUL-terminated string of at most 255 bytes.
+fn read_cstr<S: Read>(client: &mut S) -> io::Result<String> {
+	let mut bytes = Vec::new();
+	let mut byte = [0_u8; 1];
+	loop {
+		client.read_exact(&mut byte)?;
+		if byte[0] == 0 {
+			return Ok(String::from_utf8_lossy(&bytes).into_owned());
+		}
+		if bytes.len() == 255 {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"SOCKS4 string too long",
+			));
+		}
+		bytes.push(byte[0]);
+	}
+}
+
+fn write_reply<S: Write>(client: &mut S, version: u8, reply: Reply) -> io::Result<()> {
+	if version == SOCKS4 {
+		let code = if reply == Reply::Succeeded {
+			0x5a
+		} else {
+			0x5b
+		};
+		client.write_all(&[0x00, code, 0, 0, 0, 0, 0, 0])
+	} else {
+		client.write_all(&[SOCKS5, reply as u8, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::{self, Cursor, Read, Write};
+
+	use super::{read_request, write_reply, Reply, Request};
+
+	/// A client that has sent `input` and records what the proxy answers.
+	struct Client {
+		input: Cursor<Vec<u8>>,
+		output: Vec<u8>,
+	}
+
+	impl Client {
+		fn new(input: &[u8]) -> Client {
+			Client {
+				input: Cursor::new(input.to_vec()),
+				output: Vec::new(),
+			}
+		}
+	}
+
+	impl Read for Client {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			self.input.read(buf)
+		}
+	}
+
+	impl Write for Client {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.output.write(buf)
+		}
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	fn request(version: u8, host: &str, port: u16, username: &str) -> Request {
+		Request {
+			version,
+			host: host.to_string(),
+			port,
+			username: username.to_string(),
+		}
+	}
+
+	#[test]
+	fn socks5_no_auth() {
+		let mut input = vec![5, 1, 0, 5, 1, 0, 3, 11];
+		input.extend_from_slice(b"example.i2p");
+		input.extend_from_slice(&[0, 80]);
+		let mut client = Client::new(&input);
+
+		assert_eq!(
+			read_request(&mut client).unwrap(),
+			request(5, "example.i2p", 80, "")
+		);
+		assert_eq!(client.output, vec![5, 0]);
+	}
+
+	#[test]
+	fn socks5_username() {
+		let mut input = vec![5, 2, 0, 2, 1, 5];
+		input.extend_from_slice(b"alice");
+		input.extend_from_slice(&[3]);
+		input.extend_from_slice(b"pwd");
+		input.extend_from_slice(&[5, 1, 0, 3, 11]);
+		input.extend_from_slice(b"example.i2p");
+		input.extend_from_slice(&[0x1f, 0x90]);
+		let mut client = Client::new(&input);
+
+		assert_eq!(
+			read_request(&mut client).unwrap(),
+			request(5, "example.i2p", 8080, "alice")
+		);
+		assert_eq!(client.output, vec![5, 2, 1, 0]);
+	}
+
+	#[test]
+	fn socks5_rejects_unknown_auth_version() {
+		let mut input = vec![5, 1, 2, 5, 5];
+		input.extend_from_slice(b"alice");
+		let mut client = Client::new(&input);
+		assert!(read_request(&mut client).is_err());
+		assert_eq!(client.output, vec![5, 2, 1, 0xff]);
+	}
+
+	#[test]
+	fn socks5_rejects_ip_addresses() {
+		let mut client = Client::new(&[5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80]);
+		let (version, reply, _) = read_request(&mut client).unwrap_err();
+		assert_eq!((version, reply), (5, Reply::AddressTypeNotSupported));
+	}
+
+	#[test]
+	fn socks5_rejects_bind() {
+		let mut client = Client::new(&[5, 1, 0, 5, 2, 0, 3, 1, b'a', 0, 80]);
+		let (_, reply, _) = read_request(&mut client).unwrap_err();
+		assert_eq!(reply, Reply::CommandNotSupported);
+	}
+
+	#[test]
+	fn socks4a() {
+		let mut input = vec![4, 1, 0, 80, 0, 0, 0, 1];
+		input.extend_from_slice(b"bob\0example.i2p\0");
+		let mut client = Client::new(&input);
+
+		assert_eq!(
+			read_request(&mut client).unwrap(),
+			request(4, "example.i2p", 80, "bob")
+		);
+
+		let mut client = Client::new(&[4, 1, 0, 80, 10, 0, 0, 1, 0]);
+		let (version, reply, _) = read_request(&mut client).unwrap_err();
+		assert_eq!((version, reply), (4, Reply::AddressTypeNotSupported));
+	}
+
+	#[test]
+	fn replies() {
+		let mut out = Vec::new();
+		write_reply(&mut out, 5, Reply::HostUnreachable).unwrap();
+		write_reply(&mut out, 4, Reply::Succeeded).unwrap();
+		assert_eq!(
+			out,
+			vec![5, 4, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0x5a, 0, 0, 0, 0, 0, 0]
+		);
+	}
+}